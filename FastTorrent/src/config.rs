@@ -0,0 +1,197 @@
+use std::{fmt, net::{IpAddr, SocketAddr}, path::PathBuf};
+use crate::{helpers::PEER_ID_PREFIX, message::CLIENT_NAME};
+
+// Message stream encryption policy for peer connections
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EncryptionMode {
+    // Plaintext only
+    Disable,
+    // Try encryption first, fall back to plaintext
+    Prefer,
+    // Encrypted connections only
+    Require
+}
+
+// Which transports outgoing peer connections use, in order of preference
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransportOrder {
+    // TCP only, uTP disabled
+    Tcp,
+    // Try uTP first, fall back to TCP
+    UtpFirst,
+    // Try TCP first, fall back to uTP
+    TcpFirst
+}
+
+// Options given on the command line
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub encryption: EncryptionMode,
+    pub transport: TransportOrder,
+    // Start of our peer id, identifies the client to trackers and peers
+    pub peer_id_prefix: String,
+    // Hand out pieces one at a time once we are a seed (BEP 16)
+    pub super_seed: bool,
+    // Indices of the files to download, all of them when not given
+    pub files: Option<Vec<usize>>,
+    // Announce to a tracker of every tier instead of only the first that works (BEP 12)
+    pub announce_all: bool,
+    // User-Agent of HTTP tracker requests
+    pub user_agent: String,
+    // Extra PEM certificates trusted for HTTPS trackers, for trackers with an internal CA
+    pub tracker_ca: Option<PathBuf>,
+    // Address trackers should give out for us, when the one they see is wrong
+    pub announce_ip: Option<IpAddr>,
+    // Trackers to add to the torrent's own for this run, as one more tier
+    pub trackers: Vec<String>,
+    // Built-in tracker: address to serve on, and files of allowed info hashes and passkeys
+    pub listen: Option<SocketAddr>,
+    pub whitelist: Option<PathBuf>,
+    pub passkeys: Option<PathBuf>
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            encryption: EncryptionMode::Prefer,
+            transport: TransportOrder::UtpFirst,
+            peer_id_prefix: PEER_ID_PREFIX.to_string(),
+            super_seed: false,
+            files: None,
+            announce_all: false,
+            user_agent: CLIENT_NAME.replace(' ', "/"),
+            tracker_ca: None,
+            announce_ip: None,
+            trackers: Vec::new(),
+            listen: None,
+            whitelist: None,
+            passkeys: None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidOption {
+    option: String
+}
+
+impl fmt::Display for InvalidOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid option: {}", self.option)
+    }
+}
+
+impl Config {
+
+    // Parse "--option value" pairs and "--flag" switches, returning config and remaining positional arguments
+    pub fn from_args(args: Vec<String>) -> Result<(Config, Vec<String>), InvalidOption> {
+
+        let mut config = Config::default();
+        let mut positional = Vec::new();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+
+            if !arg.starts_with("--") {
+                positional.push(arg);
+                continue;
+            }
+
+            if arg == "--super-seed" {
+                config.super_seed = true;
+                continue;
+            }
+            if arg == "--announce-all" {
+                config.announce_all = true;
+                continue;
+            }
+
+            let invalid = || InvalidOption { option: arg.clone() };
+            let value = args.next().ok_or_else(invalid)?;
+
+            match arg.as_str() {
+                "--encryption" => {
+                    config.encryption = match value.as_str() {
+                        "disable" => EncryptionMode::Disable,
+                        "prefer" => EncryptionMode::Prefer,
+                        "require" => EncryptionMode::Require,
+                        _ => return Err(invalid())
+                    };
+                },
+                "--transport" => {
+                    config.transport = match value.as_str() {
+                        "tcp" => TransportOrder::Tcp,
+                        "utp-first" => TransportOrder::UtpFirst,
+                        "tcp-first" => TransportOrder::TcpFirst,
+                        _ => return Err(invalid())
+                    };
+                },
+                "--peer-id-prefix" => {
+                    if value.len() > 20 || !value.is_ascii() {
+                        return Err(invalid());
+                    }
+                    config.peer_id_prefix = value;
+                },
+                "--user-agent" => { config.user_agent = value; },
+                "--tracker-ca" => { config.tracker_ca = Some(PathBuf::from(value)); },
+                "--announce-ip" => { config.announce_ip = Some(value.parse().map_err(|_| invalid())?); },
+                "--tracker" => { config.trackers.push(value); },
+                "--listen" => { config.listen = Some(value.parse().map_err(|_| invalid())?); },
+                "--whitelist" => { config.whitelist = Some(PathBuf::from(value)); },
+                "--passkeys" => { config.passkeys = Some(PathBuf::from(value)); },
+                "--files" => {
+                    let files = value.split(',').map(|index| index.trim().parse()).collect::<Result<Vec<usize>, _>>();
+                    config.files = Some(files.map_err(|_| invalid())?);
+                },
+                _ => return Err(invalid())
+            }
+        }
+
+        Ok((config, positional))
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, EncryptionMode, TransportOrder};
+
+    #[test]
+    fn from_args_test() {
+
+        let args = ["a.torrent", "--encryption", "require", "out"].iter().map(|s| s.to_string()).collect();
+        let (config, positional) = Config::from_args(args).unwrap();
+        assert_eq!(config.encryption, EncryptionMode::Require);
+        assert_eq!(config.transport, TransportOrder::UtpFirst);
+        assert_eq!(positional, vec!["a.torrent", "out"]);
+
+        let (config, _) = Config::from_args(vec!["--transport".to_string(), "tcp-first".to_string()]).unwrap();
+        assert_eq!(config.transport, TransportOrder::TcpFirst);
+        assert_eq!(config.peer_id_prefix, "-FT0100-");
+        assert!(!config.super_seed);
+        assert!(Config::from_args(vec!["--super-seed".to_string()]).unwrap().0.super_seed);
+        assert!(Config::from_args(vec!["--announce-all".to_string()]).unwrap().0.announce_all);
+
+        let (config, _) = Config::from_args(vec!["--peer-id-prefix".to_string(), "-qB4250-".to_string()]).unwrap();
+        assert_eq!(config.peer_id_prefix, "-qB4250-");
+        assert!(Config::from_args(vec!["--peer-id-prefix".to_string(), "x".repeat(21)]).is_err());
+
+        let (config, _) = Config::from_args(vec!["--files".to_string(), "0,2".to_string()]).unwrap();
+        assert_eq!(config.files, Some(vec![0, 2]));
+        assert!(Config::from_args(vec!["--files".to_string(), "0,x".to_string()]).is_err());
+
+        let (config, _) = Config::from_args(vec!["--announce-ip".to_string(), "10.0.0.1".to_string()]).unwrap();
+        assert_eq!((config.announce_ip, config.user_agent.as_str()), (Some("10.0.0.1".parse().unwrap()), "FastTorrent/0.1.0"));
+        assert!(Config::from_args(vec!["--announce-ip".to_string(), "x".to_string()]).is_err());
+
+        let args = ["--tracker", "udp://a:1", "--tracker", "http://b/announce"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(Config::from_args(args).unwrap().0.trackers, vec!["udp://a:1", "http://b/announce"]);
+
+        let (config, positional) = Config::from_args(vec!["tracker".to_string(), "--listen".to_string(), "[::]:6969".to_string()]).unwrap();
+        assert_eq!((config.listen, positional), (Some("[::]:6969".parse().unwrap()), vec!["tracker".to_string()]));
+
+        assert!(Config::from_args(vec!["--encryption".to_string()]).is_err());
+        assert!(Config::from_args(vec!["--bogus".to_string(), "1".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,826 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    sync::{Arc, atomic::{AtomicU16, Ordering}},
+    time::{Duration, Instant}
+};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use sha1_smol::Sha1;
+use tokio::{
+    net::{UdpSocket, lookup_host},
+    sync::{Mutex, oneshot},
+    task::JoinSet,
+    time::{sleep, timeout}
+};
+use crate::{
+    bencoded_parser::{Bencode, Element},
+    helpers::gen_random_id,
+    peer::{PeerList, PeerSource}
+};
+
+// Nodes per bucket and number of parallel queries in a lookup
+pub static K: usize = 8;
+static ALPHA: usize = 3;
+
+static QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+static TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+static PEER_EXPIRY: Duration = Duration::from_secs(30 * 60);
+static ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+static MAX_STORED_PEERS: usize = 100;
+static MAX_LOOKUP_QUERIES: usize = 200;
+
+pub static BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881"
+];
+
+pub type NodeId = [u8; 20];
+
+// Queries waiting for a response, keyed by transaction id
+type Pending = HashMap<Vec<u8>, (SocketAddrV4, oneshot::Sender<Element>)>;
+
+// Peers announced to us for each info hash
+type Storage = HashMap<[u8; 20], Vec<(SocketAddrV4, Instant)>>;
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+    last_seen: Instant,
+    failed: u8
+}
+
+impl Node {
+    // Node which answered recently and has not failed to respond since
+    fn is_good(&self) -> bool {
+        self.failed < 2 && self.last_seen.elapsed() < Duration::from_secs(15 * 60)
+    }
+}
+
+// XOR metric used to compare ids
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut ret = [0; 20];
+    for i in 0..20 {
+        ret[i] = a[i] ^ b[i];
+    }
+    ret
+}
+
+// Kademlia routing table, one bucket for each shared prefix length with own id
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Node>>
+}
+
+impl RoutingTable {
+
+    pub fn new(id: NodeId) -> RoutingTable {
+        RoutingTable { id, buckets: vec![Vec::new(); 160] }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        for (i, byte) in distance(&self.id, id).iter().enumerate() {
+            if *byte != 0 {
+                return i*8 + byte.leading_zeros() as usize;
+            }
+        }
+        159
+    }
+
+    // Insert or refresh a node, bad nodes are evicted when the bucket is full
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddrV4) -> bool {
+
+        if id == self.id {
+            return false;
+        }
+
+        let ind = self.bucket_index(&id);
+        let bucket = &mut self.buckets[ind];
+
+        if let Some(pos) = bucket.iter().position(|node| node.id == id) {
+            let mut node = bucket.remove(pos);
+            node.addr = addr;
+            node.last_seen = Instant::now();
+            node.failed = 0;
+            bucket.push(node);
+            return true;
+        }
+
+        if bucket.len() >= K {
+            match bucket.iter().position(|node| !node.is_good()) {
+                Some(pos) => { bucket.remove(pos); },
+                None => { return false; }
+            }
+        }
+
+        bucket.push(Node { id, addr, last_seen: Instant::now(), failed: 0 });
+        true
+    }
+
+    pub fn mark_failed(&mut self, addr: SocketAddrV4) {
+        for bucket in &mut self.buckets {
+            for node in bucket.iter_mut() {
+                if node.addr == addr {
+                    node.failed = node.failed.saturating_add(1);
+                }
+            }
+        }
+    }
+
+    // Return n nodes closest to target
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets
+            .iter()
+            .flatten()
+            .filter(|node| node.failed < 2)
+            .cloned()
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    pub fn nodes(&self) -> Vec<Node> {
+        self.buckets.iter().flatten().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Compact node info, 20 byte id followed by 6 byte address
+fn encode_nodes(nodes: &[(NodeId, SocketAddrV4)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (id, addr) in nodes {
+        buf.extend_from_slice(id);
+        buf.extend_from_slice(&encode_addr(addr));
+    }
+    buf
+}
+
+fn decode_nodes(buf: &[u8]) -> Vec<(NodeId, SocketAddrV4)> {
+    let mut ret = Vec::new();
+    for chunk in buf.chunks_exact(26) {
+        let mut id = [0; 20];
+        id.copy_from_slice(&chunk[..20]);
+        if let Some(addr) = decode_addr(&chunk[20..]) {
+            ret.push((id, addr));
+        }
+    }
+    ret
+}
+
+// Compact peer info, 4 byte ip followed by 2 byte port
+fn encode_addr(addr: &SocketAddrV4) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&addr.ip().octets());
+    buf.write_u16::<BigEndian>(addr.port()).unwrap();
+    buf
+}
+
+fn decode_addr(mut buf: &[u8]) -> Option<SocketAddrV4> {
+    if buf.len() != 6 {
+        return None;
+    }
+    let ip = buf.read_u32::<BigEndian>().ok()?;
+    let port = buf.read_u16::<BigEndian>().ok()?;
+    Some(SocketAddrV4::new(Ipv4Addr::from(ip), port))
+}
+
+fn to_id(buf: &[u8]) -> Option<NodeId> {
+    buf.try_into().ok()
+}
+
+fn dict(pairs: Vec<(&str, Element)>) -> Element {
+    Element::Dict(pairs.into_iter().map(|(key, value)| (key.as_bytes().to_vec(), value)).collect())
+}
+
+fn bytes(buf: &[u8]) -> Element {
+    Element::ByteString(buf.to_vec())
+}
+
+// KRPC messages
+fn build_query(tid: &[u8], method: &str, args: Element) -> Vec<u8> {
+    Bencode::encode(&dict(vec![
+        ("t", bytes(tid)),
+        ("y", bytes(b"q")),
+        ("q", bytes(method.as_bytes())),
+        ("a", args)
+    ]))
+}
+
+fn build_response(tid: &[u8], resp: Element) -> Vec<u8> {
+    Bencode::encode(&dict(vec![
+        ("t", bytes(tid)),
+        ("y", bytes(b"r")),
+        ("r", resp)
+    ]))
+}
+
+fn build_error(tid: &[u8], code: i64, msg: &str) -> Vec<u8> {
+    Bencode::encode(&dict(vec![
+        ("t", bytes(tid)),
+        ("y", bytes(b"e")),
+        ("e", Element::List(vec![Element::Integer(code), bytes(msg.as_bytes())]))
+    ]))
+}
+
+// Result of an iterative lookup
+pub struct Lookup {
+    pub peers: Vec<SocketAddrV4>,
+    // Responding nodes sorted by distance along with their announce token
+    pub nodes: Vec<(NodeId, SocketAddrV4, Option<Vec<u8>>)>
+}
+
+struct Secrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant
+}
+
+pub struct Dht {
+    id: NodeId,
+    socket: UdpSocket,
+    table: Mutex<RoutingTable>,
+    pending: Mutex<Pending>,
+    storage: Mutex<Storage>,
+    secrets: Mutex<Secrets>,
+    next_tid: AtomicU16,
+    state_path: Option<PathBuf>
+}
+
+impl Dht {
+
+    // Bind DHT node to addr, restoring id and nodes from state_path if present
+    pub async fn new(addr: SocketAddrV4, state_path: Option<PathBuf>) -> Option<Arc<Dht>> {
+
+        let socket = UdpSocket::bind(addr).await.ok()?;
+
+        let (id, nodes) = state_path
+            .as_ref()
+            .and_then(Dht::load)
+            .unwrap_or((gen_random_id(), Vec::new()));
+
+        let mut table = RoutingTable::new(id);
+        for (node_id, addr) in nodes {
+            table.insert(node_id, addr);
+        }
+
+        Some(Arc::new(Dht {
+            id,
+            socket,
+            table: Mutex::new(table),
+            pending: Mutex::new(HashMap::new()),
+            storage: Mutex::new(HashMap::new()),
+            secrets: Mutex::new(Secrets { current: gen_random_id(), previous: gen_random_id(), rotated: Instant::now() }),
+            next_tid: AtomicU16::new(rand::random()),
+            state_path
+        }))
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn addr(&self) -> SocketAddrV4 {
+        match self.socket.local_addr() {
+            Ok(SocketAddr::V4(addr)) => addr,
+            _ => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr().port()
+    }
+
+    pub async fn node_count(&self) -> usize {
+        self.table.lock().await.len()
+    }
+
+    // Receive loop, answers queries and hands responses to waiting queries
+    pub async fn run(self: Arc<Self>) {
+
+        let mut buf = [0; 2048];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf).await {
+                Ok((len, SocketAddr::V4(from))) => (len, from),
+                Ok(_) => continue,
+                Err(_) => {
+                    sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            let msg = match Bencode::decode_u8(buf[..len].to_vec()) {
+                Ok(msg) => msg,
+                Err(_) => continue
+            };
+
+            self.handle_packet(msg, from).await;
+        }
+    }
+
+    async fn handle_packet(&self, msg: Element, from: SocketAddrV4) {
+
+        let tid = match msg.get("t").and_then(|t| t.as_bytes()) {
+            Some(tid) => tid.to_vec(),
+            None => return
+        };
+
+        match msg.get("y").and_then(|y| y.as_bytes()) {
+            Some(b"q") => {
+                let reply = self.handle_query(&tid, &msg, from).await;
+                let _ = self.socket.send_to(&reply, from).await;
+            },
+            Some(b"r") | Some(b"e") => {
+
+                // Only accept responses from the node that was queried
+                let mut pending = self.pending.lock().await;
+                if let Some((addr, _)) = pending.get(&tid) {
+                    if *addr != from {
+                        return;
+                    }
+                    let (_, tx) = pending.remove(&tid).unwrap();
+                    drop(pending);
+
+                    if let Some(id) = msg.get("r").and_then(|r| r.get("id")).and_then(|id| id.as_bytes()).and_then(to_id) {
+                        self.table.lock().await.insert(id, from);
+                    }
+                    let _ = tx.send(msg);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    async fn handle_query(&self, tid: &[u8], msg: &Element, from: SocketAddrV4) -> Vec<u8> {
+
+        let args = match msg.get("a") {
+            Some(args) => args,
+            None => return build_error(tid, 203, "Missing arguments")
+        };
+
+        let sender = match args.get("id").and_then(|id| id.as_bytes()).and_then(to_id) {
+            Some(id) => id,
+            None => return build_error(tid, 203, "Invalid id")
+        };
+
+        let method = msg.get("q").and_then(|q| q.as_bytes()).unwrap_or_default().to_vec();
+        let response = match method.as_slice() {
+            b"ping" => dict(vec![("id", bytes(&self.id))]),
+            b"find_node" => {
+                let target = match args.get("target").and_then(|t| t.as_bytes()).and_then(to_id) {
+                    Some(target) => target,
+                    None => return build_error(tid, 203, "Invalid target")
+                };
+                dict(vec![
+                    ("id", bytes(&self.id)),
+                    ("nodes", bytes(&self.closest_compact(&target).await))
+                ])
+            },
+            b"get_peers" => {
+                let info_hash = match args.get("info_hash").and_then(|t| t.as_bytes()).and_then(to_id) {
+                    Some(info_hash) => info_hash,
+                    None => return build_error(tid, 203, "Invalid info_hash")
+                };
+
+                let mut resp = vec![
+                    ("id", bytes(&self.id)),
+                    ("token", bytes(&self.make_token(from.ip()).await)),
+                    ("nodes", bytes(&self.closest_compact(&info_hash).await))
+                ];
+
+                let peers = self.stored_peers(&info_hash).await;
+                if !peers.is_empty() {
+                    let values = peers
+                        .iter()
+                        .map(|peer| bytes(&encode_addr(peer)))
+                        .collect();
+                    resp.push(("values", Element::List(values)));
+                }
+                dict(resp)
+            },
+            b"announce_peer" => {
+                let info_hash = args.get("info_hash").and_then(|t| t.as_bytes()).and_then(to_id);
+                let token = args.get("token").and_then(|t| t.as_bytes());
+                let port = args.get("port").and_then(|p| p.as_int());
+                let implied_port = args.get("implied_port").and_then(|p| p.as_int()).unwrap_or(0);
+
+                let (info_hash, token) = match (info_hash, token) {
+                    (Some(info_hash), Some(token)) => (info_hash, token),
+                    _ => return build_error(tid, 203, "Missing info_hash or token")
+                };
+                if !self.valid_token(from.ip(), token).await {
+                    return build_error(tid, 203, "Bad token");
+                }
+
+                let port = if implied_port != 0 {
+                    from.port()
+                }
+                else {
+                    match port {
+                        Some(port) if port > 0 && port <= u16::MAX as i64 => port as u16,
+                        _ => return build_error(tid, 203, "Invalid port")
+                    }
+                };
+
+                self.store_peer(info_hash, SocketAddrV4::new(*from.ip(), port)).await;
+                dict(vec![("id", bytes(&self.id))])
+            },
+            _ => return build_error(tid, 204, "Method Unknown")
+        };
+
+        // Querying nodes are candidates for the routing table
+        self.table.lock().await.insert(sender, from);
+
+        build_response(tid, response)
+    }
+
+    async fn closest_compact(&self, target: &NodeId) -> Vec<u8> {
+        let nodes: Vec<(NodeId, SocketAddrV4)> = self.table
+            .lock()
+            .await
+            .closest(target, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        encode_nodes(&nodes)
+    }
+
+    // Send a query and wait for the "r" dictionary of the response
+    async fn query(&self, addr: SocketAddrV4, method: &str, mut args: Vec<(&str, Element)>) -> Option<Element> {
+
+        args.push(("id", bytes(&self.id)));
+
+        let tid = self.next_tid.fetch_add(1, Ordering::Relaxed).to_be_bytes().to_vec();
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(tid.clone(), (addr, tx));
+
+        let msg = build_query(&tid, method, dict(args));
+        let resp = match self.socket.send_to(&msg, addr).await {
+            Ok(_) => timeout(QUERY_TIMEOUT, rx).await.ok().and_then(|resp| resp.ok()),
+            Err(_) => None
+        };
+        self.pending.lock().await.remove(&tid);
+
+        match resp {
+            Some(resp) => resp.get("r").cloned(),
+            None => {
+                self.table.lock().await.mark_failed(addr);
+                None
+            }
+        }
+    }
+
+    // Ping node and add it to the routing table if it answers
+    pub async fn ping(&self, addr: SocketAddrV4) -> bool {
+        self.query(addr, "ping", Vec::new()).await.is_some()
+    }
+
+    // Join the network through the given nodes and fill the routing table
+    pub async fn bootstrap(self: &Arc<Self>, nodes: &[SocketAddr]) {
+
+        let mut set = JoinSet::new();
+        for addr in nodes {
+            if let SocketAddr::V4(addr) = *addr {
+                let dht = self.clone();
+                set.spawn(async move {
+                    dht.query(addr, "find_node", vec![("target", bytes(&dht.id))]).await
+                });
+            }
+        }
+
+        while let Some(res) = set.join_next().await {
+            if let Ok(Some(resp)) = res {
+                if let Some(nodes) = resp.get("nodes").and_then(|n| n.as_bytes()) {
+                    let mut table = self.table.lock().await;
+                    for (id, addr) in decode_nodes(nodes) {
+                        table.insert(id, addr);
+                    }
+                }
+            }
+        }
+
+        self.iterative(self.id, "find_node").await;
+    }
+
+    // Iterative lookup towards target using find_node or get_peers
+    async fn iterative(self: &Arc<Self>, target: NodeId, method: &'static str) -> Lookup {
+
+        let mut shortlist: Vec<(NodeId, SocketAddrV4)> = self.table
+            .lock()
+            .await
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect();
+
+        let mut queried = HashSet::new();
+        let mut peers = Vec::new();
+        let mut nodes = Vec::new();
+
+        loop {
+
+            shortlist.sort_by_key(|(id, _)| distance(id, &target));
+
+            // Done once the K closest known nodes have all been queried
+            let next: Vec<(NodeId, SocketAddrV4)> = shortlist
+                .iter()
+                .take(K)
+                .filter(|(_, addr)| !queried.contains(addr))
+                .take(ALPHA)
+                .cloned()
+                .collect();
+            if next.is_empty() || queried.len() >= MAX_LOOKUP_QUERIES {
+                break;
+            }
+
+            let mut set = JoinSet::new();
+            for (id, addr) in next {
+                queried.insert(addr);
+                let dht = self.clone();
+                let key = if method == "get_peers" { "info_hash" } else { "target" };
+                set.spawn(async move {
+                    (id, addr, dht.query(addr, method, vec![(key, bytes(&target))]).await)
+                });
+            }
+
+            while let Some(res) = set.join_next().await {
+                let (id, addr, resp) = match res {
+                    Ok(res) => res,
+                    Err(_) => continue
+                };
+
+                let resp = match resp {
+                    Some(resp) => resp,
+                    None => {
+                        shortlist.retain(|(_, a)| *a != addr);
+                        continue;
+                    }
+                };
+
+                if let Some(buf) = resp.get("nodes").and_then(|n| n.as_bytes()) {
+                    for (node_id, node_addr) in decode_nodes(buf) {
+                        if node_id != self.id && !shortlist.iter().any(|(_, a)| *a == node_addr) {
+                            shortlist.push((node_id, node_addr));
+                        }
+                    }
+                }
+
+                if let Some(values) = resp.get("values").and_then(|v| v.as_list()) {
+                    for value in values {
+                        if let Some(peer) = value.as_bytes().and_then(decode_addr) {
+                            if !peers.contains(&peer) {
+                                peers.push(peer);
+                            }
+                        }
+                    }
+                }
+
+                let token = resp.get("token").and_then(|t| t.as_bytes()).map(|t| t.to_vec());
+                nodes.push((id, addr, token));
+            }
+        }
+
+        nodes.sort_by_key(|(id, _, _)| distance(id, &target));
+        Lookup { peers, nodes }
+    }
+
+    // Find peers for info_hash
+    pub async fn lookup(self: &Arc<Self>, info_hash: [u8; 20]) -> Lookup {
+        self.iterative(info_hash, "get_peers").await
+    }
+
+    // Find peers for info_hash and announce ourselves on port to the closest nodes
+    pub async fn announce(self: &Arc<Self>, info_hash: [u8; 20], port: u16) -> Vec<SocketAddrV4> {
+
+        let lookup = self.lookup(info_hash).await;
+
+        let mut set = JoinSet::new();
+        for (_, addr, token) in lookup.nodes.into_iter().filter(|(_, _, token)| token.is_some()).take(K) {
+            let dht = self.clone();
+            set.spawn(async move {
+                dht.query(addr, "announce_peer", vec![
+                    ("info_hash", bytes(&info_hash)),
+                    ("port", Element::Integer(port as i64)),
+                    ("token", Element::ByteString(token.unwrap()))
+                ]).await
+            });
+        }
+        while set.join_next().await.is_some() {}
+
+        lookup.peers
+    }
+
+    // Token given out in get_peers and required by announce_peer
+    async fn make_token(&self, ip: &Ipv4Addr) -> Vec<u8> {
+
+        let mut secrets = self.secrets.lock().await;
+        if secrets.rotated.elapsed() > TOKEN_ROTATION {
+            secrets.previous = secrets.current;
+            secrets.current = gen_random_id();
+            secrets.rotated = Instant::now();
+        }
+        token(&secrets.current, ip)
+    }
+
+    async fn valid_token(&self, ip: &Ipv4Addr, received: &[u8]) -> bool {
+        let secrets = self.secrets.lock().await;
+        token(&secrets.current, ip) == received || token(&secrets.previous, ip) == received
+    }
+
+    async fn store_peer(&self, info_hash: [u8; 20], peer: SocketAddrV4) {
+
+        let mut storage = self.storage.lock().await;
+        let peers = storage.entry(info_hash).or_default();
+
+        peers.retain(|(p, time)| *p != peer && time.elapsed() < PEER_EXPIRY);
+        if peers.len() >= MAX_STORED_PEERS {
+            peers.remove(0);
+        }
+        peers.push((peer, Instant::now()));
+    }
+
+    async fn stored_peers(&self, info_hash: &[u8; 20]) -> Vec<SocketAddrV4> {
+        let mut storage = self.storage.lock().await;
+        match storage.get_mut(info_hash) {
+            Some(peers) => {
+                peers.retain(|(_, time)| time.elapsed() < PEER_EXPIRY);
+                peers.iter().map(|(peer, _)| *peer).collect()
+            },
+            None => Vec::new()
+        }
+    }
+
+    // Persist node id and routing table
+    pub async fn save(&self) {
+
+        let path = match &self.state_path {
+            Some(path) => path,
+            None => return
+        };
+
+        let nodes: Vec<(NodeId, SocketAddrV4)> = self.table
+            .lock()
+            .await
+            .nodes()
+            .into_iter()
+            .filter(|node| node.is_good())
+            .map(|node| (node.id, node.addr))
+            .collect();
+
+        let state = dict(vec![
+            ("id", bytes(&self.id)),
+            ("nodes", bytes(&encode_nodes(&nodes)))
+        ]);
+
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        let _ = fs::write(path, Bencode::encode(&state));
+    }
+
+    fn load(path: &PathBuf) -> Option<(NodeId, Vec<(NodeId, SocketAddrV4)>)> {
+        let state = Bencode::decode_u8(fs::read(path).ok()?).ok()?;
+        let id = to_id(state.get("id")?.as_bytes()?)?;
+        let nodes = decode_nodes(state.get("nodes").and_then(|n| n.as_bytes()).unwrap_or_default());
+        Some((id, nodes))
+    }
+
+}
+
+fn token(secret: &[u8; 20], ip: &Ipv4Addr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    hasher.update(&ip.octets());
+    hasher.digest().bytes()[..8].to_vec()
+}
+
+async fn resolve_bootstrap() -> Vec<SocketAddr> {
+    let mut ret = Vec::new();
+    for host in BOOTSTRAP_NODES {
+        if let Ok(Ok(addrs)) = timeout(Duration::from_secs(5), lookup_host(host)).await {
+            ret.extend(addrs.filter(|addr| addr.is_ipv4()));
+        }
+    }
+    ret
+}
+
+// Periodically look up info_hash in the DHT and feed discovered peers into peer_list
+pub async fn find_peers(dht: Arc<Dht>, info_hash: [u8; 20], port: u16, peer_list: PeerList, piece_left: Arc<Mutex<u16>>) {
+
+    loop {
+        if *(piece_left.lock().await) == 0 {
+            break;
+        }
+
+        // (Re)join the network while the routing table is nearly empty
+        if dht.node_count().await < K {
+            let mut nodes = resolve_bootstrap().await;
+            nodes.extend(dht.table.lock().await.nodes().iter().map(|node| SocketAddr::V4(node.addr)));
+            dht.bootstrap(&nodes).await;
+        }
+
+        let peers = dht.announce(info_hash, port).await;
+        let found = !peers.is_empty();
+        {
+            let mut list = peer_list.lock().await;
+            for peer in peers.into_iter().map(SocketAddr::V4) {
+                if !list.iter().any(|(p, _)| *p == peer) {
+                    list.push_back((peer, PeerSource::Dht));
+                }
+            }
+        }
+
+        dht.save().await;
+
+        sleep(if found { ANNOUNCE_INTERVAL } else { Duration::from_secs(30) }).await;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+    use crate::helpers::gen_random_id;
+    use super::{Dht, RoutingTable, K, decode_nodes, encode_nodes};
+
+    #[test]
+    fn routing_table_test() {
+
+        let own = [0u8; 20];
+        let mut table = RoutingTable::new(own);
+        assert!(!table.insert(own, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1)));
+
+        // All ids with first bit set share a bucket
+        for i in 0..(K as u8 + 2) {
+            let mut id = [0u8; 20];
+            id[0] = 0x80;
+            id[19] = i;
+            table.insert(id, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1000 + i as u16));
+        }
+        assert_eq!(table.len(), K);
+
+        let mut near = [0u8; 20];
+        near[19] = 1;
+        table.insert(near, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1));
+        assert_eq!(table.closest(&own, 1)[0].id, near);
+    }
+
+    #[test]
+    fn compact_nodes_test() {
+        let nodes = vec![(gen_random_id(), SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881))];
+        assert_eq!(decode_nodes(&encode_nodes(&nodes)), nodes);
+    }
+
+    #[tokio::test]
+    async fn local_dht_test() {
+
+        let mut nodes = Vec::new();
+        for _ in 0..6 {
+            let dht = Dht::new(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0), None).await.unwrap();
+            tokio::spawn(dht.clone().run());
+            nodes.push(dht);
+        }
+
+        let boot = [SocketAddr::V4(nodes[0].addr())];
+        for dht in &nodes[1..] {
+            dht.bootstrap(&boot).await;
+        }
+        assert!(nodes[0].node_count().await >= 4);
+        assert!(nodes[5].ping(nodes[1].addr()).await);
+
+        let info_hash = gen_random_id();
+        assert!(nodes[2].announce(info_hash, 5555).await.is_empty());
+
+        let lookup = nodes[4].lookup(info_hash).await;
+        assert!(lookup.peers.contains(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, 5555)));
+    }
+
+    #[tokio::test]
+    async fn state_test() {
+
+        let path = std::env::temp_dir().join(format!("dht_state_{}", u32::from_be_bytes(gen_random_id()[..4].try_into().unwrap())));
+        let local = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+
+        let dht = Dht::new(local, Some(path.clone())).await.unwrap();
+        let other = Dht::new(local, None).await.unwrap();
+        tokio::spawn(other.clone().run());
+        tokio::spawn(dht.clone().run());
+        assert!(dht.ping(other.addr()).await);
+        dht.save().await;
+
+        let restored = Dht::new(local, Some(path.clone())).await.unwrap();
+        assert_eq!(restored.id(), dht.id());
+        assert_eq!(restored.node_count().await, 1);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,435 +1,1422 @@
-use std::{
-    collections::{HashSet, LinkedList}, fs::File, io::{Write, stdout}, net::{Ipv4Addr, SocketAddrV4}, os::unix::fs::FileExt, sync::Arc, time::Duration
-};
-use crossterm::{QueueableCommand, cursor, terminal, ExecutableCommand};
-use sha1_smol::Sha1;
-use tokio::{
-    io::{AsyncWriteExt, AsyncReadExt},
-    net::TcpStream,
-    sync::Mutex,
-    time::{timeout, sleep, self}
-};
-use byteorder::{BigEndian, ReadBytesExt};
-use crate::{
-    torrent_parser::{Torrent, Piece}, 
-    message::{HandshakeMsg, Message}, 
-    helpers::{self, BLOCK_SIZE, CONN_LIMIT, on_whole_msg}
-};
-
-pub async fn download_file(torrent: Torrent, file_ref: Arc<Vec<(File, u64)>>) {    
-
-    let mut handles = vec![];
-    loop {
-        if *(torrent.piece_left.lock().await) == 0 {
-            break;
-        }
-
-        while (*(torrent.connections.lock().await)).len() as u32 >= CONN_LIMIT || torrent.peer_list.lock().await.is_empty() {
-            sleep(Duration::from_millis(1000)).await;
-        }
-
-        while !torrent.peer_list.lock().await.is_empty() {
-            let mut q = torrent.peer_list.lock().await;
-            let peer = (*q).pop_front().unwrap();
-
-            let freq_ref  = torrent.piece_freq.clone();
-            let file_ref = file_ref.clone();
-            let down_ref = torrent.downloaded.clone();
-            let conn_ref = torrent.connections.clone();
-            let hashes = torrent.piece_hashes.clone();
-            let left = torrent.piece_left.clone();
-
-            if (*(conn_ref.lock().await)).contains(&peer) {
-                continue;
-            }
-
-            let h = tokio::spawn( async move{
-
-                let stream = connect(peer, torrent.info_hash, torrent.peer_id).await;
-                if let Some(stream) = stream {
-                    {
-                        let mut connections = conn_ref.lock().await;
-                        (*connections).insert(peer);
-                    }
-                    handle_connection(stream, freq_ref, file_ref, down_ref, hashes, left).await;
-                    {
-                        let mut connections = conn_ref.lock().await;
-                        (*connections).remove(&peer);
-                    }
-                }
-                else {
-                    return;
-                }
-            });
-
-            handles.push(h);
-        }
-        
-        sleep(time::Duration::from_secs(5)).await;
-    }
-
-    for handle in handles {
-        handle.await.unwrap();
-    }
-
-}
-
-async fn connect(peer: (u32,u16), info_hash: [u8; 20], peer_id: [u8; 20]) -> Option<TcpStream> {
-
-    let socket = SocketAddrV4::new(Ipv4Addr::from(peer.0),peer.1);
-    let stream = timeout(tokio::time::Duration::from_secs(2),TcpStream::connect(socket)).await.ok()?.ok()?;
-
-    handshake(stream, info_hash, peer_id).await
-
-}
-
-async fn handshake(mut stream: TcpStream, info_hash: [u8; 20], peer_id: [u8;20]) -> Option<TcpStream> {
-
-    // Get handshake msg
-    let mut handshake_msg = HandshakeMsg::build_msg(info_hash, peer_id);
-
-    // Write handshake message to stream
-    stream.write(&mut handshake_msg).await.unwrap();
-
-    // Read handshake response
-    let mut buf = Vec::new();
-    let bytes_read = timeout(tokio::time::Duration::from_secs(2),stream.read_buf(&mut buf)).await.ok()?.ok()?;
-    
-    // Check whether response handshake or not
-    if bytes_read == 64 && String::from_utf8_lossy(&buf[..=19])[1..] == "BitTorrent protocol".to_string() {
-        // Read waste 4 bytes
-        let mut tmp = [0; 4];
-        let _res = timeout(tokio::time::Duration::from_secs(2),stream.read_exact(&mut tmp)).await;
-        // Handle Torrent further from here
-        Some(stream)
-    }
-    else {
-        None
-    }
-
-}
-
-async fn handle_connection(mut stream: TcpStream, freq_ref: Arc<Mutex<Vec<Piece>>>, file: Arc<Vec<(File, u64)>>, down_ref: Arc<Mutex<u64>>, hashes: Arc<Vec<Vec<u8>>>, piece_left: Arc<Mutex<u16>>) {
-
-    let mut bitfield = vec![false; (*(freq_ref.lock().await)).len()];
-    let mut choke = true;
-    let mut requested: LinkedList<u32> = LinkedList::new();
-    let mut piece_req: Option<usize> = None;
-
-    loop {
-        
-
-        // Read length
-        let mut msg;
-        if let Some(len) = get_length(&mut stream).await {
-            msg = on_whole_msg(&mut stream, len).await.unwrap();
-        }
-        else { 
-            if !requested.is_empty() {
-
-                let mut freq = freq_ref.lock().await;
-                for begin in requested {
-                    (*freq)[piece_req.unwrap()].blocks[begin as usize].is_req = false;
-                }
-
-            }
-            return; 
-        }
-
-        
-        // Read id of message
-        let mut id = None;
-        if msg.len() >= 1 { id = Some(msg[0]); }
-
-        match id {
-            None => {
-                // keep-alive
-            },
-            Some(0) => {
-                // choke
-                choke = true;
-            },
-            Some(1) => {
-                // unchoke
-                choke = false;
-            },
-            Some(2) => {
-
-                // Interested
-                stream.write_all(&Message::build_unchoke()).await.unwrap();
-
-            },
-            Some(3) => {
-                // not-interested
-            },
-            Some(4) => {
-
-                // have
-                let piece_index = ReadBytesExt::read_u32::<BigEndian>(&mut msg.as_mut_slice()[1..].as_ref()).unwrap();
-                if !bitfield[piece_index as usize] {
-                    (*(freq_ref.lock().await))[piece_index as usize].ref_no += 1;
-                    bitfield[piece_index as usize] = true;
-                }
-
-            },
-            Some(5) => {
-
-                //bitfield
-                let mut freq_arr = freq_ref.lock().await;
-                for i in 1..msg.len() {
-                    for (j, val) in helpers::u8_to_bin(msg[i as usize]).iter().enumerate() {
-
-                        if !val { continue; }
-                        let ind = (i as usize -1)*8 + j;
-                        if ind >= bitfield.len() {
-                            break;
-                        }
-                        bitfield[ind] = *val;
-                        (*freq_arr)[ind].ref_no += 1;
-
-                    }
-                }
-
-            },
-            Some(6) => {
-
-                // request
-                let req = Message::read_request(&msg);
-                println!("{:?}",req);
-
-            },
-            Some(7) => {
-
-                let mut donwloaded = down_ref.lock().await;
-                *donwloaded += (msg.len() - 9) as u64;
-
-                let begin = write_to_file(msg, file.clone(), freq_ref.clone()).await;
-                
-                for (i, el) in requested.iter().enumerate() {
-                    if *el == begin {
-                        let mut split = requested.split_off(i);
-                        split.pop_front();
-                        requested.append(&mut split);
-                        break;
-                    }
-                }
-                
-                if requested.is_empty() {
-
-                    let piece_length;
-                    let offset;
-                    {
-                        let freq = freq_ref.lock().await;
-                        piece_length = (*freq)[piece_req.unwrap()].length;
-                        offset = (*freq)[piece_req.unwrap()].blocks[0].offset;
-                    }
-                    
-                    if !verify_piece(piece_length, offset, file.clone(), &(*hashes)[piece_req.unwrap()]) {
-                        let mut freq = freq_ref.lock().await;
-
-                        for block in &mut (*freq)[piece_req.unwrap()].blocks {
-                            block.is_req = false;
-                        }
-                    }
-                    else {
-
-                        let mut freq = freq_ref.lock().await;
-                        (*freq)[piece_req.unwrap()].completed = true;
-                        
-                        let mut left = piece_left.lock().await;
-                        *left -= 1;
-                        
-                    }
-
-                }
-
-            },
-            Some(8) => {
-                // cancel
-            },
-            Some(9) => {
-                // port
-            },
-            _ => {
-                return;
-            }
-        }
-
-        if !choke && requested.is_empty() {
-
-            (requested, piece_req) = make_request(freq_ref.lock().await, &mut stream, &bitfield).await;
-            if piece_req == None {return;}
-
-        }
-
-    }
-
-}
-
-pub fn verify_piece(piece_length: u64, offset: u64, file: Arc<Vec<(File,u64)>>, hash: &Vec<u8>) -> bool {
-
-    let mut buf = vec![0u8; piece_length as usize];
-
-    // Reading file at different locations
-    let mut ind: usize = 0;
-    let mut length: u64 = 0;
-    while length <= offset  {
-        length += (*file)[ind].1;
-        ind += 1;
-    }
-    ind -= 1;
-
-    // Read file
-    let mut read = 0;
-    while read != piece_length as usize && ind < (*file).len() {
-        let res = (*file)[ind].0.read_at(&mut buf[read..], offset);
-
-        // Return false if error in reading
-        match res {
-            Ok(bytes) => { read += bytes; },
-            Err(_) => {
-                return false; 
-            }
-        }
-
-        ind += 1;
-    }
-
-    if read != piece_length as usize {
-        return false;
-    }
-
-    // Generate hash
-    let mut hasher = Sha1::new();
-    hasher.update(&buf);
-
-    // Validate hash
-    if (*hash) == hasher.digest().bytes() {
-        return true;
-    }
-    else {
-        return false;
-    }
-
-}
-
-async fn get_length(stream: &mut TcpStream) -> Option<u32> {
-
-    let mut buf  = [0; 4];
-    timeout(tokio::time::Duration::from_secs(120),stream.read_exact(&mut buf)).await.ok()?.ok()?;
-
-    Some(ReadBytesExt::read_u32::<BigEndian>(&mut buf.as_ref()).unwrap())
-}
-
-async fn make_request(mut freq_arr: tokio::sync::MutexGuard<'_, Vec<Piece>>, stream: &mut TcpStream, bitfield: &Vec<bool> ) -> (LinkedList<u32>, Option<usize>) {
-
-    let mut to_req = None;
-    let mut mn = u16::MAX;
-
-    // Find piece with minimum nodes
-    for (i, piece) in (*freq_arr).iter_mut().enumerate() {
-        if bitfield[i] && piece.ref_no < mn && piece.completed == false {
-
-            for block in piece.blocks.iter() {
-
-                if block.is_req == false {
-
-                    to_req = Some(i);
-                    mn = piece.ref_no;
-                    break;
-
-                }
-            }
-            
-        }
-
-    }
-
-    let mut req = LinkedList::new();
-    
-    if to_req != None {
-        
-        let ind = to_req.unwrap();
-
-        for (j, block) in (*freq_arr)[ind].blocks.iter_mut().enumerate() {
-            if block.is_req == false {
-                block.is_req = true;
-                let res = stream.write(&Message::build_request(ind as u32, (j as u32)*BLOCK_SIZE, block.length as u32)).await;
-
-                if let Err(_) = res {
-                    return (req, None);
-                }
-
-                req.push_back(j as u32);
-            }
-        }
-    }
-
-    (req, to_req)
-}
-
-async fn write_to_file(mut msg: Vec<u8>, file: Arc<Vec<(File, u64)>>, freq_ref: Arc<Mutex<Vec<Piece>>>) -> u32 {
-
-    // piece
-    let buf = &mut msg.as_mut_slice()[1..].as_ref();
-    let index = ReadBytesExt::read_u32::<BigEndian>(buf).unwrap();
-    let mut begin = ReadBytesExt::read_u32::<BigEndian>(buf).unwrap();
-    begin /= BLOCK_SIZE;
-
-    let offset = (*freq_ref.lock().await)[index as usize].blocks[begin as usize].offset;
-
-    // Writing to file at different locations
-    let mut ind: usize = 0;
-    let mut length: u64 = 0;
-    while length <= offset  {
-        length += (*file)[ind].1;
-        ind += 1;
-    }
-    ind -= 1;
-    let available = (*file)[ind].1 - offset;
-    if available < (msg.len()-9) as u64 {
-        ((*file)[ind]).0.write_at(&msg[9..(9 + available) as usize], offset).unwrap();
-        ((*file)[ind+1]).0.write_at(&msg[(9 + available) as usize ..], offset).unwrap();
-    }
-    else {
-        ((*file)[ind]).0.write_at(&msg[9..], offset).unwrap();
-    }
-    begin
-}
-
-pub async fn download_print(downloaded: Arc<Mutex<u64>>, connections: Arc<Mutex<HashSet<(u32,u16)>>>, piece_left: Arc<Mutex<u16>>) {
-    let mut stdout = stdout();
-
-    stdout.execute(cursor::Hide).unwrap();
-
-    let mut last = 0;
-
-    loop {
-
-        let now; 
-        let connection: usize; 
-        let left;
-        {
-            now = *(downloaded.lock().await);
-            connection = (*(connections.lock().await)).len();
-            left = *(piece_left.lock().await);
-        }
-
-        if left == 0 {
-            break;
-        }
-
-        let tot = (now as f64) / (1048756 as f64);
-        let speed = ((now - last) as f64) / ((1048756*3) as f64);
-        
-        stdout.write_all(format!("\rDownloaded: {:.2} MB\nSpeed: {:.2} MB/s\nConnections: {}/{}\nPieces Left: {}", tot, speed, connection, CONN_LIMIT, left).as_bytes()).unwrap();
-        
-        stdout.execute(cursor::MoveUp(3)).unwrap();
-        stdout.queue(terminal::Clear(terminal::ClearType::FromCursorDown)).unwrap();
-        last = now;
-        sleep(time::Duration::from_secs(3)).await;
-    }
-    stdout.execute(cursor::Show).unwrap();
-
-    println!("Done!");
-}
\ No newline at end of file
+use std::{
+    collections::HashSet, fs::File, io::{Write, stdout}, net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4}, os::unix::fs::FileExt, sync::Arc, time::{Duration, Instant}
+};
+use crossterm::{QueueableCommand, cursor, terminal, ExecutableCommand};
+use sha1_smol::Sha1;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, AsyncReadExt},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+    time::{timeout, sleep, self}
+};
+use byteorder::{BigEndian, ReadBytesExt};
+use socket2::Type;
+use crate::{
+    torrent_parser::{Torrent, Piece}, 
+    message::{ExtendedHandshake, HandshakeMsg, Message, CLIENT_NAME, DHT_BIT, EXTENSION_BIT, FAST_BIT, UPLOAD_ONLY_ID}, 
+    helpers::{self, ALLOWED_FAST, BLOCK_SIZE, CONN_LIMIT, QUEUE_LIMIT, SNUB_TIMEOUT, MessageReader},
+    dht::Dht,
+    config::{Config, EncryptionMode, TransportOrder},
+    mse::{self, CryptoStream, CRYPTO_PLAINTEXT, CRYPTO_RC4},
+    utp::UtpSocket,
+    peer::{client_name, PeerRegistry, PeerSource, PeerState},
+    tracker::{Tracker, TrackerList}
+};
+
+// Byte stream a peer session runs over, TCP or uTP
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+pub type PeerStream = CryptoStream<Box<dyn Transport>>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransportKind {
+    Tcp,
+    Utp
+}
+
+// Every piece is here and we are not staying on to super seed. Holding only the wanted
+// pieces is not enough, a partial seed keeps serving what it has
+async fn finished(torrent: &Torrent) -> bool {
+    *(torrent.piece_left.lock().await) == 0 && torrent.super_seed.is_none()
+}
+
+pub async fn download_file(torrent: Torrent, file_ref: Arc<Vec<(File, u64)>>, dht: Option<Arc<Dht>>, config: Arc<Config>, utp: Option<Arc<UtpSocket>>) {    
+
+    let mut handles = vec![];
+    loop {
+        if finished(&torrent).await {
+            break;
+        }
+
+        // Web seeds may finish the download while we wait for peers
+        while ((*(torrent.connections.lock().await)).len() as u32 >= CONN_LIMIT || torrent.peer_list.lock().await.is_empty()) && !finished(&torrent).await {
+            sleep(Duration::from_millis(1000)).await;
+        }
+
+        while !torrent.peer_list.lock().await.is_empty() {
+            let mut q = torrent.peer_list.lock().await;
+            let (peer, source) = (*q).pop_front().unwrap();
+            if !torrent.accepts(source) {
+                continue;
+            }
+
+            let torrent = torrent.clone();
+            let file_ref = file_ref.clone();
+            let conn_ref = torrent.connections.clone();
+            let dht = dht.clone();
+            let config = config.clone();
+            let utp = utp.clone();
+
+            if (*(conn_ref.lock().await)).contains_key(&peer) || torrent.bans.lock().await.is_banned(peer.ip()) {
+                continue;
+            }
+
+            let h = tokio::spawn( async move{
+
+                let stream = connect(peer, torrent.info_hash, torrent.peer_id, &config, utp).await;
+                if let Some((stream, handshake, transport)) = stream {
+                    let state = PeerState::new(peer, handshake.peer_id, source, transport, stream.is_encrypted());
+                    {
+                        let mut connections = conn_ref.lock().await;
+                        (*connections).insert(peer, state.clone());
+                    }
+                    handle_connection(stream, state, handshake.reserved, torrent, file_ref, dht).await;
+                    {
+                        let mut connections = conn_ref.lock().await;
+                        (*connections).remove(&peer);
+                    }
+                }
+            });
+
+            handles.push(h);
+        }
+        
+        sleep(time::Duration::from_secs(5)).await;
+    }
+
+    // A connection task which panicked takes only its own peer down
+    for handle in handles {
+        let _ = handle.await;
+    }
+
+}
+
+// TCP listener for incoming peers, dual stack where IPv6 is available, IPv4 only otherwise
+pub async fn bind_listener(port: u16) -> Option<TcpListener> {
+    match helpers::dual_stack(Type::STREAM, port) {
+        Ok(socket) => TcpListener::from_std(socket.into()).ok(),
+        Err(_) => TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).await.ok()
+    }
+}
+
+// Accept incoming peer connections over TCP and uTP until the download is complete, partial seeds included
+pub async fn listen(torrent: Torrent, file_ref: Arc<Vec<(File, u64)>>, dht: Option<Arc<Dht>>, config: Arc<Config>, listener: Option<TcpListener>, utp: Option<Arc<UtpSocket>>) {
+
+    if listener.is_none() && utp.is_none() {
+        return;
+    }
+
+    loop {
+        if finished(&torrent).await {
+            break;
+        }
+
+        let accepted = tokio::select! {
+            Ok((stream, addr)) = async { listener.as_ref().unwrap().accept().await }, if listener.is_some() => {
+                Some((Box::new(stream) as Box<dyn Transport>, addr, TransportKind::Tcp))
+            },
+            Some(stream) = async { utp.as_ref().unwrap().accept().await }, if utp.is_some() => {
+                let addr = stream.peer_addr();
+                Some((Box::new(stream) as Box<dyn Transport>, addr, TransportKind::Utp))
+            },
+            _ = sleep(Duration::from_secs(5)) => None
+        };
+
+        let (stream, peer, transport) = match accepted {
+            Some((stream, addr, transport)) => (stream, helpers::canonical_addr(addr), transport),
+            None => continue
+        };
+
+        {
+            let connections = torrent.connections.lock().await;
+            if (*connections).len() as u32 >= CONN_LIMIT || (*connections).contains_key(&peer) || torrent.bans.lock().await.is_banned(peer.ip()) {
+                continue;
+            }
+        }
+
+        let torrent = torrent.clone();
+        let file_ref = file_ref.clone();
+        let dht = dht.clone();
+        let encryption = config.encryption;
+
+        tokio::spawn(async move {
+            if let Some((stream, handshake)) = accept_handshake(stream, torrent.info_hash, torrent.peer_id, encryption).await {
+                let state = PeerState::new(peer, handshake.peer_id, PeerSource::Incoming, transport, stream.is_encrypted());
+                let conn_ref = torrent.connections.clone();
+                (*conn_ref.lock().await).insert(peer, state.clone());
+                handle_connection(stream, state, handshake.reserved, torrent, file_ref, dht).await;
+                (*conn_ref.lock().await).remove(&peer);
+            }
+        });
+    }
+
+}
+
+// Open a TCP or uTP connection to the peer
+async fn open_transport(kind: TransportKind, addr: SocketAddr, utp: &Option<Arc<UtpSocket>>) -> Option<Box<dyn Transport>> {
+    match (kind, utp) {
+        (TransportKind::Tcp, _) => {
+            let stream = timeout(Duration::from_secs(2), TcpStream::connect(addr)).await.ok()?.ok()?;
+            Some(Box::new(stream))
+        },
+        (TransportKind::Utp, Some(utp)) => Some(Box::new(utp.connect(addr).await?)),
+        (TransportKind::Utp, None) => None
+    }
+}
+
+// Try the transports in configured order, the first one that connects carries the session
+async fn connect(addr: SocketAddr, info_hash: [u8; 20], peer_id: [u8; 20], config: &Config, utp: Option<Arc<UtpSocket>>) -> Option<(PeerStream, HandshakeMsg, TransportKind)> {
+
+    let order: &[TransportKind] = match config.transport {
+        TransportOrder::Tcp => &[TransportKind::Tcp],
+        TransportOrder::UtpFirst => &[TransportKind::Utp, TransportKind::Tcp],
+        TransportOrder::TcpFirst => &[TransportKind::Tcp, TransportKind::Utp]
+    };
+
+    for kind in order {
+        let stream = match open_transport(*kind, addr, &utp).await {
+            Some(stream) => stream,
+            None => continue
+        };
+
+        let res = match config.encryption {
+            EncryptionMode::Disable => handshake(CryptoStream::plain(stream), info_hash, peer_id).await,
+            EncryptionMode::Require => {
+                let stream = timeout(Duration::from_secs(10), mse::initiate(stream, &info_hash, CRYPTO_RC4)).await.ok()??;
+                handshake(stream, info_hash, peer_id).await
+            },
+            EncryptionMode::Prefer => {
+                let provide = CRYPTO_RC4 | CRYPTO_PLAINTEXT;
+                match timeout(Duration::from_secs(10), mse::initiate(stream, &info_hash, provide)).await {
+                    Ok(Some(stream)) => handshake(stream, info_hash, peer_id).await,
+                    // Peer does not speak MSE, reconnect in plaintext
+                    _ => {
+                        let stream = open_transport(*kind, addr, &utp).await?;
+                        handshake(CryptoStream::plain(stream), info_hash, peer_id).await
+                    }
+                }
+            }
+        };
+        return res.map(|(stream, handshake)| (stream, handshake, *kind));
+    }
+
+    None
+
+}
+
+// Incoming connection, either plaintext or MSE depending on the first bytes and policy
+async fn accept_handshake(mut stream: Box<dyn Transport>, info_hash: [u8; 20], peer_id: [u8; 20], encryption: EncryptionMode) -> Option<(PeerStream, HandshakeMsg)> {
+
+    let mut prefix = [0; 20];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut prefix)).await.ok()?.ok()?;
+
+    let mut stream = if prefix[0] == 19 && &prefix[1..] == b"BitTorrent protocol" {
+        if encryption == EncryptionMode::Require {
+            return None;
+        }
+        CryptoStream::with_prefix(stream, prefix.to_vec())
+    }
+    else {
+        let allow = match encryption {
+            EncryptionMode::Disable => return None,
+            EncryptionMode::Prefer => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            EncryptionMode::Require => CRYPTO_RC4
+        };
+        timeout(Duration::from_secs(10), mse::respond(stream, &prefix, &info_hash, allow)).await.ok()??
+    };
+
+    // Read handshake of peer then answer with ours
+    let mut buf = [0; 68];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut buf)).await.ok()?.ok()?;
+
+    let resp = HandshakeMsg::read_msg(&buf)?;
+    if resp.info_hash != info_hash {
+        return None;
+    }
+
+    stream.write_all(&HandshakeMsg::build_msg(info_hash, peer_id)).await.ok()?;
+
+    Some((stream, resp))
+
+}
+
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, info_hash: [u8; 20], peer_id: [u8;20]) -> Option<(S, HandshakeMsg)> {
+
+    // Get handshake msg
+    let handshake_msg = HandshakeMsg::build_msg(info_hash, peer_id);
+
+    // Write handshake message to stream
+    stream.write_all(&handshake_msg).await.ok()?;
+
+    // Read handshake response
+    let mut buf = [0; 68];
+    timeout(tokio::time::Duration::from_secs(2),stream.read_exact(&mut buf)).await.ok()?.ok()?;
+    
+    // Check whether response handshake for the same torrent or not
+    let resp = HandshakeMsg::read_msg(&buf)?;
+    if resp.info_hash != info_hash {
+        return None;
+    }
+
+    // Handle Torrent further from here
+    Some((stream, resp))
+
+}
+
+// Messages sent right after the handshake: what we have, our allowed fast set, DHT port and interest
+async fn initial_messages(torrent: &Torrent, fast: bool, reserved: u64, peer_ip: IpAddr, dht: &Option<Arc<Dht>>, super_seeding: bool) -> (Vec<u8>, Vec<u32>) {
+
+    // A super seed claims to have nothing and reveals pieces one by one
+    let have: Vec<bool> = (*(torrent.piece_freq.lock().await)).iter().map(|piece| piece.completed && !super_seeding).collect();
+    let mut buf = Vec::new();
+
+    // Have all/none replace the bitfield when possible
+    if fast && have.iter().all(|val| *val) {
+        buf.extend(Message::build_have_all());
+    }
+    else if fast && !have.iter().any(|val| *val) {
+        buf.extend(Message::build_have_none());
+    }
+    else if have.iter().any(|val| *val) {
+        buf.extend(Message::build_bitfield(helpers::bin_to_u8(&have)));
+    }
+
+    // Pieces the peer may request even while choked, the set is only defined for IPv4
+    let mut allowed_fast = Vec::new();
+    if let (true, IpAddr::V4(peer_ip)) = (fast, peer_ip) {
+        for index in helpers::allowed_fast_set(ALLOWED_FAST, have.len() as u32, &torrent.info_hash, peer_ip) {
+            if have[index as usize] {
+                buf.extend(Message::build_allowed_fast(index));
+                allowed_fast.push(index);
+            }
+        }
+    }
+
+    let done = *(torrent.wanted_left.lock().await) == 0;
+    if reserved & EXTENSION_BIT != 0 {
+        let mut handshake = ExtendedHandshake {
+            reqq: Some(QUEUE_LIMIT),
+            client: Some(CLIENT_NAME.to_string()),
+            upload_only: done,
+            port: Some(torrent.listen_port),
+            ipv6: helpers::local_ipv6(),
+            ..Default::default()
+        };
+        handshake.m.insert("upload_only".to_string(), UPLOAD_ONLY_ID);
+        buf.extend(handshake.build());
+    }
+
+    // Advertise our DHT node to peers which support it
+    if let Some(dht) = dht {
+        if reserved & DHT_BIT != 0 {
+            buf.extend(Message::build_port(dht.port()));
+        }
+    }
+
+    if !done {
+        buf.extend(Message::build_interested());
+    }
+
+    (buf, allowed_fast)
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, mut state: PeerState, reserved: u64, torrent: Torrent, file: Arc<Vec<(File, u64)>>, dht: Option<Arc<Dht>>) {
+
+    let (freq_ref, down_ref, up_ref, hashes, piece_left, wanted_left) = (torrent.piece_freq.clone(), torrent.downloaded.clone(), torrent.uploaded.clone(), torrent.piece_hashes.clone(), torrent.piece_left.clone(), torrent.wanted_left.clone());
+
+    let piece_no = (*(freq_ref.lock().await)).len();
+    let mut bitfield = vec![false; piece_no];
+    let mut choke = true;
+    let mut am_choking = true;
+    let mut peer_interested = false;
+    let mut queue = RequestQueue::new(state.addr.ip());
+
+    // Fast extension state, pieces we may request while choked and pieces suggested by peer
+    let fast = reserved & FAST_BIT != 0;
+    let mut allowed_fast: Vec<u32> = Vec::new();
+    let mut suggested: Vec<u32> = Vec::new();
+
+    let peer = state.addr;
+    let peer_ip = peer.ip();
+
+    // Super seeding only starts once we have every piece
+    let seeding = *(torrent.piece_left.lock().await) == 0;
+    let super_seed = torrent.super_seed.clone().filter(|_| seeding);
+    let mut offered: Vec<u32> = Vec::new();
+
+    let (setup, our_allowed_fast) = initial_messages(&torrent, fast, reserved, peer_ip, &dht, super_seed.is_some()).await;
+    if stream.write_all(&setup).await.is_err() {
+        return;
+    }
+    state.am_interested = *(wanted_left.lock().await) != 0;
+
+    // Upload only state (BEP 21), ours was in the extension handshake if we were already done,
+    // the peer's id for the message, and whether the peer wants nothing more either
+    let mut upload_only_sent = !state.am_interested;
+    let mut peer_upload_only_id = None;
+    let mut peer_upload_only = false;
+
+    // Totals at the last registry update, for the rates
+    let mut published = Instant::now();
+    let mut last_totals = (0, 0);
+
+    let mut reader = MessageReader::new();
+    let mut last_msg = Instant::now();
+
+    loop {
+        
+
+        // Wake up every second to look after outstanding requests even if the peer is silent
+        let msg = match timeout(Duration::from_secs(1), reader.next(&mut stream)).await {
+            Ok(Some(msg)) => {
+                last_msg = Instant::now();
+                msg
+            },
+            Err(_) if last_msg.elapsed() < Duration::from_secs(120) => Vec::new(),
+            _ => {
+                release_blocks(&freq_ref, &queue).await;
+                return;
+            }
+        };
+
+        
+        // Read id of message
+        let mut id = None;
+        if !msg.is_empty() { id = Some(msg[0]); }
+
+        match id {
+            None => {
+                // keep-alive
+            },
+            Some(0) => {
+
+                // choke, requests outside the allowed fast set will not be answered
+                choke = true;
+                let (kept, dropped) = queue.pending.drain(..).partition(|req| fast && allowed_fast.contains(&req.index));
+                queue.pending = kept;
+                release_requests(&freq_ref, &dropped, peer_ip).await;
+
+            },
+            Some(1) => {
+                // unchoke
+                choke = false;
+            },
+            Some(2) => {
+
+                // Interested
+                peer_interested = true;
+                am_choking = false;
+                if stream.write_all(&Message::build_unchoke()).await.is_err() {
+                    release_blocks(&freq_ref, &queue).await;
+                    return;
+                }
+
+            },
+            Some(3) => {
+                // not-interested
+                peer_interested = false;
+            },
+            Some(4) => {
+
+                // have
+                if let Some(piece_index) = Message::read_piece_index(&msg).filter(|index| (*index as usize) < piece_no && !bitfield[*index as usize]) {
+                    (*(freq_ref.lock().await))[piece_index as usize].ref_no += 1;
+                    bitfield[piece_index as usize] = true;
+
+                    if let Some(seed) = &super_seed {
+                        seed.lock().await.on_have(piece_index, peer_ip);
+                    }
+                }
+
+            },
+            Some(5) => {
+
+                //bitfield
+                let mut freq_arr = freq_ref.lock().await;
+                for (i, byte) in msg.iter().enumerate().skip(1) {
+                    for (j, val) in helpers::u8_to_bin(*byte).iter().enumerate() {
+
+                        if !val { continue; }
+                        let ind = (i -1)*8 + j;
+                        if ind >= bitfield.len() {
+                            break;
+                        }
+                        bitfield[ind] = *val;
+                        (*freq_arr)[ind].ref_no += 1;
+
+                    }
+                }
+
+                if let Some(seed) = &super_seed {
+                    let mut seed = seed.lock().await;
+                    for (ind, _) in bitfield.iter().enumerate().filter(|(_, val)| **val) {
+                        seed.on_have(ind as u32, peer_ip);
+                    }
+                }
+
+            },
+            Some(6) => {
+
+                // request, served only if unchoked or in the allowed fast set we gave out
+                if let Some(Message::Request { index, begin, req_length, .. }) = Message::read_request(&msg).filter(|_| msg.len() == 13) {
+
+                    let mut block = None;
+                    // A super seed only serves the pieces it revealed to this peer
+                    let revealed = super_seed.is_none() || offered.contains(&index);
+                    if (!am_choking || our_allowed_fast.contains(&index)) && revealed {
+                        block = read_block(&freq_ref, &file, index, begin, req_length).await;
+                    }
+
+                    let res = match block {
+                        Some(block) => {
+                            *(up_ref.lock().await) += block.len() as u64;
+                            state.uploaded += block.len() as u64;
+                            stream.write_all(&Message::build_piece(index, begin, block)).await
+                        },
+                        None if fast => stream.write_all(&Message::build_reject(index, begin, req_length)).await,
+                        None => Ok(())
+                    };
+                    if res.is_err() {
+                        release_blocks(&freq_ref, &queue).await;
+                        return;
+                    }
+                }
+
+            },
+            Some(7) => {
+
+                if msg.len() < 9 {
+                    release_blocks(&freq_ref, &queue).await;
+                    return;
+                }
+
+                *(down_ref.lock().await) += (msg.len() - 9) as u64;
+                state.downloaded += (msg.len() - 9) as u64;
+
+                let (index, block, finished) = match write_to_file(&msg, &file, &freq_ref, peer_ip).await {
+                    Some(res) => res,
+                    None => continue
+                };
+                queue.on_block(index, block, msg.len() - 9);
+
+                // Last block of the piece arrived, check its hash
+                if finished {
+
+                    let (piece_length, offset) = {
+                        let freq = freq_ref.lock().await;
+                        ((*freq)[index as usize].length, (*freq)[index as usize].blocks[0].offset)
+                    };
+
+                    let valid = verify_piece(piece_length, offset, file.clone(), &(*hashes)[index as usize]);
+                    let mut freq = freq_ref.lock().await;
+                    let piece = &mut (*freq)[index as usize];
+                    if valid {
+                        piece.completed = true;
+                        piece.single_source = false;
+                        piece.owner = None;
+                        *(piece_left.lock().await) -= 1;
+                        if piece.wanted {
+                            *(wanted_left.lock().await) -= 1;
+                        }
+                    }
+                    else {
+                        let contributors: HashSet<IpAddr> = piece.blocks.iter().filter_map(|block| block.from).collect();
+                        for block in &mut piece.blocks {
+                            block.is_req = false;
+                            block.received = false;
+                            block.from = None;
+                        }
+                        piece.owner = None;
+
+                        // One sender is proof, otherwise strike them all and fetch the piece from a single peer
+                        let mut bans = torrent.bans.lock().await;
+                        if contributors.len() == 1 {
+                            bans.ban(*contributors.iter().next().unwrap());
+                            piece.single_source = false;
+                        }
+                        else {
+                            for ip in &contributors {
+                                bans.strike(*ip);
+                            }
+                            piece.single_source = true;
+                        }
+                    }
+
+                }
+
+            },
+            Some(8) => {
+                // cancel, requests are answered as soon as they arrive
+            },
+            Some(9) => {
+
+                // port, add the peer's DHT node to our routing table, the DHT runs over IPv4
+                if let (Some(dht), 3, IpAddr::V4(ip)) = (&dht, msg.len(), peer_ip) {
+                    if let Message::Port { listen_port, .. } = Message::read_port(&msg) {
+                        let dht = dht.clone();
+                        tokio::spawn(async move {
+                            dht.ping(SocketAddrV4::new(ip, listen_port)).await;
+                        });
+                    }
+                }
+
+            },
+            Some(0x0D..=0x11) if !fast => {
+                // fast extension messages without negotiating it
+                release_blocks(&freq_ref, &queue).await;
+                return;
+            },
+            Some(0x0D) => {
+
+                // suggest piece
+                if let Some(index) = Message::read_piece_index(&msg).filter(|index| (*index as usize) < piece_no) {
+                    if !suggested.contains(&index) {
+                        suggested.push(index);
+                    }
+                }
+
+            },
+            Some(0x0E) => {
+
+                // have all
+                let mut freq_arr = freq_ref.lock().await;
+                for (ind, val) in bitfield.iter_mut().enumerate() {
+                    if !*val {
+                        *val = true;
+                        (*freq_arr)[ind].ref_no += 1;
+                    }
+                }
+
+            },
+            Some(0x0F) => {
+                // have none
+            },
+            Some(0x10) => {
+
+                // reject request, release the block so it can be requested again unless another peer holds it
+                if let Some(Message::RejectRequest { index, begin, .. }) = Message::read_reject(&msg).filter(|_| msg.len() == 13) {
+                    if let Some(req) = queue.remove(index, begin / BLOCK_SIZE) {
+                        release_requests(&freq_ref, &[req], peer_ip).await;
+                    }
+                }
+
+            },
+            Some(0x11) => {
+
+                // allowed fast
+                if let Some(index) = Message::read_piece_index(&msg).filter(|index| (*index as usize) < piece_no) {
+                    if !allowed_fast.contains(&index) {
+                        allowed_fast.push(index);
+                    }
+                }
+
+            },
+            Some(20) => {
+
+                // extension protocol, the handshake and upload only
+                match Message::read_extended(&msg) {
+                    Some((0, payload)) => {
+                        if let Some(handshake) = ExtendedHandshake::parse(payload) {
+                            if let Some(reqq) = handshake.reqq {
+                                queue.set_limit(reqq);
+                            }
+                            state.client = client_name(&state.peer_id, handshake.client.as_deref());
+                            peer_upload_only_id = handshake.m.get("upload_only").copied();
+                            peer_upload_only = handshake.upload_only;
+                        }
+                    },
+                    Some((id, payload)) if id == UPLOAD_ONLY_ID => {
+                        peer_upload_only = payload.first().is_some_and(|val| *val != 0);
+                    },
+                    _ => {}
+                }
+
+            },
+            _ => {
+                release_blocks(&freq_ref, &queue).await;
+                return;
+            }
+        }
+
+        // Refresh this peer's entry in the registry
+        let elapsed = published.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            state.download_rate = (state.downloaded - last_totals.0) as f64 / elapsed.as_secs_f64();
+            state.upload_rate = (state.uploaded - last_totals.1) as f64 / elapsed.as_secs_f64();
+            last_totals = (state.downloaded, state.uploaded);
+
+            state.am_choking = am_choking;
+            state.peer_choking = choke;
+            state.peer_interested = peer_interested;
+            state.outstanding = queue.pending.len();
+            state.progress = bitfield.iter().filter(|val| **val).count() as f64 / piece_no.max(1) as f64;
+
+            torrent.connections.lock().await.insert(peer, state.clone());
+            published = Instant::now();
+
+            if torrent.bans.lock().await.is_banned(peer.ip()) {
+                release_blocks(&freq_ref, &queue).await;
+                return;
+            }
+        }
+
+        // Every wanted piece is here, stop asking for more so the peer spends its unchoke slots elsewhere
+        if !upload_only_sent && *(wanted_left.lock().await) == 0 {
+            let mut buf = Message::build_uninterested();
+            if let Some(id) = peer_upload_only_id {
+                buf.extend(Message::build_extended(id, &[1]));
+            }
+            if stream.write_all(&buf).await.is_err() {
+                release_blocks(&freq_ref, &queue).await;
+                return;
+            }
+            state.am_interested = false;
+            upload_only_sent = true;
+        }
+
+        // Neither side wants anything from the other
+        if upload_only_sent && peer_upload_only {
+            release_blocks(&freq_ref, &queue).await;
+            return;
+        }
+
+        // Reveal the next piece once the last one we showed this peer has spread to another peer
+        if let Some(seed) = &super_seed {
+            let mut seed = seed.lock().await;
+            let next = match offered.last() {
+                Some(index) => bitfield[*index as usize] && seed.spread(*index, peer_ip),
+                None => true
+            };
+            if next {
+                if let Some(index) = seed.offer(&bitfield) {
+                    offered.push(index);
+                    if stream.write_all(&Message::build_have(index)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+
+        // Hand blocks that took too long back to the picker
+        let expired = queue.expire(Duration::from_secs(SNUB_TIMEOUT));
+        if !expired.is_empty() {
+            let cancels: Vec<u8> = expired.iter().flat_map(|req| Message::build_cancel(req.index, req.block * BLOCK_SIZE, req.length)).collect();
+            release_requests(&freq_ref, &expired, peer_ip).await;
+            if stream.write_all(&cancels).await.is_err() {
+                release_blocks(&freq_ref, &queue).await;
+                return;
+            }
+        }
+
+        // Cancel duplicate requests for blocks another peer delivered first
+        if queue.endgame && cancel_received(&freq_ref, &mut stream, &mut queue).await.is_err() {
+            release_blocks(&freq_ref, &queue).await;
+            return;
+        }
+
+        // While choked only pieces from the allowed fast set can be requested
+        let can_request = !choke || allowed_fast.iter().any(|index| bitfield[*index as usize]);
+        if can_request && queue.pending.len() < queue.depth {
+
+            let allowed = if choke { Some(allowed_fast.as_slice()) } else { None };
+            if !make_request(freq_ref.lock().await, &mut stream, &bitfield, allowed, &suggested, &mut queue).await {
+                release_blocks(&freq_ref, &queue).await;
+                return;
+            }
+
+            // Nothing left to exchange with this peer
+            if queue.pending.is_empty() && !choke && !peer_interested {return;}
+
+        }
+
+    }
+
+}
+
+// Outstanding block requests to one peer, topped up to a depth following the bandwidth-delay product
+struct RequestQueue {
+    // IP of the peer
+    peer: IpAddr,
+    pending: Vec<Request>,
+    depth: usize,
+    // Most requests the peer accepts at once (reqq)
+    limit: usize,
+    // Download rate in bytes per second and bytes received since it was last updated
+    rate: f64,
+    received: usize,
+    since: Instant,
+    // Lowest recent delay between a request and its block
+    min_rtt: Option<Duration>,
+    // Some outstanding blocks were also requested from other peers
+    endgame: bool,
+    last_block: Instant,
+    snubbed: bool
+}
+
+struct Request {
+    index: u32,
+    block: u32,
+    length: u32,
+    sent: Instant,
+    // Block was marked as requested for us, false for speculative requests of a snubbed peer
+    owned: bool
+}
+
+impl RequestQueue {
+
+    fn new(peer: IpAddr) -> RequestQueue {
+        RequestQueue {
+            peer,
+            pending: Vec::new(),
+            depth: 4,
+            limit: QUEUE_LIMIT as usize,
+            rate: 0.0,
+            received: 0,
+            since: Instant::now(),
+            min_rtt: None,
+            endgame: false,
+            last_block: Instant::now(),
+            snubbed: false
+        }
+    }
+
+    fn set_limit(&mut self, reqq: u32) {
+        self.limit = (reqq as usize).clamp(1, QUEUE_LIMIT as usize);
+        self.depth = self.depth.min(self.limit);
+    }
+
+    fn contains(&self, index: u32, block: u32) -> bool {
+        self.pending.iter().any(|req| req.index == index && req.block == block)
+    }
+
+    fn push(&mut self, index: u32, block: u32, length: u32, owned: bool) {
+        self.pending.push(Request { index, block, length, sent: Instant::now(), owned });
+    }
+
+    // Take out requests outstanding for longer than the timeout, marking the peer snubbed if nothing arrived meanwhile
+    fn expire(&mut self, limit: Duration) -> Vec<Request> {
+
+        let (expired, kept) = self.pending.drain(..).partition(|req: &Request| req.sent.elapsed() > limit);
+        self.pending = kept;
+
+        if !expired.is_empty() && self.last_block.elapsed() > limit {
+            self.snubbed = true;
+            self.depth = 1;
+        }
+        expired
+    }
+
+    // Remove a request, None if it was not outstanding
+    fn remove(&mut self, index: u32, block: u32) -> Option<Request> {
+        let pos = self.pending.iter().position(|req| req.index == index && req.block == block)?;
+        Some(self.pending.remove(pos))
+    }
+
+    fn on_block(&mut self, index: u32, block: u32, length: usize) {
+
+        self.last_block = Instant::now();
+        if self.snubbed {
+            self.snubbed = false;
+            self.depth = 2;
+        }
+
+        if let Some(pos) = self.pending.iter().position(|req| req.index == index && req.block == block) {
+            let rtt = self.pending.remove(pos).sent.elapsed();
+
+            // Let the minimum drift up slowly so it follows route changes
+            self.min_rtt = Some(match self.min_rtt {
+                Some(min) if min <= rtt => min + (rtt - min) / 64,
+                _ => rtt
+            });
+        }
+
+        self.received += length;
+        let elapsed = self.since.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            let sample = self.received as f64 / elapsed.as_secs_f64();
+            self.rate = if self.rate == 0.0 { sample } else { 0.6 * self.rate + 0.4 * sample };
+            self.received = 0;
+            self.since = Instant::now();
+
+            if let (Some(rtt), false) = (self.min_rtt, self.snubbed) {
+                self.depth = bdp_depth(self.rate, rtt, self.limit);
+            }
+        }
+    }
+}
+
+// Requests needed to keep the link busy for one round trip, with some slack for jitter
+fn bdp_depth(rate: f64, rtt: Duration, limit: usize) -> usize {
+    let blocks = (rate * rtt.as_secs_f64() / BLOCK_SIZE as f64).ceil() as usize;
+    (blocks + 2).clamp(2, limit)
+}
+
+// Send Cancel for outstanding requests whose block already arrived
+async fn cancel_received<S: AsyncWrite + Unpin>(freq_ref: &Arc<Mutex<Vec<Piece>>>, stream: &mut S, queue: &mut RequestQueue) -> std::io::Result<()> {
+
+    let mut buf = Vec::new();
+    {
+        let freq = freq_ref.lock().await;
+        queue.pending.retain(|req| {
+            let block = &(*freq)[req.index as usize].blocks[req.block as usize];
+            if block.received {
+                buf.extend(Message::build_cancel(req.index, req.block * BLOCK_SIZE, req.length));
+            }
+            !block.received
+        });
+    }
+
+    if buf.is_empty() {
+        return Ok(());
+    }
+    stream.write_all(&buf).await
+
+}
+
+// Mark outstanding blocks as available to other peers
+async fn release_blocks(freq_ref: &Arc<Mutex<Vec<Piece>>>, queue: &RequestQueue) {
+    release_requests(freq_ref, &queue.pending, queue.peer).await;
+}
+
+async fn release_requests(freq_ref: &Arc<Mutex<Vec<Piece>>>, requests: &[Request], peer: IpAddr) {
+
+    let mut freq = freq_ref.lock().await;
+    for req in requests.iter().filter(|req| req.owned) {
+        let piece = &mut (*freq)[req.index as usize];
+
+        // A single source piece starts over with whoever picks it up next
+        if piece.single_source && piece.owner == Some(peer) {
+            for block in &mut piece.blocks {
+                block.is_req = false;
+                block.received = false;
+                block.from = None;
+            }
+            piece.owner = None;
+            continue;
+        }
+
+        let block = &mut piece.blocks[req.block as usize];
+        if !block.received {
+            block.is_req = false;
+        }
+    }
+
+}
+
+// Read buf.len() bytes starting at offset of the torrent, spanning file boundaries
+fn read_files(file: &[(File, u64)], mut offset: u64, buf: &mut [u8]) -> bool {
+
+    let mut start = 0;
+    let mut read = 0;
+    for (f, size) in file {
+        if read == buf.len() {
+            break;
+        }
+        if offset < start + size {
+            let len = (buf.len() - read).min((start + size - offset) as usize);
+            if f.read_exact_at(&mut buf[read..(read + len)], offset - start).is_err() {
+                return false;
+            }
+            read += len;
+            offset += len as u64;
+        }
+        start += size;
+    }
+
+    read == buf.len()
+}
+
+// Write data starting at offset of the torrent, spanning file boundaries
+pub fn write_files(file: &[(File, u64)], mut offset: u64, data: &[u8]) -> bool {
+
+    let mut start = 0;
+    let mut written = 0;
+    for (f, size) in file {
+        if written == data.len() {
+            break;
+        }
+        if offset < start + size {
+            let len = (data.len() - written).min((start + size - offset) as usize);
+            if f.write_all_at(&data[written..(written + len)], offset - start).is_err() {
+                return false;
+            }
+            written += len;
+            offset += len as u64;
+        }
+        start += size;
+    }
+
+    written == data.len()
+}
+
+pub fn verify_piece(piece_length: u64, offset: u64, file: Arc<Vec<(File,u64)>>, hash: &[u8]) -> bool {
+
+    let mut buf = vec![0u8; piece_length as usize];
+
+    // Return false if error in reading
+    if !read_files(&file, offset, &mut buf) {
+        return false;
+    }
+
+    // Generate hash
+    let mut hasher = Sha1::new();
+    hasher.update(&buf);
+
+    // Validate hash
+    (*hash) == hasher.digest().bytes()
+
+}
+
+// Read a block of a completed piece to upload
+async fn read_block(freq_ref: &Arc<Mutex<Vec<Piece>>>, file: &Arc<Vec<(File, u64)>>, index: u32, begin: u32, length: u32) -> Option<Vec<u8>> {
+
+    let offset;
+    {
+        let freq = freq_ref.lock().await;
+        let piece = (*freq).get(index as usize)?;
+        // Widen before adding, begin comes straight from the peer
+        if !piece.completed || length > BLOCK_SIZE || begin as u64 + length as u64 > piece.length {
+            return None;
+        }
+        offset = piece.blocks[0].offset + begin as u64;
+    }
+
+    let mut buf = vec![0u8; length as usize];
+    if read_files(file, offset, &mut buf) {
+        Some(buf)
+    }
+    else {
+        None
+    }
+
+}
+
+// Top up the request queue, finishing pieces in progress before starting the rarest available one
+async fn make_request<S: AsyncWrite + Unpin>(mut freq_arr: tokio::sync::MutexGuard<'_, Vec<Piece>>, stream: &mut S, bitfield: &[bool], allowed: Option<&[u32]>, suggested: &[u32], queue: &mut RequestQueue) -> bool {
+
+    // Single source pieces go to one peer, and never to a snubbed one
+    let peer = queue.peer;
+    let snubbed = queue.snubbed;
+    let has = |i: usize, piece: &Piece| {
+        bitfield[i] && piece.wanted && !piece.completed && allowed.is_none_or(|allowed| allowed.contains(&(i as u32)))
+            && (!piece.single_source || (!snubbed && piece.owner.is_none_or(|owner| owner == peer)))
+    };
+    let available = |i: usize, piece: &Piece| has(i, piece) && piece.blocks.iter().any(|block| !block.is_req);
+
+    let mut buf = Vec::new();
+    while queue.pending.len() < queue.depth {
+
+        // Pieces partly requested or received come first, then the ones suggested by the peer
+        let mut to_req = (*freq_arr).iter().enumerate()
+            .find(|(i, piece)| available(*i, piece) && piece.blocks.iter().any(|block| block.is_req))
+            .map(|(i, _)| i);
+
+        if to_req.is_none() {
+            to_req = suggested.iter().map(|index| *index as usize).find(|index| available(*index, &(*freq_arr)[*index]));
+        }
+
+        // Find piece with minimum nodes
+        if to_req.is_none() {
+            let mut mn = u16::MAX;
+            for (i, piece) in (*freq_arr).iter().enumerate() {
+                if piece.ref_no < mn && available(i, piece) {
+                    to_req = Some(i);
+                    mn = piece.ref_no;
+                }
+            }
+        }
+
+        let ind = match to_req {
+            Some(ind) => ind,
+            None => break
+        };
+
+        // A snubbed peer gets no blocks of its own, others may still request the ones it was asked for
+        let before = queue.pending.len();
+        if (*freq_arr)[ind].single_source {
+            (*freq_arr)[ind].owner = Some(peer);
+        }
+        for (j, block) in (*freq_arr)[ind].blocks.iter_mut().enumerate() {
+            if queue.pending.len() >= queue.depth {
+                break;
+            }
+            if !block.is_req && !queue.contains(ind as u32, j as u32) {
+                block.is_req = !queue.snubbed;
+                buf.extend(Message::build_request(ind as u32, (j as u32)*BLOCK_SIZE, block.length as u32));
+                queue.push(ind as u32, j as u32, block.length as u32, !queue.snubbed);
+            }
+        }
+        if queue.pending.len() == before {
+            break;
+        }
+    }
+
+    // Endgame, every missing block is requested somewhere so ask this peer for the ones it has too
+    if queue.pending.len() < queue.depth && !(*freq_arr).iter().any(|piece| piece.wanted && !piece.completed && piece.blocks.iter().any(|block| !block.is_req)) {
+        'pieces: for (i, piece) in (*freq_arr).iter().enumerate() {
+            if !has(i, piece) {
+                continue;
+            }
+            for (j, block) in piece.blocks.iter().enumerate() {
+                if queue.pending.len() >= queue.depth {
+                    break 'pieces;
+                }
+                if !block.received && !queue.contains(i as u32, j as u32) {
+                    buf.extend(Message::build_request(i as u32, (j as u32)*BLOCK_SIZE, block.length as u32));
+                    queue.push(i as u32, j as u32, block.length as u32, false);
+                    queue.endgame = true;
+                }
+            }
+        }
+    }
+
+    buf.is_empty() || stream.write_all(&buf).await.is_ok()
+}
+
+// Store a received block, returns its piece and block number and whether it was the last one missing
+async fn write_to_file(msg: &[u8], file: &Arc<Vec<(File, u64)>>, freq_ref: &Arc<Mutex<Vec<Piece>>>, from: IpAddr) -> Option<(u32, u32, bool)> {
+
+    // piece
+    let buf = &mut &msg[1..];
+    let index = ReadBytesExt::read_u32::<BigEndian>(buf).ok()?;
+    let begin = ReadBytesExt::read_u32::<BigEndian>(buf).ok()?;
+    let block = begin / BLOCK_SIZE;
+    let data = &msg[9..];
+
+    let offset = {
+        let freq = freq_ref.lock().await;
+        let piece = (*freq).get(index as usize)?;
+        let blk = piece.blocks.get(block as usize)?;
+        if !begin.is_multiple_of(BLOCK_SIZE) || blk.length != data.len() as u64 {
+            return None;
+        }
+        // Duplicate of a block we already have
+        if piece.completed || blk.received {
+            return Some((index, block, false));
+        }
+        blk.offset
+    };
+
+    // Writing to file at different locations
+    if !write_files(file, offset, data) {
+        return None;
+    }
+
+    let mut freq = freq_ref.lock().await;
+    let piece = &mut (*freq)[index as usize];
+    let was_missing = !piece.blocks[block as usize].received;
+    piece.blocks[block as usize].is_req = true;
+    piece.blocks[block as usize].received = true;
+    piece.blocks[block as usize].from = Some(from);
+
+    Some((index, block, was_missing && piece.blocks.iter().all(|blk| blk.received)))
+}
+
+// Number of peers listed below the totals
+static PEERS_SHOWN: usize = 10;
+
+// Table of the trackers, with what went wrong under each one
+fn tracker_lines(trackers: &[Tracker], now: Instant) -> Vec<String> {
+
+    let mut lines = Vec::new();
+    if !trackers.is_empty() {
+        lines.push(format!("\n{:<40} {:>4} {:<8} {:>8} {:>6} {:>6} {:>6} {:>6}", "Tracker", "Tier", "Status", "Last", "Next", "Peers", "Seeds", "Leech"));
+    }
+    let ago = |at: Option<Instant>| at.map_or("-".to_string(), |at| format!("{}s", now.saturating_duration_since(at).as_secs()));
+    let count = |count: Option<u32>| count.map_or("-".to_string(), |count| count.to_string());
+    for tracker in trackers {
+        let url: String = tracker.url.chars().take(40).collect();
+        let next = format!("{}s", tracker.next_announce.saturating_duration_since(now).as_secs());
+        lines.push(format!("{:<40} {:>4} {:<8} {:>8} {:>6} {:>6} {:>6} {:>6}",
+            url, tracker.tier, tracker.status(), ago(tracker.last_attempt), next, tracker.peers, count(tracker.seeders), count(tracker.leechers)));
+        if let Some(error) = &tracker.error {
+            lines.push(format!("  failed: {}", error));
+        }
+        if let Some(warning) = &tracker.warning {
+            lines.push(format!("  warns: {}", warning));
+        }
+    }
+    lines
+}
+
+pub async fn download_print(downloaded: Arc<Mutex<u64>>, connections: PeerRegistry, piece_left: Arc<Mutex<u16>>, trackers: TrackerList) {
+    let mut stdout = stdout();
+
+    stdout.execute(cursor::Hide).unwrap();
+
+    let mut last = 0;
+
+    loop {
+
+        let now; 
+        let mut peers: Vec<PeerState>;
+        let left;
+        let notes: Vec<String>;
+        {
+            now = *(downloaded.lock().await);
+            peers = (*(connections.lock().await)).values().cloned().collect();
+            left = *(piece_left.lock().await);
+            notes = tracker_lines(&trackers.lock().await, Instant::now());
+        }
+
+        if left == 0 {
+            break;
+        }
+
+        let tot = (now as f64) / 1048756.0;
+        let speed = ((now - last) as f64) / (1048756.0*3.0);
+
+        let mut out = format!("\rDownloaded: {:.2} MB\nSpeed: {:.2} MB/s\nConnections: {}/{}\nPieces Left: {}", tot, speed, peers.len(), CONN_LIMIT, left);
+        for note in notes {
+            out += "\n";
+            out += &note;
+        }
+
+        // Fastest peers first
+        peers.sort_by(|a, b| b.download_rate.total_cmp(&a.download_rate));
+        if !peers.is_empty() {
+            out += &format!("\n\n{:<21} {:<20} {:<7} {:>10} {:>10} {:>4} {:>6}", "Address", "Client", "Flags", "Down KB/s", "Up KB/s", "Reqs", "Has");
+        }
+        for peer in peers.iter().take(PEERS_SHOWN) {
+            let client: String = peer.client.as_deref().unwrap_or("").chars().take(20).collect();
+            out += &format!("\n{:<21} {:<20} {:<7} {:>10.1} {:>10.1} {:>4} {:>5.1}%",
+                peer.address(), client, peer.flags(), peer.download_rate / 1024.0, peer.upload_rate / 1024.0, peer.outstanding, peer.progress * 100.0);
+        }
+
+        stdout.write_all(out.as_bytes()).unwrap();
+        
+        stdout.execute(cursor::MoveUp((out.lines().count() - 1) as u16)).unwrap();
+        stdout.queue(terminal::Clear(terminal::ClearType::FromCursorDown)).unwrap();
+        last = now;
+        sleep(time::Duration::from_secs(3)).await;
+    }
+    stdout.execute(cursor::Show).unwrap();
+
+    println!("Done!");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::{net::IpAddr, sync::Arc, time::{Duration, Instant}};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, sync::Mutex, time::{sleep, timeout}};
+    use crate::{
+        message::{ExtendedHandshake, Message, EXTENSION_BIT},
+        peer::{PeerSource, PeerState},
+        torrent_parser::{test_torrent, Torrent},
+        tracker::Tracker
+    };
+    use super::{bdp_depth, cancel_received, handle_connection, make_request, read_block, read_files, release_requests, tracker_lines, write_files, RequestQueue, TransportKind};
+
+    // Split a stream of length prefixed messages, keep-alives dropped
+    fn split_messages(mut buf: &[u8]) -> Vec<Vec<u8>> {
+        let mut msgs = Vec::new();
+        while buf.len() >= 4 {
+            let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+            if len > 0 {
+                msgs.push(buf[4..4 + len].to_vec());
+            }
+            buf = &buf[4 + len..];
+        }
+        msgs
+    }
+
+    #[test]
+    fn files_span_test() {
+
+        let dir = std::env::temp_dir().join(format!("ft_span_{}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        let file: Vec<_> = [3u64, 5, 4].iter().enumerate().map(|(i, size)| {
+            (OpenOptions::new().read(true).write(true).create(true).truncate(true).open(dir.join(i.to_string())).unwrap(), *size)
+        }).collect();
+
+        let data: Vec<u8> = (0..12).collect();
+        assert!(write_files(&file, 0, &data));
+        assert_eq!(fs::read(dir.join("1")).unwrap(), vec![3, 4, 5, 6, 7]);
+
+        let mut buf = [0; 6];
+        assert!(read_files(&file, 2, &mut buf));
+        assert_eq!(buf, [2, 3, 4, 5, 6, 7]);
+        assert!(!read_files(&file, 10, &mut buf));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_block_test() {
+
+        let path = std::env::temp_dir().join(format!("ft_block_{}", rand::random::<u32>()));
+        let data: Vec<u8> = (0..32768u32).map(|i| i as u8).collect();
+        fs::write(&path, &data).unwrap();
+        let file = Arc::new(vec![(OpenOptions::new().read(true).open(&path).unwrap(), 32768)]);
+
+        let mut freq = Torrent::build_piece_freq(2, 1, 32768, 32768);
+        freq[0].completed = true;
+        let freq = Arc::new(Mutex::new(freq));
+
+        assert_eq!(read_block(&freq, &file, 0, 16384, 16384).await, Some(data[16384..].to_vec()));
+        assert_eq!(read_block(&freq, &file, 0, 16385, 16384).await, None);
+        assert_eq!(read_block(&freq, &file, 1, 0, 16384).await, None);
+
+        // Would wrap around to the start of the piece if added in 32 bits
+        assert_eq!(read_block(&freq, &file, 0, u32::MAX - 100, 16384).await, None);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn bdp_depth_test() {
+
+        // 1 MiB/s over 100ms needs 7 blocks in flight, plus slack
+        assert_eq!(bdp_depth(1048576.0, Duration::from_millis(100), 50), 9);
+        assert_eq!(bdp_depth(0.0, Duration::from_millis(100), 50), 2);
+        assert_eq!(bdp_depth(100e6, Duration::from_millis(200), 50), 50);
+        assert_eq!(bdp_depth(100e6, Duration::from_millis(200), 2), 2);
+
+    }
+
+    #[test]
+    fn expire_test() {
+
+        let mut queue = RequestQueue::new(IpAddr::from([0, 0, 0, 0]));
+        queue.push(0, 0, 16384, true);
+        queue.push(0, 1, 16384, true);
+        queue.pending[0].sent -= Duration::from_secs(30);
+
+        // Something arrived recently, so only the old request goes
+        assert_eq!(queue.expire(Duration::from_secs(20)).len(), 1);
+        assert!(!queue.snubbed);
+
+        queue.last_block -= Duration::from_secs(30);
+        queue.pending[0].sent -= Duration::from_secs(30);
+        assert_eq!(queue.expire(Duration::from_secs(20)).len(), 1);
+        assert!(queue.snubbed);
+        assert_eq!(queue.depth, 1);
+
+        queue.on_block(0, 1, 16384);
+        assert!(!queue.snubbed);
+
+    }
+
+    #[tokio::test]
+    async fn endgame_test() {
+
+        // Two pieces of two blocks, both peers have everything
+        let freq = Arc::new(Mutex::new(Torrent::build_piece_freq(2, 2, 32768, 65536)));
+        let bitfield = [true, true];
+        let (mut ours, mut theirs) = tokio::io::duplex(65536);
+        let mut buf = vec![0; 65536];
+
+        // The first peer takes every block
+        let mut first = RequestQueue::new(IpAddr::from([10, 0, 0, 1]));
+        assert!(make_request(freq.lock().await, &mut ours, &bitfield, None, &[], &mut first).await);
+        let len = theirs.read(&mut buf).await.unwrap();
+        assert_eq!(split_messages(&buf[..len]).len(), 4);
+        assert!(!first.endgame);
+        assert!(freq.lock().await.iter().all(|piece| piece.blocks.iter().all(|block| block.is_req)));
+
+        // Nothing is left unrequested, so the second peer is asked for the same blocks
+        let mut second = RequestQueue::new(IpAddr::from([10, 0, 0, 2]));
+        assert!(make_request(freq.lock().await, &mut ours, &bitfield, None, &[], &mut second).await);
+        let len = theirs.read(&mut buf).await.unwrap();
+        let requests = split_messages(&buf[..len]);
+        assert_eq!(requests.len(), 4);
+        assert!(second.endgame);
+        assert!(second.pending.iter().all(|req| !req.owned));
+        assert_eq!(requests[1], Message::build_request(0, 16384, 16384)[4..]);
+
+        // A block arrived from the second peer, the first one is told to drop it
+        {
+            let mut freq = freq.lock().await;
+            freq[0].blocks[1].received = true;
+        }
+        cancel_received(&freq, &mut ours, &mut first).await.unwrap();
+        let len = theirs.read(&mut buf).await.unwrap();
+        assert_eq!(split_messages(&buf[..len]), vec![Message::build_cancel(0, 16384, 16384)[4..].to_vec()]);
+        assert_eq!(first.pending.len(), 3);
+        assert!(!first.pending.iter().any(|req| req.index == 0 && req.block == 1));
+
+        // A rejected duplicate leaves the block with the peer it belongs to
+        let req = second.remove(1, 0).unwrap();
+        assert!(!req.owned);
+        release_requests(&freq, &[req], second.peer).await;
+        assert!(freq.lock().await[1].blocks[0].is_req);
+        let req = first.remove(1, 0).unwrap();
+        release_requests(&freq, &[req], first.peer).await;
+        assert!(!freq.lock().await[1].blocks[0].is_req);
+
+    }
+
+    #[tokio::test]
+    async fn upload_only_test() {
+
+        let mut torrent = test_torrent("http://t/announce", 2).await;
+        torrent.listen_port = 7000;
+        let wanted_left = torrent.wanted_left.clone();
+        let state = PeerState::new("10.0.0.1:6881".parse().unwrap(), [2; 20], PeerSource::Incoming, TransportKind::Tcp, false);
+        let (ours, mut theirs) = tokio::io::duplex(65536);
+        let session = tokio::spawn(handle_connection(ours, state, EXTENSION_BIT, torrent, Arc::new(Vec::new()), None));
+
+        // The peer is a seed, wants nothing from us and takes upload only as message 3
+        let mut handshake = ExtendedHandshake { upload_only: true, ..Default::default() };
+        handshake.m.insert("upload_only".to_string(), 3);
+        theirs.write_all(&handshake.build()).await.unwrap();
+
+        // Our wanted pieces finish partway through, so we say so and the session ends
+        sleep(Duration::from_millis(500)).await;
+        *wanted_left.lock().await = 0;
+        timeout(Duration::from_secs(10), session).await.unwrap().unwrap();
+
+        let mut sent = Vec::new();
+        theirs.read_to_end(&mut sent).await.unwrap();
+        let msgs = split_messages(&sent);
+        let position = |msg: &[u8]| msgs.iter().position(|sent| sent == msg).unwrap();
+        assert!(position(&[2]) < position(&[3]));
+        assert_eq!(position(&[20, 3, 1]), position(&[3]) + 1);
+
+        // Our handshake gives the port we are listening on
+        let handshake = msgs.iter().find_map(|msg| msg.strip_prefix(&[20, 0][..]).and_then(ExtendedHandshake::parse)).unwrap();
+        assert_eq!(handshake.port, Some(7000));
+    }
+
+    #[tokio::test]
+    async fn truncated_messages_test() {
+
+        let torrent = test_torrent("http://t/announce", 2).await;
+        let state = PeerState::new("10.0.0.1:6881".parse().unwrap(), [2; 20], PeerSource::Incoming, TransportKind::Tcp, false);
+        let (ours, mut theirs) = tokio::io::duplex(65536);
+        let session = tokio::spawn(handle_connection(ours, state, EXTENSION_BIT, torrent, Arc::new(Vec::new()), None));
+
+        // Short have, request and reject messages are ignored, the session ends cleanly when the peer leaves
+        theirs.write_all(&[0, 0, 0, 3, 4, 0, 0]).await.unwrap();
+        theirs.write_all(&[0, 0, 0, 5, 6, 0, 0, 0, 1]).await.unwrap();
+        theirs.write_all(&[0, 0, 0, 5, 0x10, 0, 0, 0, 1]).await.unwrap();
+        sleep(Duration::from_millis(500)).await;
+        drop(theirs);
+        timeout(Duration::from_secs(10), session).await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn tracker_lines_test() {
+
+        let mut tracker = Tracker::new("http://t/announce".to_string(), 1);
+        tracker.error = Some("no answer".to_string());
+        let lines = tracker_lines(&[tracker], Instant::now());
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("http://t/announce") && lines[1].contains("failed"));
+        assert_eq!(lines[2], "  failed: no answer");
+        assert!(tracker_lines(&[], Instant::now()).is_empty());
+    }
+}
@@ -1,98 +1,280 @@
-use tokio::{net::TcpStream, time::timeout, io::AsyncReadExt};
-
-pub static BLOCK_SIZE: u32 = 16384; //2^14
-pub static CONN_LIMIT: u32 = 100;
-pub static QUEUE_LIMIT: u32 = 50;
-
-// Convert u8 value to String of hex value
-pub fn u8_to_hex(mut val: u8) -> String {
-
-    let mut ans = String::new();
-    for _ in 0..2 {
-        ans.push(if val%16 <= 9 {
-            ('0' as u8+ val%16) as char
-        } else {
-            ('A' as u8 + (val%16 - 10)) as char
-        });
-        val /= 16;
-    }
-    ans.chars().rev().collect()
-
-}
-
-// Convert byte array to url_encoded format
-pub fn u8_to_url(arr: [u8; 20]) -> String {
-    let mut ans = String::new();
-
-    for byte in arr {
-        if (byte >= ('0' as u8) && byte <= ('9' as u8)) 
-        || (byte >= ('A' as u8) && byte <= ('Z' as u8)) 
-        || (byte >= ('a' as u8) && byte <= ('z' as u8))
-        || byte == '.' as u8 || byte == '-' as u8 || byte == '_' as u8 || byte == '~' as u8  {
-            ans.push(byte as char);
-        }
-        else {
-            ans.push('%');
-            ans.push_str(&u8_to_hex(byte));
-        }
-    }
-
-    ans
-}
-
-// Convert u8 value to binary
-pub fn u8_to_bin(n: u8) -> Vec<bool> {
-    let mut s = Vec::new();
-    for i in (0..8).rev() {
-        s.push(n&(1<<i) == 1<<i)
-    }
-    s
-}
-
-// Generate a random peer id
-pub fn gen_random_id() -> [u8; 20] {
-
-    let mut buf: [u8; 20] = [0;20];
-    for i in  0..20 {
-        buf[i] = rand::random();
-    }
-
-    buf
-
-}
-
-// Function which returns message from Tcp Stream only on getting message of entire length provided as input.
-// Will terminate connection if expected length not recieved
-pub async fn on_whole_msg(stream: &mut TcpStream, len: u32) -> Option<Vec<u8>> {
-
-    let mut ret = Vec::new();
-    while ret.len() < len as usize {
-        let mut buf = [0];
-        timeout(tokio::time::Duration::from_secs(20),stream.read_exact(&mut buf)).await.ok()?.ok()?;
-        ret.push(buf[0]);
-    }
-    Some(ret)
-
-}
-
-
-
-#[cfg(test)]
-mod tests {
-    use crate::helpers::{u8_to_bin, u8_to_url};
-
-    #[test]
-    fn u8_to_bin_test() {
-        assert_eq!(vec![true, true, true, true, true, true, true, true], u8_to_bin(255));
-        assert_eq!(vec![false, false, false, false, true, false, false, false], u8_to_bin(8));
-        assert_eq!(vec![false, false, false, false, true, false, true, true], u8_to_bin(11));
-    }
-
-    #[test]
-    fn u8_to_url_test() {
-
-        let arr: [u8; 20] = [ 18 , 52 , 86 , 120 , 154 , 188 , 222 , 241 , 35 , 69 , 103 , 137 , 171 , 205 , 239 , 18 , 52 , 86 , 120 , 154 ] ;
-        assert_eq!(u8_to_url(arr), "%124Vx%9A%BC%DE%F1%23Eg%89%AB%CD%EF%124Vx%9A");
-
-    }
-}
\ No newline at end of file
+use std::{env, io, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket}, path::PathBuf};
+use rand::{Rng, distributions::Alphanumeric};
+use socket2::{Domain, Socket, Type};
+use sha1_smol::Sha1;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub static BLOCK_SIZE: u32 = 16384; //2^14
+pub static CONN_LIMIT: u32 = 100;
+pub static QUEUE_LIMIT: u32 = 50;
+pub static LISTEN_PORT: u16 = 6881;
+// uTP listens on UDP LISTEN_PORT, so the DHT needs a port of its own
+pub static DHT_PORT: u16 = 6882;
+pub static ALLOWED_FAST: usize = 10;
+// Azureus-style client code and version, FastTorrent 0.1.0.0
+pub static PEER_ID_PREFIX: &str = "-FT0100-";
+pub static MAX_MSG_LEN: usize = 1 << 20;
+// Seconds without a requested block before a peer counts as snubbing us
+pub static SNUB_TIMEOUT: u64 = 20;
+
+// Convert u8 value to String of hex value
+pub fn u8_to_hex(mut val: u8) -> String {
+
+    let mut ans = String::new();
+    for _ in 0..2 {
+        ans.push(if val%16 <= 9 {
+            (b'0' + val%16) as char
+        } else {
+            (b'A' + (val%16 - 10)) as char
+        });
+        val /= 16;
+    }
+    ans.chars().rev().collect()
+
+}
+
+// Convert byte array to url_encoded format
+pub fn u8_to_url(arr: [u8; 20]) -> String {
+    let mut ans = String::new();
+
+    for byte in arr {
+        if byte.is_ascii_alphanumeric()
+        || byte == b'.' || byte == b'-' || byte == b'_' || byte == b'~'  {
+            ans.push(byte as char);
+        }
+        else {
+            ans.push('%');
+            ans.push_str(&u8_to_hex(byte));
+        }
+    }
+
+    ans
+}
+
+// Convert u8 value to binary
+pub fn u8_to_bin(n: u8) -> Vec<bool> {
+    let mut s = Vec::new();
+    for i in (0..8).rev() {
+        s.push(n&(1<<i) == 1<<i)
+    }
+    s
+}
+
+// Convert bitfield to bytes, high bit first
+pub fn bin_to_u8(bitfield: &[bool]) -> Vec<u8> {
+    let mut ret = vec![0u8; bitfield.len().div_ceil(8)];
+    for (i, val) in bitfield.iter().enumerate() {
+        if *val {
+            ret[i/8] |= 1 << (7 - i%8);
+        }
+    }
+    ret
+}
+
+// Allowed fast set for a peer as described in BEP 6
+pub fn allowed_fast_set(k: usize, piece_no: u32, info_hash: &[u8; 20], ip: Ipv4Addr) -> Vec<u32> {
+
+    let mut ret = Vec::new();
+    let k = k.min(piece_no as usize);
+
+    let mut x = (u32::from(ip) & 0xFFFFFF00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+
+    while ret.len() < k {
+        x = Sha1::from(&x).digest().bytes().to_vec();
+        for i in 0..5 {
+            if ret.len() >= k {
+                break;
+            }
+            let index = u32::from_be_bytes(x[i*4..(i+1)*4].try_into().unwrap()) % piece_no;
+            if !ret.contains(&index) {
+                ret.push(index);
+            }
+        }
+    }
+
+    ret
+}
+
+// Generate a random peer id
+pub fn gen_random_id() -> [u8; 20] {
+
+    let mut buf: [u8; 20] = [0;20];
+    for byte in buf.iter_mut() {
+        *byte = rand::random();
+    }
+
+    buf
+
+}
+
+// Generate an Azureus-style peer id, the prefix followed by random alphanumeric characters
+pub fn gen_peer_id(prefix: &str) -> [u8; 20] {
+
+    let mut buf: [u8; 20] = [0;20];
+    for byte in buf.iter_mut() {
+        *byte = rand::thread_rng().sample(Alphanumeric);
+    }
+
+    let len = prefix.len().min(20);
+    buf[..len].copy_from_slice(&prefix.as_bytes()[..len]);
+    buf
+
+}
+
+// Directory for state kept between runs, ~/.fasttorrent or the current directory
+pub fn state_dir() -> PathBuf {
+    match env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(".fasttorrent"),
+        None => env::current_dir().unwrap_or_default()
+    }
+}
+
+// Socket on [::] which also takes IPv4 traffic, as IPv4-mapped addresses
+pub fn dual_stack(kind: Type, port: u16) -> io::Result<Socket> {
+
+    let socket = Socket::new(Domain::IPV6, kind, None)?;
+    socket.set_only_v6(false)?;
+    if kind == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    if kind == Type::STREAM {
+        socket.listen(128)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+// Address with IPv4-mapped IPv6 turned back into plain IPv4, so a peer has one form everywhere
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+// Our global IPv6 address, the source the routing table picks for a public destination
+pub fn local_ipv6() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888), 80)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if ip.segments()[0] & 0xE000 == 0x2000 => Some(ip),
+        _ => None
+    }
+}
+
+// Buffers stream data, so waiting for a message can be cut short by a timeout without losing bytes
+pub struct MessageReader {
+    buf: Vec<u8>
+}
+
+impl MessageReader {
+
+    pub fn new() -> MessageReader {
+        MessageReader { buf: Vec::new() }
+    }
+
+    // Next whole message without its length prefix, None if the stream closed or sent garbage
+    pub async fn next<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> Option<Vec<u8>> {
+
+        let mut chunk = [0; 16384];
+        loop {
+            if self.buf.len() >= 4 {
+                let len = u32::from_be_bytes([self.buf[0], self.buf[1], self.buf[2], self.buf[3]]) as usize;
+                if len > MAX_MSG_LEN {
+                    return None;
+                }
+                if self.buf.len() >= 4 + len {
+                    let msg = self.buf[4..(4 + len)].to_vec();
+                    self.buf.drain(..(4 + len));
+                    return Some(msg);
+                }
+            }
+
+            let n = stream.read(&mut chunk).await.ok()?;
+            if n == 0 {
+                return None;
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+
+    }
+}
+
+impl Default for MessageReader {
+    fn default() -> MessageReader {
+        MessageReader::new()
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use tokio::io::AsyncWriteExt;
+    use crate::helpers::{u8_to_bin, u8_to_url, bin_to_u8, allowed_fast_set, canonical_addr, gen_peer_id, MessageReader};
+
+    #[test]
+    fn u8_to_bin_test() {
+        assert_eq!(vec![true, true, true, true, true, true, true, true], u8_to_bin(255));
+        assert_eq!(vec![false, false, false, false, true, false, false, false], u8_to_bin(8));
+        assert_eq!(vec![false, false, false, false, true, false, true, true], u8_to_bin(11));
+    }
+
+    #[test]
+    fn bin_to_u8_test() {
+        assert_eq!(bin_to_u8(&[true, false, false, false, true, false, true, true, true]), vec![139, 128]);
+    }
+
+    #[test]
+    fn allowed_fast_set_test() {
+
+        // Example from BEP 6
+        let info_hash = [0xAA; 20];
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        assert_eq!(allowed_fast_set(7, 1313, &info_hash, ip), vec![1059, 431, 808, 1217, 287, 376, 1188]);
+        assert_eq!(allowed_fast_set(9, 1313, &info_hash, ip), vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]);
+
+    }
+
+    #[test]
+    fn canonical_addr_test() {
+
+        let mapped = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped(), 6881));
+        assert_eq!(canonical_addr(mapped), SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 6881)));
+        assert_eq!(canonical_addr("[::1]:6881".parse().unwrap()), "[::1]:6881".parse().unwrap());
+    }
+
+    #[test]
+    fn u8_to_url_test() {
+
+        let arr: [u8; 20] = [ 18 , 52 , 86 , 120 , 154 , 188 , 222 , 241 , 35 , 69 , 103 , 137 , 171 , 205 , 239 , 18 , 52 , 86 , 120 , 154 ] ;
+        assert_eq!(u8_to_url(arr), "%124Vx%9A%BC%DE%F1%23Eg%89%AB%CD%EF%124Vx%9A");
+
+    }
+
+    #[tokio::test]
+    async fn message_reader_test() {
+
+        let (mut a, mut b) = tokio::io::duplex(64);
+        let mut reader = MessageReader::new();
+
+        a.write_all(&[0, 0, 0, 2, 7]).await.unwrap();
+        let pending = tokio::time::timeout(std::time::Duration::from_millis(50), reader.next(&mut b)).await;
+        assert!(pending.is_err());
+
+        // Bytes read before the timeout are kept
+        a.write_all(&[9, 0, 0, 0, 0]).await.unwrap();
+        assert_eq!(reader.next(&mut b).await, Some(vec![7, 9]));
+        assert_eq!(reader.next(&mut b).await, Some(vec![]));
+
+        drop(a);
+        assert_eq!(reader.next(&mut b).await, None);
+    }
+
+    #[test]
+    fn gen_peer_id_test() {
+        let id = gen_peer_id("-FT0100-");
+        assert_eq!(&id[..8], b"-FT0100-");
+        assert!(id[8..].iter().all(|c| c.is_ascii_alphanumeric()));
+    }
+}
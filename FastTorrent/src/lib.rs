@@ -1,6 +1,17 @@
-pub mod bencoded_parser;
-pub mod tracker;
-pub mod torrent_parser;
-pub mod download;
-pub mod message;
-pub mod helpers;
\ No newline at end of file
+pub mod bencoded_parser;
+pub mod tracker;
+pub mod torrent_parser;
+pub mod download;
+pub mod message;
+pub mod helpers;
+pub mod dht;
+pub mod lsd;
+pub mod config;
+pub mod mse;
+pub mod utp;
+pub mod peer;
+pub mod superseed;
+pub mod webseed;
+pub mod magnet;
+pub mod tracker_server;
+pub mod resume;
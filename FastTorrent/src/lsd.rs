@@ -0,0 +1,165 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Duration
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::UdpSocket,
+    sync::Mutex,
+    time::{self, timeout}
+};
+use crate::peer::{PeerList, PeerSource};
+
+// Local Service Discovery (BEP 14) multicast group
+pub static LSD_ADDR: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub static LSD_PORT: u16 = 6771;
+
+static ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, PartialEq)]
+pub struct Announce {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    pub cookie: Option<String>
+}
+
+// Build BT-SEARCH announcement for the given torrents
+pub fn build_announce(info_hashes: &[[u8; 20]], port: u16, cookie: &str) -> Vec<u8> {
+
+    let mut msg = String::from("BT-SEARCH * HTTP/1.1\r\n");
+    msg += &format!("Host: {}:{}\r\n", LSD_ADDR, LSD_PORT);
+    msg += &format!("Port: {}\r\n", port);
+    for info_hash in info_hashes {
+        msg += &format!("Infohash: {}\r\n", hex::encode(info_hash));
+    }
+    msg += &format!("cookie: {}\r\n", cookie);
+    msg += "\r\n\r\n";
+
+    msg.into_bytes()
+}
+
+// Parse BT-SEARCH announcement, headers are case insensitive
+pub fn parse_announce(buf: &[u8]) -> Option<Announce> {
+
+    let msg = std::str::from_utf8(buf).ok()?;
+    let mut lines = msg.split("\r\n");
+
+    if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+        return None;
+    }
+
+    let mut port = None;
+    let mut info_hashes = Vec::new();
+    let mut cookie = None;
+
+    for line in lines {
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim().to_ascii_lowercase(), value.trim()),
+            None => continue
+        };
+
+        match key.as_str() {
+            "port" => { port = value.parse::<u16>().ok(); },
+            "infohash" => {
+                let mut info_hash = [0; 20];
+                if hex::decode_to_slice(value, &mut info_hash).is_ok() {
+                    info_hashes.push(info_hash);
+                }
+            },
+            "cookie" => { cookie = Some(value.to_string()); },
+            _ => {}
+        }
+    }
+
+    if info_hashes.is_empty() {
+        return None;
+    }
+
+    Some(Announce { port: port.filter(|port| *port != 0)?, info_hashes, cookie })
+}
+
+// Socket joined to the multicast group, shared with other clients on this host
+fn bind_multicast() -> Option<UdpSocket> {
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).ok()?;
+    socket.set_reuse_address(true).ok()?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true).ok()?;
+    socket.set_nonblocking(true).ok()?;
+    socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, LSD_PORT)).into()).ok()?;
+    socket.join_multicast_v4(&LSD_ADDR, &Ipv4Addr::UNSPECIFIED).ok()?;
+    socket.set_multicast_loop_v4(true).ok()?;
+
+    UdpSocket::from_std(socket.into()).ok()
+}
+
+// Announce torrent on the local network and put LAN peers at the front of peer_list
+pub async fn discover(info_hash: [u8; 20], port: u16, peer_list: PeerList, piece_left: Arc<Mutex<u16>>) {
+
+    let socket = match bind_multicast() {
+        Some(socket) => socket,
+        None => return
+    };
+
+    // Used to recognise our own announcements
+    let cookie = hex::encode(rand::random::<[u8; 8]>());
+    let announce = build_announce(&[info_hash], port, &cookie);
+
+    let mut interval = time::interval(ANNOUNCE_INTERVAL);
+    let mut buf = [0; 1500];
+
+    loop {
+        if *(piece_left.lock().await) == 0 {
+            break;
+        }
+
+        tokio::select! {
+            _ = interval.tick() => {
+                let _ = socket.send_to(&announce, SocketAddrV4::new(LSD_ADDR, LSD_PORT)).await;
+            },
+            res = timeout(Duration::from_secs(5), socket.recv_from(&mut buf)) => {
+
+                let (len, from) = match res {
+                    Ok(Ok((len, SocketAddr::V4(from)))) => (len, from),
+                    _ => continue
+                };
+
+                let msg = match parse_announce(&buf[..len]) {
+                    Some(msg) => msg,
+                    None => continue
+                };
+                if msg.cookie.as_deref() == Some(cookie.as_str()) || !msg.info_hashes.contains(&info_hash) {
+                    continue;
+                }
+
+                let peer = SocketAddr::from((*from.ip(), msg.port));
+                let mut list = peer_list.lock().await;
+                list.retain(|(p, _)| *p != peer);
+                list.push_front((peer, PeerSource::Lsd));
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::gen_random_id;
+    use super::{build_announce, parse_announce, Announce};
+
+    #[test]
+    fn announce_test() {
+
+        let info_hash = gen_random_id();
+        let msg = build_announce(&[info_hash], 6881, "abc");
+        assert_eq!(parse_announce(&msg), Some(Announce { port: 6881, info_hashes: vec![info_hash], cookie: Some("abc".to_string()) }));
+
+        let msg = b"BT-SEARCH * HTTP/1.1\r\nhost: 239.192.152.143:6771\r\nport: 51413\r\ninfohash: 0123456789ABCDEF0123456789ABCDEF01234567\r\n\r\n\r\n";
+        let parsed = parse_announce(msg).unwrap();
+        assert_eq!((parsed.port, parsed.info_hashes[0][0], parsed.cookie), (51413, 0x01, None));
+
+        assert!(parse_announce(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n").is_none());
+        assert!(parse_announce(b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n").is_none());
+    }
+}
@@ -0,0 +1,91 @@
+use url::Url;
+
+// Magnet link (BEP 9), enough of it to find the swarm
+#[derive(Debug, PartialEq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    pub name: Option<String>,
+    pub trackers: Vec<String>
+}
+
+impl Magnet {
+
+    // Parse a magnet uri, the info hash may be hex or base32
+    pub fn parse(uri: &str) -> Option<Magnet> {
+
+        let url = Url::parse(uri).ok()?;
+        if url.scheme() != "magnet" {
+            return None;
+        }
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = info_hash.or(parse_hash(hash));
+                    }
+                },
+                "dn" => { name = Some(value.into_owned()); },
+                "tr" => { trackers.push(value.into_owned()); },
+                _ => {}
+            }
+        }
+
+        Some(Magnet { info_hash: info_hash?, name, trackers })
+    }
+}
+
+// Info hash of a btih urn, 40 hex digits or 32 base32 characters
+fn parse_hash(hash: &str) -> Option<[u8; 20]> {
+
+    let mut ret = [0; 20];
+    match hash.len() {
+        40 => hex::decode_to_slice(hash, &mut ret).ok()?,
+        32 => {
+            let mut bits: u64 = 0;
+            let mut count = 0;
+            let mut i = 0;
+            for c in hash.bytes() {
+                let val = match c.to_ascii_uppercase() {
+                    c @ b'A'..=b'Z' => c - b'A',
+                    c @ b'2'..=b'7' => c - b'2' + 26,
+                    _ => return None
+                };
+                bits = (bits << 5) | val as u64;
+                count += 5;
+                if count >= 8 {
+                    count -= 8;
+                    ret[i] = (bits >> count) as u8;
+                    i += 1;
+                }
+            }
+        },
+        _ => return None
+    }
+    Some(ret)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Magnet;
+
+    #[test]
+    fn parse_test() {
+
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:0123456789abcdef0123456789ABCDEF01234567&dn=a%20b&tr=udp%3A%2F%2Ft%3A80&tr=http://u/announce").unwrap();
+        assert_eq!((magnet.info_hash[0], magnet.info_hash[19]), (0x01, 0x67));
+        assert_eq!(magnet.name.as_deref(), Some("a b"));
+        assert_eq!(magnet.trackers, vec!["udp://t:80", "http://u/announce"]);
+
+        // Base32 form of the same hash
+        let base32 = Magnet::parse("magnet:?xt=urn:btih:AERUKZ4JVPG66AJDIVTYTK6N54ASGRLH").unwrap();
+        assert_eq!(base32.info_hash, magnet.info_hash);
+
+        assert!(Magnet::parse("magnet:?dn=x").is_none());
+        assert!(Magnet::parse("http://x/?xt=urn:btih:0123456789abcdef0123456789ABCDEF01234567").is_none());
+    }
+}
@@ -1,147 +1,310 @@
-use std::{fs::{File, self, OpenOptions},env, sync::Arc, path::PathBuf};
-use r_torrent::{
-    torrent_parser::{Torrent, Piece},
-    download,
-    tracker::get_peers
-};
-use tokio::{sync::Mutex, time};
-
-#[tokio::main]
-async fn main() {
-    
-    // Open file and get decoded and info hash
-    let mut args = env::args();
-    if args.len() < 3 {
-        panic!("usage: cargo run source_torrent destination_folder");
-    }
-    args.next();
-    
-    let dir = env::current_dir().unwrap();
-
-    // Open .torrent file
-    let source_dir = dir
-        .join(args
-                .next()
-                .unwrap());
-    let mut file = File::open(source_dir).unwrap();
-    
-
-    // All info mentioned in torrent file
-    let mut torrent = Torrent::parse_decoded(&mut file).await.unwrap(); 
-    
-    // Initialize Destination file
-    let destination_dir = dir
-            .join(args
-                .next()
-                .unwrap())
-            .join(&torrent.name.to_owned());
-    
-    // Create a file vector and pass it to download function
-    let mut file_vec = Vec::new();
-    
-    // if multiple files
-    if torrent.file_list != None {
-
-        // Create dir based on destination dir
-        fs::create_dir_all(&destination_dir).unwrap();
-
-        // Create files inside that dir
-        for (path, size) in torrent.file_list.unwrap() {
-            let file_path = destination_dir.join(path);
-            file_vec.push((open_file(file_path), size));
-        }
-        torrent.file_list = None;
-    }
-    else {
-        file_vec.push(( open_file(destination_dir), torrent.length ));
-    }
-
-
-    // Distribute torrent info
-    let (announce_url, announce_list) = (torrent.announce_url, torrent.announce_list);
-    (torrent.announce_url, torrent.announce_list) = (None, None);
-    
-    let file_vec = Arc::new(file_vec);
-    verify_file(torrent.piece_freq.clone(), file_vec.clone(), torrent.piece_hashes.clone(), torrent.downloaded.clone(), torrent.piece_left.clone()).await;
-    
-    // Get peers
-    let h1 = get_peers(
-        torrent.info_hash.clone(),
-        torrent.length.clone(),
-        torrent.peer_id.clone(),
-        announce_url,
-        torrent.peer_list.clone(),
-        announce_list,
-        torrent.connections.clone(),
-        torrent.downloaded.clone(),
-        torrent.piece_left.clone()
-    );
-
-
-    // Display function for downloading
-    let h2 = download::download_print(torrent.downloaded.clone(), torrent.connections.clone(), torrent.piece_left.clone());
-
-
-    // Download torrent
-    let h3 = download::download_file(torrent, file_vec);
-
-
-    tokio::join!(h1, h2, h3);
-
-}
-
-fn open_file(path: PathBuf) -> File {
-    OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(path)
-        .unwrap() 
-}
-
-async fn verify_file(freq_ref: Arc<Mutex<Vec<Piece>>>, file_ref: Arc<Vec<(File,u64)>>, piece_hashes: Arc<Vec<Vec<u8>>>, downloaded: Arc<Mutex<u64>>, piece_left: Arc<Mutex<u16>>)  {
-
-    println!("Checking already downloaded");
-
-    let start = time::Instant::now();
-    let len = (*freq_ref.lock().await).len();
-
-    // let mut total: u64 = 0;
-    let mut handles = Vec::new();
-    
-    for ind in 0..len {
-
-        let freq = freq_ref.clone();
-        let (downloaded, piece_left) = (downloaded.clone(), piece_left.clone());
-        let (length, offset, file, hash);
-        {
-            let ref1 = freq.lock().await;
-            (length, offset, file, hash) = ((*ref1)[ind].length, (*ref1)[ind].blocks[0].offset, file_ref.clone(), (*piece_hashes)[ind].clone())
-        }
-
-        let h = tokio::spawn(async move {
-
-            if download::verify_piece(length, offset, file, &hash) {
-
-                let mut ref1 = freq.lock().await;
-                (*ref1)[ind].completed = true;
-
-                let mut download = downloaded.lock().await;
-                *download += (*ref1)[ind].length;
-
-                let mut left = piece_left.lock().await;
-                *left -= 1;
-            }
-        });
-        handles.push(h);
-    }
-
-    for handle in handles {
-        handle.await.unwrap();
-    }
-    
-    let elapsed = start.elapsed();
-
-    println!("Elapsed:{:.2?}\n",elapsed);
-
-}
\ No newline at end of file
+use std::{fs::{File, self, OpenOptions},env, sync::Arc, path::PathBuf, net::{Ipv4Addr, SocketAddr, SocketAddrV4}};
+use socket2::Type;
+use r_torrent::{
+    torrent_parser::Torrent,
+    download,
+    tracker::{self, TrackerClient},
+    dht::{self, Dht},
+    lsd,
+    config::{Config, TransportOrder},
+    utp::UtpSocket,
+    superseed::SuperSeed,
+    webseed::{self, WebSeed},
+    magnet::Magnet,
+    tracker_server::{self, ServerOptions},
+    resume::ResumeData,
+    helpers::{self, DHT_PORT, LISTEN_PORT}
+};
+use tokio::{io::{AsyncBufReadExt, BufReader}, sync::Mutex, time};
+
+#[tokio::main]
+async fn main() {
+    
+    // Open file and get decoded and info hash
+    let (config, args) = Config::from_args(env::args().skip(1).collect()).unwrap();
+    if args.first().map(String::as_str) == Some("tracker") {
+        let listen = config.listen.expect("fasttorrent tracker needs --listen address:port");
+        let options = ServerOptions::load(config.whitelist.as_deref(), config.passkeys.as_deref()).unwrap();
+        tracker_server::serve(options, listen).await.unwrap();
+        return;
+    }
+
+    let tracker_client = TrackerClient::new(&config).unwrap();
+    if args.len() == 2 && args[0] == "scrape" {
+        scrape(&args[1], &tracker_client).await;
+        return;
+    }
+    if args.len() < 2 {
+        panic!("usage: fasttorrent source_torrent destination_folder [--encryption disable|prefer|require] [--transport utp-first|tcp-first|tcp] [--peer-id-prefix prefix] [--super-seed] [--files 0,2,...] [--announce-all] [--user-agent agent] [--tracker-ca bundle.pem] [--announce-ip ip] [--tracker url]...\n       fasttorrent scrape <torrent|magnet>\n       fasttorrent tracker --listen address:port [--whitelist info_hashes.txt] [--passkeys passkeys.txt]");
+    }
+    let mut args = args.into_iter();
+    let config = Arc::new(config);
+    
+    let dir = env::current_dir().unwrap();
+
+    // Open .torrent file
+    let source_dir = dir
+        .join(args
+                .next()
+                .unwrap());
+    let mut file = File::open(source_dir).unwrap();
+    
+
+    // All info mentioned in torrent file
+    let mut torrent = Torrent::parse_decoded(&mut file).await.unwrap(); 
+    torrent.peer_id = helpers::gen_peer_id(&config.peer_id_prefix);
+
+    // Trackers as last edited, then any given on the command line
+    torrent.resume(ResumeData::path(&torrent.info_hash)).await;
+    let tier = torrent.trackers().await.last().map_or(0, |tracker| tracker.tier + 1);
+    for url in &config.trackers {
+        torrent.add_session_tracker(url.clone(), tier).await;
+    }
+    if config.super_seed {
+        let piece_no = torrent.piece_freq.lock().await.len();
+        torrent.super_seed = Some(Arc::new(Mutex::new(SuperSeed::new(piece_no))));
+    }
+    
+    // Initialize Destination file
+    let destination_dir = dir
+            .join(args
+                .next()
+                .unwrap())
+            .join(&torrent.name);
+    
+    if let Some(files) = &config.files {
+        torrent.select_files(files).await;
+    }
+
+    // Web seeds need the file list, which is dropped below
+    let web_seeds = WebSeed::from_torrent(&torrent);
+
+    // Create a file vector and pass it to download function
+    let mut file_vec = Vec::new();
+    
+    // if multiple files
+    if torrent.file_list.is_some() {
+
+        // Create dir based on destination dir
+        fs::create_dir_all(&destination_dir).unwrap();
+
+        // Create files inside that dir
+        for (path, size) in torrent.file_list.unwrap() {
+            let file_path = destination_dir.join(path);
+            file_vec.push((open_file(file_path), size));
+        }
+        torrent.file_list = None;
+    }
+    else {
+        file_vec.push(( open_file(destination_dir), torrent.length ));
+    }
+
+
+    let file_vec = Arc::new(file_vec);
+    verify_file(&torrent, file_vec.clone()).await;
+    torrent.existing = *torrent.downloaded.lock().await;
+    
+    // uTP shares the listen port number over UDP, dual stack if possible, outgoing connections work from any port
+    let utp = if config.transport == TransportOrder::Tcp {
+        None
+    }
+    else {
+        let listen_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, LISTEN_PORT));
+        match helpers::dual_stack(Type::DGRAM, LISTEN_PORT).and_then(|socket| UtpSocket::from_std(socket.into())) {
+            Ok(utp) => Some(utp),
+            Err(_) => match UtpSocket::bind(listen_addr).await {
+                Ok(utp) => Some(utp),
+                Err(_) => UtpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await.ok()
+            }
+        }
+    };
+
+    // Everyone is told the port we actually got, TCP first as most peers connect over it
+    let listener = download::bind_listener(LISTEN_PORT).await;
+    let bound = listener.as_ref().and_then(|listener| listener.local_addr().ok())
+        .or_else(|| utp.as_ref().and_then(|utp| utp.local_addr().ok()));
+    if let Some(addr) = bound {
+        torrent.listen_port = addr.port();
+    }
+
+    // Get peers
+    let h1 = tracker::get_peers(torrent.clone(), tracker_client.clone());
+
+    // Tracker edits typed while running, not waited on since stdin may never close
+    tokio::spawn(tracker_commands(torrent.clone()));
+
+
+    // Start DHT node, falling back to any free port, a private torrent stays off it
+    let state_path = Some(helpers::state_dir().join("dht.dat"));
+    let mut dht = None;
+    if !torrent.private {
+        dht = Dht::new(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DHT_PORT), state_path.clone()).await;
+        if dht.is_none() {
+            dht = Dht::new(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0), state_path).await;
+        }
+    }
+
+    // Peer discovery stops with the download, unless we stay on to super seed
+    let discovery_left = if config.super_seed { Arc::new(Mutex::new(u16::MAX)) } else { torrent.wanted_left.clone() };
+
+    let h4 = {
+        let (dht, info_hash, port, peer_list, piece_left) = (dht.clone(), torrent.info_hash, torrent.listen_port, torrent.peer_list.clone(), discovery_left.clone());
+        async move {
+            if let Some(dht) = dht {
+                tokio::spawn(dht.clone().run());
+                dht::find_peers(dht, info_hash, port, peer_list, piece_left).await;
+            }
+        }
+    };
+
+    // Look for peers on the local network
+    let h5 = {
+        let (private, info_hash, port, peer_list) = (torrent.private, torrent.info_hash, torrent.listen_port, torrent.peer_list.clone());
+        async move {
+            if !private {
+                lsd::discover(info_hash, port, peer_list, discovery_left).await;
+            }
+        }
+    };
+
+    // Display function for downloading
+    let h2 = download::download_print(torrent.downloaded.clone(), torrent.connections.clone(), torrent.wanted_left.clone(), torrent.trackers.clone());
+
+
+    // Accept incoming peers
+    let h6 = download::listen(torrent.clone(), file_vec.clone(), dht.clone(), config.clone(), listener, utp.clone());
+
+    // Download from HTTP mirrors alongside peers
+    let h7 = webseed::download_all(web_seeds, torrent.clone(), file_vec.clone());
+
+    // Download torrent
+    let shutdown = torrent.clone();
+    let h3 = download::download_file(torrent, file_vec, dht, config, utp);
+
+
+    // Run until done or interrupted, then tell the trackers we are leaving
+    tokio::select! {
+        _ = async { tokio::join!(h1, h2, h3, h4, h5, h6, h7) } => {},
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    tracker::stop(&shutdown, &tracker_client).await;
+
+}
+
+// Lines of add <url> [tier], remove <url>, edit <url> <new url> and reannounce [url]
+async fn tracker_commands(torrent: Torrent) {
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["add", url] => {
+                let tier = torrent.trackers().await.last().map_or(0, |tracker| tracker.tier + 1);
+                torrent.add_tracker(url.to_string(), tier).await;
+            },
+            ["add", url, tier] => {
+                if let Ok(tier) = tier.parse() {
+                    torrent.add_tracker(url.to_string(), tier).await;
+                }
+            },
+            ["remove", url] => { torrent.remove_tracker(url).await; },
+            ["edit", url, new_url] => { torrent.edit_tracker(url, new_url.to_string()).await; },
+            ["reannounce"] => { torrent.reannounce(None).await; },
+            ["reannounce", url] => { torrent.reannounce(Some(url)).await; },
+            _ => {}
+        }
+    }
+}
+
+fn open_file(path: PathBuf) -> File {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .unwrap() 
+}
+
+async fn verify_file(torrent: &Torrent, file_ref: Arc<Vec<(File,u64)>>)  {
+
+    let (freq_ref, piece_hashes, downloaded, piece_left, wanted_left) = (torrent.piece_freq.clone(), torrent.piece_hashes.clone(), torrent.downloaded.clone(), torrent.piece_left.clone(), torrent.wanted_left.clone());
+
+    println!("Checking already downloaded");
+
+    let start = time::Instant::now();
+    let len = (*freq_ref.lock().await).len();
+
+    // let mut total: u64 = 0;
+    let mut handles = Vec::new();
+    
+    for ind in 0..len {
+
+        let freq = freq_ref.clone();
+        let (downloaded, piece_left, wanted_left) = (downloaded.clone(), piece_left.clone(), wanted_left.clone());
+        let (length, offset, file, hash);
+        {
+            let ref1 = freq.lock().await;
+            (length, offset, file, hash) = ((*ref1)[ind].length, (*ref1)[ind].blocks[0].offset, file_ref.clone(), (*piece_hashes)[ind].clone())
+        }
+
+        let h = tokio::spawn(async move {
+
+            if download::verify_piece(length, offset, file, &hash) {
+
+                let mut ref1 = freq.lock().await;
+                (*ref1)[ind].completed = true;
+
+                let mut download = downloaded.lock().await;
+                *download += (*ref1)[ind].length;
+
+                let mut left = piece_left.lock().await;
+                *left -= 1;
+
+                if (*ref1)[ind].wanted {
+                    *(wanted_left.lock().await) -= 1;
+                }
+            }
+        });
+        handles.push(h);
+    }
+
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    
+    let elapsed = start.elapsed();
+
+    println!("Elapsed:{:.2?}\n",elapsed);
+
+}
+
+// Print the swarm counts every tracker of a torrent file or magnet link has
+async fn scrape(source: &str, client: &TrackerClient) {
+
+    let (info_hash, urls) = match Magnet::parse(source) {
+        Some(magnet) => (magnet.info_hash, magnet.trackers),
+        None => {
+            let torrent = Torrent::parse_decoded(&mut File::open(source).unwrap()).await.unwrap();
+            let urls = torrent.trackers.lock().await.iter().map(|tracker| tracker.url.clone()).collect();
+            (torrent.info_hash, urls)
+        }
+    };
+
+    let handles: Vec<_> = urls.into_iter().map(|url| {
+        let client = client.clone();
+        tokio::spawn(async move {
+            let res = tracker::scrape(&client, &url, &[info_hash]).await;
+            (url, res)
+        })
+    }).collect();
+
+    for handle in handles {
+        let (url, res) = handle.await.unwrap();
+        match res.map(|stats| stats.get(&info_hash).copied()) {
+            Ok(Some(stats)) => println!("{}: {} seeders, {} leechers, {} completed", url, stats.seeders, stats.leechers, stats.completed),
+            Ok(None) => println!("{}: torrent not tracked", url),
+            Err(err) => println!("{}: {}", url, err)
+        }
+    }
+}
@@ -1,191 +1,489 @@
-use byteorder::{WriteBytesExt, BigEndian, ReadBytesExt};
-
-#[allow(dead_code)]
-#[derive(Debug)]
-pub enum Message {
-    KeepAlive {
-        length: u32
-    },
-    Choke {
-        length: u32,
-        id: u8
-    },
-    Unchoke {
-        length: u32,
-        id: u8
-    },
-    Interested {
-        length: u32,
-        id: u8
-    },
-    Uninterested {
-        length: u32,
-        id: u8
-    },
-    Have {
-        length: u32,
-        id: u8,
-        piece_index: u32
-    },
-    BitField {
-        length: u32,
-        id: u8,
-        bitfield: Vec<u8>
-    },
-    Request {
-        length: u32,
-        id: u8,
-        index: u32,
-        begin: u32,
-        req_length: u32
-    },
-    Piece {
-        length: u32,
-        id: u8,
-        index: u32,
-        begin: u32,
-        block: Vec<u8>
-    },
-    Cancel {
-        length: u32,
-        id: u8,
-        index: u32,
-        begin: u32,
-        req_length: u32
-    },
-    Port {
-        length: u32,
-        id: u8,
-        listen_port: u16
-    },
-}
-
-#[allow(dead_code)]
-impl Message {
-    fn build_keep_alive() -> Message {
-        Message::KeepAlive { length: 0 }
-    }
-
-    fn build_choke() -> Vec<u8> {
-        let mut buf: Vec<u8> = Vec::new();
-        buf.write_u32::<BigEndian>(1).unwrap();
-        buf.write_u8(0).unwrap();
-        buf
-    }
-
-    pub fn build_unchoke() -> Vec<u8> {
-        let mut buf: Vec<u8> = Vec::new();
-        buf.write_u32::<BigEndian>(1).unwrap();
-        buf.write_u8(1).unwrap();
-        buf
-    }
-
-    fn build_interested() -> Message {
-        Message::Interested { length: 1, id: 2 }
-    }
-
-    fn build_uninterested() -> Message {
-        Message::Uninterested { length: 1, id: 3 }
-    }
-
-    fn build_have(piece_index:u32 ) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.write_u32::<BigEndian>(5).unwrap();
-        buf.write_u8(4).unwrap();
-        buf.write_u32::<BigEndian>(piece_index).unwrap();
-        buf
-    }
-
-    fn build_bitfield(bitfield: Vec<u8>) -> Message {
-        Message::BitField { length: 1+(bitfield.len() as u32), id: 5, bitfield}
-    }
-
-    pub fn build_request(index: u32, begin: u32, req_length: u32) -> Vec<u8> {
-        let mut buf: Vec<u8> = Vec::new();
-        buf.write_u32::<BigEndian>(13).unwrap();
-        buf.write_u8(6).unwrap();
-        buf.write_u32::<BigEndian>(index).unwrap();
-        buf.write_u32::<BigEndian>(begin).unwrap();
-        buf.write_u32::<BigEndian>(req_length).unwrap();
-        buf
-    }
-
-    pub fn read_request(mut msg: &[u8]) -> Message {
-        Message::Request { 
-            length: 13, 
-            id: msg.read_u8().unwrap(), 
-            index: msg.read_u32::<BigEndian>().unwrap(),
-            begin: msg.read_u32::<BigEndian>().unwrap(), 
-            req_length: msg.read_u32::<BigEndian>().unwrap() 
-        }
-    }
-
-    fn build_piece(index: u32, begin: u32, block: Vec<u8>) -> Message {
-        Message::Piece { length: 9+(block.len() as u32), id: 7, index, begin, block }
-    }
-
-    fn build_cancel(index: u32, begin: u32, req_length: u32) -> Message {
-        Message::Cancel { length: 13, id: 8, index, begin, req_length }
-    }
-
-    fn build_port(listen_port: u16) -> Message {
-        Message::Port { length: 3, id: 9, listen_port }
-    } 
-}
-
-pub struct HandshakeMsg {
-    pstrlen: u8,
-    pstr: String,
-    reserved: u64,
-    info_hash: [u8; 20],
-    peer_id: [u8; 20]
-}
-
-impl HandshakeMsg {
-
-    pub fn build_msg( info_hash: [u8; 20], peer_id: [u8;20]) -> Vec<u8> {
-
-        let handshake = HandshakeMsg {
-            pstrlen: 19,
-            pstr: "BitTorrent protocol".to_string(),
-            reserved: 0,
-            info_hash,
-            peer_id
-        };
-
-        let mut buf = Vec::new();
-
-        buf.write_u8(handshake.pstrlen).unwrap(); // pstrlen
-        //pstr
-        for byte in handshake.pstr.as_bytes() {
-            buf.write_u8(*byte).unwrap();
-        }
-        buf.write_u64::<BigEndian>(handshake.reserved).unwrap(); // reserved
-        // info_hash
-        for byte in handshake.info_hash {
-            buf.write_u8(byte).unwrap();
-        }
-        // peer_id
-        for byte in handshake.peer_id {
-            buf.write_u8(byte).unwrap();
-        }
-
-        buf
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::helpers::gen_random_id;
-
-    use super::HandshakeMsg;
-
-    #[test]
-    fn test_build_msg() {
-
-
-        let buf = HandshakeMsg::build_msg(gen_random_id(), gen_random_id());
-        assert_eq!(buf.len(), 68);
-
-    }
-}
\ No newline at end of file
+use std::{collections::HashMap, net::Ipv6Addr};
+use byteorder::{WriteBytesExt, BigEndian, ReadBytesExt};
+use crate::bencoded_parser::{Bencode, Element};
+
+// Reserved handshake bits for supported extensions
+pub static DHT_BIT: u64 = 0x01;
+pub static FAST_BIT: u64 = 0x04;
+pub static EXTENSION_BIT: u64 = 0x10_0000;
+
+// Client name sent in the extension handshake
+pub static CLIENT_NAME: &str = "FastTorrent 0.1.0";
+
+// Id we receive upload only messages with (BEP 21)
+pub static UPLOAD_ONLY_ID: u8 = 1;
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum Message {
+    KeepAlive {
+        length: u32
+    },
+    Choke {
+        length: u32,
+        id: u8
+    },
+    Unchoke {
+        length: u32,
+        id: u8
+    },
+    Interested {
+        length: u32,
+        id: u8
+    },
+    Uninterested {
+        length: u32,
+        id: u8
+    },
+    Have {
+        length: u32,
+        id: u8,
+        piece_index: u32
+    },
+    BitField {
+        length: u32,
+        id: u8,
+        bitfield: Vec<u8>
+    },
+    Request {
+        length: u32,
+        id: u8,
+        index: u32,
+        begin: u32,
+        req_length: u32
+    },
+    Piece {
+        length: u32,
+        id: u8,
+        index: u32,
+        begin: u32,
+        block: Vec<u8>
+    },
+    Cancel {
+        length: u32,
+        id: u8,
+        index: u32,
+        begin: u32,
+        req_length: u32
+    },
+    Port {
+        length: u32,
+        id: u8,
+        listen_port: u16
+    },
+    SuggestPiece {
+        length: u32,
+        id: u8,
+        piece_index: u32
+    },
+    HaveAll {
+        length: u32,
+        id: u8
+    },
+    HaveNone {
+        length: u32,
+        id: u8
+    },
+    RejectRequest {
+        length: u32,
+        id: u8,
+        index: u32,
+        begin: u32,
+        req_length: u32
+    },
+    AllowedFast {
+        length: u32,
+        id: u8,
+        piece_index: u32
+    },
+}
+
+#[allow(dead_code)]
+impl Message {
+    fn build_keep_alive() -> Message {
+        Message::KeepAlive { length: 0 }
+    }
+
+    fn build_choke() -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.write_u32::<BigEndian>(1).unwrap();
+        buf.write_u8(0).unwrap();
+        buf
+    }
+
+    pub fn build_unchoke() -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.write_u32::<BigEndian>(1).unwrap();
+        buf.write_u8(1).unwrap();
+        buf
+    }
+
+    pub fn build_interested() -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.write_u32::<BigEndian>(1).unwrap();
+        buf.write_u8(2).unwrap();
+        buf
+    }
+
+    pub fn build_uninterested() -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.write_u32::<BigEndian>(1).unwrap();
+        buf.write_u8(3).unwrap();
+        buf
+    }
+
+    pub fn build_have(piece_index:u32 ) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u32::<BigEndian>(5).unwrap();
+        buf.write_u8(4).unwrap();
+        buf.write_u32::<BigEndian>(piece_index).unwrap();
+        buf
+    }
+
+    pub fn build_bitfield(bitfield: Vec<u8>) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.write_u32::<BigEndian>(1+(bitfield.len() as u32)).unwrap();
+        buf.write_u8(5).unwrap();
+        buf.extend_from_slice(&bitfield);
+        buf
+    }
+
+    pub fn build_request(index: u32, begin: u32, req_length: u32) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.write_u32::<BigEndian>(13).unwrap();
+        buf.write_u8(6).unwrap();
+        buf.write_u32::<BigEndian>(index).unwrap();
+        buf.write_u32::<BigEndian>(begin).unwrap();
+        buf.write_u32::<BigEndian>(req_length).unwrap();
+        buf
+    }
+
+    pub fn read_request(mut msg: &[u8]) -> Option<Message> {
+        Some(Message::Request {
+            length: 13,
+            id: msg.read_u8().ok()?,
+            index: msg.read_u32::<BigEndian>().ok()?,
+            begin: msg.read_u32::<BigEndian>().ok()?,
+            req_length: msg.read_u32::<BigEndian>().ok()?
+        })
+    }
+
+    pub fn build_piece(index: u32, begin: u32, block: Vec<u8>) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.write_u32::<BigEndian>(9+(block.len() as u32)).unwrap();
+        buf.write_u8(7).unwrap();
+        buf.write_u32::<BigEndian>(index).unwrap();
+        buf.write_u32::<BigEndian>(begin).unwrap();
+        buf.extend_from_slice(&block);
+        buf
+    }
+
+    pub fn build_cancel(index: u32, begin: u32, req_length: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u32::<BigEndian>(13).unwrap();
+        buf.write_u8(8).unwrap();
+        buf.write_u32::<BigEndian>(index).unwrap();
+        buf.write_u32::<BigEndian>(begin).unwrap();
+        buf.write_u32::<BigEndian>(req_length).unwrap();
+        buf
+    }
+
+    pub fn build_port(listen_port: u16) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.write_u32::<BigEndian>(3).unwrap();
+        buf.write_u8(9).unwrap();
+        buf.write_u16::<BigEndian>(listen_port).unwrap();
+        buf
+    }
+
+    pub fn read_port(mut msg: &[u8]) -> Message {
+        Message::Port {
+            length: 3,
+            id: msg.read_u8().unwrap(),
+            listen_port: msg.read_u16::<BigEndian>().unwrap()
+        }
+    }
+
+    // Fast extension (BEP 6)
+    pub fn build_suggest(piece_index: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u32::<BigEndian>(5).unwrap();
+        buf.write_u8(0x0D).unwrap();
+        buf.write_u32::<BigEndian>(piece_index).unwrap();
+        buf
+    }
+
+    pub fn build_have_all() -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u32::<BigEndian>(1).unwrap();
+        buf.write_u8(0x0E).unwrap();
+        buf
+    }
+
+    pub fn build_have_none() -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u32::<BigEndian>(1).unwrap();
+        buf.write_u8(0x0F).unwrap();
+        buf
+    }
+
+    pub fn build_reject(index: u32, begin: u32, req_length: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u32::<BigEndian>(13).unwrap();
+        buf.write_u8(0x10).unwrap();
+        buf.write_u32::<BigEndian>(index).unwrap();
+        buf.write_u32::<BigEndian>(begin).unwrap();
+        buf.write_u32::<BigEndian>(req_length).unwrap();
+        buf
+    }
+
+    pub fn read_reject(mut msg: &[u8]) -> Option<Message> {
+        Some(Message::RejectRequest {
+            length: 13,
+            id: msg.read_u8().ok()?,
+            index: msg.read_u32::<BigEndian>().ok()?,
+            begin: msg.read_u32::<BigEndian>().ok()?,
+            req_length: msg.read_u32::<BigEndian>().ok()?
+        })
+    }
+
+    pub fn build_allowed_fast(piece_index: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u32::<BigEndian>(5).unwrap();
+        buf.write_u8(0x11).unwrap();
+        buf.write_u32::<BigEndian>(piece_index).unwrap();
+        buf
+    }
+
+    // Piece index of Have, SuggestPiece and AllowedFast messages
+    pub fn read_piece_index(mut msg: &[u8]) -> Option<u32> {
+        msg.read_u8().ok()?;
+        msg.read_u32::<BigEndian>().ok()
+    }
+
+    // Extension protocol message, ext_id 0 is the extension handshake
+    pub fn build_extended(ext_id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u32::<BigEndian>(2 + payload.len() as u32).unwrap();
+        buf.write_u8(20).unwrap();
+        buf.write_u8(ext_id).unwrap();
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    // Extension id and payload of an extension protocol message
+    pub fn read_extended(msg: &[u8]) -> Option<(u8, &[u8])> {
+        if msg.len() < 2 || msg[0] != 20 {
+            return None;
+        }
+        Some((msg[1], &msg[2..]))
+    }
+}
+
+// Extension handshake (BEP 10)
+#[derive(Debug, Default, PartialEq)]
+pub struct ExtendedHandshake {
+    // Extension names mapped to the message ids the sender wants to receive them with
+    pub m: HashMap<String, u8>,
+    // Number of outstanding requests the sender allows
+    pub reqq: Option<u32>,
+    pub client: Option<String>,
+    // Sender will not download anything more (BEP 21)
+    pub upload_only: bool,
+    // Sender's listen port and IPv6 address, so a peer seen over IPv4 can be reached over IPv6 too
+    pub port: Option<u16>,
+    pub ipv6: Option<Ipv6Addr>
+}
+
+impl ExtendedHandshake {
+
+    pub fn build(&self) -> Vec<u8> {
+
+        let m = self.m.iter().map(|(name, id)| (name.as_bytes().to_vec(), Element::Integer(*id as i64))).collect();
+        let mut dict = HashMap::new();
+        dict.insert(b"m".to_vec(), Element::Dict(m));
+        if let Some(reqq) = self.reqq {
+            dict.insert(b"reqq".to_vec(), Element::Integer(reqq as i64));
+        }
+        if let Some(client) = &self.client {
+            dict.insert(b"v".to_vec(), Element::ByteString(client.as_bytes().to_vec()));
+        }
+        if self.upload_only {
+            dict.insert(b"upload_only".to_vec(), Element::Integer(1));
+        }
+        if let Some(port) = self.port {
+            dict.insert(b"p".to_vec(), Element::Integer(port as i64));
+        }
+        if let Some(ipv6) = self.ipv6 {
+            dict.insert(b"ipv6".to_vec(), Element::ByteString(ipv6.octets().to_vec()));
+        }
+
+        Message::build_extended(0, &Bencode::encode(&Element::Dict(dict)))
+    }
+
+    pub fn parse(payload: &[u8]) -> Option<ExtendedHandshake> {
+
+        let decoded = Bencode::decode_u8(payload.to_vec()).ok()?;
+
+        let mut m = HashMap::new();
+        if let Some(Element::Dict(dict)) = decoded.get("m") {
+            for (name, id) in dict {
+                if let (Ok(name), Some(id)) = (String::from_utf8(name.clone()), id.as_int()) {
+                    // Id 0 disables the extension
+                    if (1..=255).contains(&id) {
+                        m.insert(name, id as u8);
+                    }
+                }
+            }
+        }
+
+        Some(ExtendedHandshake {
+            m,
+            reqq: decoded.get("reqq").and_then(Element::as_int).filter(|reqq| *reqq > 0).map(|reqq| reqq.min(u32::MAX as i64) as u32),
+            client: decoded.get("v").and_then(Element::as_bytes).map(|v| String::from_utf8_lossy(v).into_owned()),
+            upload_only: decoded.get("upload_only").and_then(Element::as_int).is_some_and(|val| val != 0),
+            port: decoded.get("p").and_then(Element::as_int).filter(|port| (1..=65535).contains(port)).map(|port| port as u16),
+            ipv6: decoded.get("ipv6").and_then(Element::as_bytes).and_then(|ip| <[u8; 16]>::try_from(ip).ok()).map(Ipv6Addr::from)
+        })
+    }
+}
+
+pub struct HandshakeMsg {
+    pstrlen: u8,
+    pstr: String,
+    pub reserved: u64,
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20]
+}
+
+impl HandshakeMsg {
+
+    pub fn build_msg( info_hash: [u8; 20], peer_id: [u8;20]) -> Vec<u8> {
+
+        let handshake = HandshakeMsg {
+            pstrlen: 19,
+            pstr: "BitTorrent protocol".to_string(),
+            reserved: DHT_BIT | FAST_BIT | EXTENSION_BIT,
+            info_hash,
+            peer_id
+        };
+
+        let mut buf = Vec::new();
+
+        buf.write_u8(handshake.pstrlen).unwrap(); // pstrlen
+        //pstr
+        for byte in handshake.pstr.as_bytes() {
+            buf.write_u8(*byte).unwrap();
+        }
+        buf.write_u64::<BigEndian>(handshake.reserved).unwrap(); // reserved
+        // info_hash
+        for byte in handshake.info_hash {
+            buf.write_u8(byte).unwrap();
+        }
+        // peer_id
+        for byte in handshake.peer_id {
+            buf.write_u8(byte).unwrap();
+        }
+
+        buf
+    }
+
+    // Parse handshake received from peer
+    pub fn read_msg(mut buf: &[u8]) -> Option<HandshakeMsg> {
+
+        if buf.len() != 68 || buf[0] != 19 || &buf[1..20] != b"BitTorrent protocol" {
+            return None;
+        }
+        buf = &buf[20..];
+
+        let reserved = buf.read_u64::<BigEndian>().ok()?;
+        let mut info_hash = [0; 20];
+        info_hash.copy_from_slice(&buf[..20]);
+        let mut peer_id = [0; 20];
+        peer_id.copy_from_slice(&buf[20..40]);
+
+        Some(HandshakeMsg {
+            pstrlen: 19,
+            pstr: "BitTorrent protocol".to_string(),
+            reserved,
+            info_hash,
+            peer_id
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+    use crate::helpers::gen_random_id;
+
+    use super::{ExtendedHandshake, HandshakeMsg, Message, DHT_BIT, FAST_BIT};
+
+    #[test]
+    fn test_build_msg() {
+
+
+        let buf = HandshakeMsg::build_msg(gen_random_id(), gen_random_id());
+        assert_eq!(buf.len(), 68);
+
+    }
+
+    #[test]
+    fn test_read_msg() {
+
+        let (info_hash, peer_id) = (gen_random_id(), gen_random_id());
+        let msg = HandshakeMsg::read_msg(&HandshakeMsg::build_msg(info_hash, peer_id)).unwrap();
+        assert_eq!((msg.reserved & (DHT_BIT | FAST_BIT), msg.info_hash, msg.peer_id), (DHT_BIT | FAST_BIT, info_hash, peer_id));
+        assert!(HandshakeMsg::read_msg(&[0; 68]).is_none());
+
+    }
+
+    #[test]
+    fn test_port() {
+
+        let buf = Message::build_port(6881);
+        match Message::read_port(&buf[4..]) {
+            Message::Port { listen_port, .. } => assert_eq!(listen_port, 6881),
+            _ => panic!()
+        }
+
+    }
+
+    #[test]
+    fn test_fast_messages() {
+
+        let buf = Message::build_reject(3, 16384, 100);
+        assert_eq!(buf.len(), 17);
+        match Message::read_reject(&buf[4..]) {
+            Some(Message::RejectRequest { id, index, begin, req_length, .. }) => assert_eq!((id, index, begin, req_length), (0x10, 3, 16384, 100)),
+            _ => panic!()
+        }
+        // Truncated messages from a peer are not parsed
+        assert!(Message::read_reject(&buf[4..9]).is_none());
+        assert!(Message::read_request(&Message::build_request(1, 2, 3)[4..12]).is_none());
+        assert_eq!(Message::read_piece_index(&[4, 0, 0]), None);
+
+        assert_eq!(Message::read_piece_index(&Message::build_allowed_fast(7)[4..]), Some(7));
+        assert_eq!(Message::read_piece_index(&Message::build_suggest(9)[4..]), Some(9));
+        assert_eq!(Message::build_have_all(), vec![0, 0, 0, 1, 0x0E]);
+        assert_eq!(Message::build_have_none(), vec![0, 0, 0, 1, 0x0F]);
+        assert_eq!(Message::build_cancel(1, 2, 3), vec![0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]);
+
+    }
+
+    #[test]
+    fn test_extended_handshake() {
+
+        let mut handshake = ExtendedHandshake { reqq: Some(250), client: Some("FastTorrent 0.1.0".to_string()), upload_only: true, port: Some(6881), ipv6: Some(Ipv6Addr::LOCALHOST), ..Default::default() };
+        handshake.m.insert("ut_pex".to_string(), 1);
+
+        let buf = handshake.build();
+        let (ext_id, payload) = Message::read_extended(&buf[4..]).unwrap();
+        assert_eq!(ext_id, 0);
+        assert_eq!(ExtendedHandshake::parse(payload), Some(handshake));
+
+        assert_eq!(ExtendedHandshake::parse(b"d1:md6:ut_pexi0eee").unwrap().m.len(), 0);
+        assert!(ExtendedHandshake::parse(b"d1:m").is_none());
+
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready}
+};
+use num_bigint::BigUint;
+use sha1_smol::Sha1;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+// Message Stream Encryption, 768 bit Diffie-Hellman prime with generator 2
+static PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+static KEY_LEN: usize = 96;
+static MAX_PAD: usize = 512;
+static VC: [u8; 8] = [0; 8];
+
+// crypto_provide and crypto_select bits
+pub static CRYPTO_PLAINTEXT: u32 = 0x01;
+pub static CRYPTO_RC4: u32 = 0x02;
+
+#[derive(Clone)]
+pub struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8
+}
+
+impl Rc4 {
+
+    pub fn new(key: &[u8]) -> Rc4 {
+
+        let mut s = [0u8; 256];
+        for (i, val) in s.iter_mut().enumerate() {
+            *val = i as u8;
+        }
+
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+
+        Rc4 { s, i: 0, j: 0 }
+    }
+
+    // MSE discards the first 1024 bytes of keystream
+    fn new_discarded(key: &[u8]) -> Rc4 {
+        let mut rc4 = Rc4::new(key);
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    pub fn apply(&mut self, buf: &mut [u8]) {
+        for byte in buf.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[(self.s[self.i as usize].wrapping_add(self.s[self.j as usize])) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+// Peer stream which encrypts all traffic with RC4 once selected by the MSE handshake
+pub struct CryptoStream<S> {
+    inner: S,
+    // (encrypt, decrypt)
+    cipher: Option<(Rc4, Rc4)>,
+    // Data already received and decrypted, handed out before reading from inner
+    read_buf: Vec<u8>,
+    // Encrypted data not yet written to inner
+    write_buf: Vec<u8>
+}
+
+impl<S> CryptoStream<S> {
+
+    pub fn plain(inner: S) -> CryptoStream<S> {
+        CryptoStream::with_prefix(inner, Vec::new())
+    }
+
+    // Plaintext stream where prefix was already read from inner
+    pub fn with_prefix(inner: S, prefix: Vec<u8>) -> CryptoStream<S> {
+        CryptoStream { inner, cipher: None, read_buf: prefix, write_buf: Vec::new() }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> CryptoStream<S> {
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for CryptoStream<S> {
+
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+
+        let this = self.get_mut();
+
+        // Keep pushing out buffered writes while the session is waiting on reads
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        if !this.read_buf.is_empty() {
+            let n = buf.remaining().min(this.read_buf.len());
+            buf.put_slice(&this.read_buf[..n]);
+            this.read_buf.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some((_, dec)) = &mut this.cipher {
+            dec.apply(&mut buf.filled_mut()[before..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CryptoStream<S> {
+
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+
+        let this = self.get_mut();
+        if this.cipher.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        ready!(this.poll_drain(cx))?;
+
+        // Encrypted bytes are owned by the stream from here, they are drained by later calls
+        let mut data = buf.to_vec();
+        if let Some((enc, _)) = &mut this.cipher {
+            enc.apply(&mut data);
+        }
+        this.write_buf = data;
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.digest().bytes()
+}
+
+// Big endian number padded to the key length
+fn to_key(n: &BigUint) -> Vec<u8> {
+    let bytes = n.to_bytes_be();
+    let mut ret = vec![0; KEY_LEN - bytes.len()];
+    ret.extend(bytes);
+    ret
+}
+
+// Private key and public key to send
+fn dh_keypair() -> (BigUint, Vec<u8>) {
+    let prime = BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap();
+    let private = BigUint::from_bytes_be(&rand::random::<[u8; 20]>());
+    let public = BigUint::from(2u32).modpow(&private, &prime);
+    (private, to_key(&public))
+}
+
+fn dh_secret(private: &BigUint, public: &[u8]) -> Vec<u8> {
+    let prime = BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap();
+    to_key(&BigUint::from_bytes_be(public).modpow(private, &prime))
+}
+
+fn random_pad() -> Vec<u8> {
+    let len = rand::random::<usize>() % (MAX_PAD + 1);
+    (0..len).map(|_| rand::random()).collect()
+}
+
+// Read from stream until the last bytes read equal pattern, giving up after limit bytes
+async fn sync<S: AsyncRead + Unpin>(stream: &mut S, pattern: &[u8], limit: usize) -> Option<()> {
+    let mut window = Vec::new();
+    while window.len() < limit {
+        window.push(stream.read_u8().await.ok()?);
+        if window.ends_with(pattern) {
+            return Some(());
+        }
+    }
+    None
+}
+
+// Outgoing handshake, offering the methods in provide
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, info_hash: &[u8; 20], provide: u32) -> Option<CryptoStream<S>> {
+
+    // 1. A->B: Ya, PadA
+    let (private, public) = dh_keypair();
+    let mut buf = public;
+    buf.extend(random_pad());
+    stream.write_all(&buf).await.ok()?;
+
+    // 2. B->A: Yb, PadB
+    let mut their_public = vec![0; KEY_LEN];
+    stream.read_exact(&mut their_public).await.ok()?;
+    let secret = dh_secret(&private, &their_public);
+
+    let mut enc = Rc4::new_discarded(&hash(&[b"keyA", &secret, info_hash]));
+    let mut dec = Rc4::new_discarded(&hash(&[b"keyB", &secret, info_hash]));
+
+    // 3. A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S), ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA))
+    let mut buf = hash(&[b"req1", &secret]).to_vec();
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    buf.extend(req2.iter().zip(req3.iter()).map(|(a, b)| a ^ b));
+
+    let mut payload = VC.to_vec();
+    payload.extend(provide.to_be_bytes());
+    payload.extend(0u16.to_be_bytes());
+    payload.extend(0u16.to_be_bytes());
+    enc.apply(&mut payload);
+    buf.extend(payload);
+    stream.write_all(&buf).await.ok()?;
+
+    // 4. B->A: ENCRYPT(VC, crypto_select, len(padD), padD), found by scanning past PadB for the encrypted VC
+    let mut encrypted_vc = VC.to_vec();
+    dec.clone().apply(&mut encrypted_vc);
+    sync(&mut stream, &encrypted_vc, MAX_PAD + VC.len()).await?;
+    dec.apply(&mut VC.clone());
+
+    let mut select = [0; 6];
+    stream.read_exact(&mut select).await.ok()?;
+    dec.apply(&mut select);
+    let crypto_select = u32::from_be_bytes(select[..4].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(select[4..].try_into().unwrap()) as usize;
+    if pad_len > MAX_PAD {
+        return None;
+    }
+    let mut pad = vec![0; pad_len];
+    stream.read_exact(&mut pad).await.ok()?;
+    dec.apply(&mut pad);
+
+    if crypto_select & provide == 0 || crypto_select.count_ones() != 1 {
+        return None;
+    }
+
+    let cipher = if crypto_select == CRYPTO_RC4 { Some((enc, dec)) } else { None };
+    Some(CryptoStream { inner: stream, cipher, read_buf: Vec::new(), write_buf: Vec::new() })
+}
+
+// Incoming handshake, prefix holds bytes of Ya already read while checking for a plaintext handshake
+pub async fn respond<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, prefix: &[u8], info_hash: &[u8; 20], allow: u32) -> Option<CryptoStream<S>> {
+
+    // 1. A->B: Ya, PadA
+    let mut their_public = prefix.to_vec();
+    their_public.resize(KEY_LEN, 0);
+    stream.read_exact(&mut their_public[prefix.len()..]).await.ok()?;
+
+    // 2. B->A: Yb, PadB
+    let (private, public) = dh_keypair();
+    let mut buf = public;
+    buf.extend(random_pad());
+    stream.write_all(&buf).await.ok()?;
+    let secret = dh_secret(&private, &their_public);
+
+    // 3. A->B: HASH('req1', S) found by scanning past PadA, then the torrent A wants
+    sync(&mut stream, &hash(&[b"req1", &secret]), MAX_PAD + 20).await?;
+
+    let mut skey = [0; 20];
+    stream.read_exact(&mut skey).await.ok()?;
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    if skey.iter().zip(req2.iter().zip(req3.iter())).any(|(s, (a, b))| *s != a ^ b) {
+        return None;
+    }
+
+    let mut dec = Rc4::new_discarded(&hash(&[b"keyA", &secret, info_hash]));
+    let mut enc = Rc4::new_discarded(&hash(&[b"keyB", &secret, info_hash]));
+
+    let mut provide = [0; 14];
+    stream.read_exact(&mut provide).await.ok()?;
+    dec.apply(&mut provide);
+    if provide[..8] != VC {
+        return None;
+    }
+    let crypto_provide = u32::from_be_bytes(provide[8..12].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(provide[12..].try_into().unwrap()) as usize;
+    if pad_len > MAX_PAD {
+        return None;
+    }
+
+    // PadC followed by len(IA) and IA
+    let mut pad = vec![0; pad_len + 2];
+    stream.read_exact(&mut pad).await.ok()?;
+    dec.apply(&mut pad);
+    let ia_len = u16::from_be_bytes(pad[pad_len..].try_into().unwrap()) as usize;
+    let mut initial_payload = vec![0; ia_len];
+    stream.read_exact(&mut initial_payload).await.ok()?;
+    dec.apply(&mut initial_payload);
+
+    // 4. B->A: ENCRYPT(VC, crypto_select, len(padD), padD)
+    let crypto_select = if crypto_provide & allow & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    }
+    else if crypto_provide & allow & CRYPTO_PLAINTEXT != 0 {
+        CRYPTO_PLAINTEXT
+    }
+    else {
+        return None;
+    };
+
+    let mut buf = VC.to_vec();
+    buf.extend(crypto_select.to_be_bytes());
+    buf.extend(0u16.to_be_bytes());
+    enc.apply(&mut buf);
+    stream.write_all(&buf).await.ok()?;
+
+    let cipher = if crypto_select == CRYPTO_RC4 { Some((enc, dec)) } else { None };
+    Some(CryptoStream { inner: stream, cipher, read_buf: initial_payload, write_buf: Vec::new() })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use crate::helpers::gen_random_id;
+    use super::{Rc4, initiate, respond, CRYPTO_PLAINTEXT, CRYPTO_RC4};
+
+    #[test]
+    fn rc4_test() {
+        let mut buf = b"Plaintext".to_vec();
+        Rc4::new(b"Key").apply(&mut buf);
+        assert_eq!(hex::encode(buf), "bbf316e8d940af0ad3");
+    }
+
+    #[tokio::test]
+    async fn handshake_test() {
+
+        for (provide, allow, encrypted) in [(CRYPTO_RC4 | CRYPTO_PLAINTEXT, CRYPTO_RC4, true), (CRYPTO_RC4 | CRYPTO_PLAINTEXT, CRYPTO_PLAINTEXT, false)] {
+
+            let info_hash = gen_random_id();
+            let (a, mut b) = duplex(4096);
+
+            let outgoing = tokio::spawn(async move {
+                let mut stream = initiate(a, &info_hash, provide).await.unwrap();
+                stream.write_all(b"hello").await.unwrap();
+                let mut buf = [0; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                (stream.is_encrypted(), buf)
+            });
+
+            let mut prefix = [0; 20];
+            b.read_exact(&mut prefix).await.unwrap();
+            let mut stream = respond(b, &prefix, &info_hash, allow).await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            stream.write_all(b"world").await.unwrap();
+
+            assert_eq!(outgoing.await.unwrap(), (encrypted, *b"world"));
+            assert_eq!(stream.is_encrypted(), encrypted);
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_torrent_test() {
+
+        let (a, mut b) = duplex(4096);
+        tokio::spawn(async move {
+            initiate(a, &gen_random_id(), CRYPTO_RC4).await
+        });
+
+        let mut prefix = [0; 20];
+        b.read_exact(&mut prefix).await.unwrap();
+        assert!(respond(b, &prefix, &gen_random_id(), CRYPTO_RC4).await.is_none());
+    }
+}
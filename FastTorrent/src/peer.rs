@@ -0,0 +1,289 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, net::{IpAddr, SocketAddr}, sync::Arc};
+use tokio::sync::Mutex;
+use crate::download::TransportKind;
+
+// Where we learned about a peer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    Lsd,
+    Incoming
+}
+
+// Peers waiting to be connected, with where they came from
+pub type PeerList = Arc<Mutex<VecDeque<(SocketAddr, PeerSource)>>>;
+
+// Connected peers by address
+pub type PeerRegistry = Arc<Mutex<HashMap<SocketAddr, PeerState>>>;
+
+// Snapshot of a connection, refreshed by its session about once a second
+#[derive(Clone, Debug)]
+pub struct PeerState {
+    pub addr: SocketAddr,
+    pub peer_id: [u8; 20],
+    pub client: Option<String>,
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    // Bytes per second
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub outstanding: usize,
+    // Fraction of pieces the peer has
+    pub progress: f64,
+    pub source: PeerSource,
+    pub transport: TransportKind,
+    pub encrypted: bool
+}
+
+impl PeerState {
+
+    pub fn new(addr: SocketAddr, peer_id: [u8; 20], source: PeerSource, transport: TransportKind, encrypted: bool) -> PeerState {
+        PeerState {
+            addr,
+            peer_id,
+            client: client_name(&peer_id, None),
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            download_rate: 0.0,
+            upload_rate: 0.0,
+            downloaded: 0,
+            uploaded: 0,
+            outstanding: 0,
+            progress: 0.0,
+            source,
+            transport,
+            encrypted
+        }
+    }
+
+    pub fn address(&self) -> String {
+        self.addr.to_string()
+    }
+
+    // Flags in the usual client notation: D/d downloading or interested but choked, U/u likewise for uploading,
+    // then E encrypted, P uTP and the source, I incoming, H DHT, X PEX, L LSD
+    pub fn flags(&self) -> String {
+
+        let mut flags = String::new();
+        if self.am_interested {
+            flags.push(if self.peer_choking { 'd' } else { 'D' });
+        }
+        if self.peer_interested {
+            flags.push(if self.am_choking { 'u' } else { 'U' });
+        }
+        if self.encrypted {
+            flags.push('E');
+        }
+        if self.transport == TransportKind::Utp {
+            flags.push('P');
+        }
+        match self.source {
+            PeerSource::Incoming => flags.push('I'),
+            PeerSource::Dht => flags.push('H'),
+            PeerSource::Pex => flags.push('X'),
+            PeerSource::Lsd => flags.push('L'),
+            PeerSource::Tracker => {}
+        }
+
+        flags
+    }
+}
+
+
+// Strikes after which a peer that keeps sending data for failed pieces is banned
+static STRIKE_LIMIT: u32 = 3;
+
+// Peers that sent corrupt data, kept for the rest of the session
+#[derive(Debug, Default)]
+pub struct BanList {
+    banned: HashSet<IpAddr>,
+    strikes: HashMap<IpAddr, u32>
+}
+
+impl BanList {
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.contains(&ip)
+    }
+
+    pub fn ban(&mut self, ip: IpAddr) {
+        self.banned.insert(ip);
+    }
+
+    // Count a failed piece the peer contributed to, returns true once it is banned
+    pub fn strike(&mut self, ip: IpAddr) -> bool {
+        let strikes = self.strikes.entry(ip).or_insert(0);
+        *strikes += 1;
+        if *strikes >= STRIKE_LIMIT {
+            self.banned.insert(ip);
+        }
+        self.is_banned(ip)
+    }
+
+    pub fn len(&self) -> usize {
+        self.banned.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.banned.is_empty()
+    }
+}
+
+// Azureus-style client codes, -XXvvvv-
+static AZUREUS_CLIENTS: [(&str, &str); 24] = [
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FT", "FastTorrent"),
+    ("FW", "FrostWire"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "libTorrent"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("SD", "Thunder"),
+    ("ST", "SymTorrent"),
+    ("TL", "Tribler"),
+    ("TR", "Transmission"),
+    ("UM", "\u{b5}Torrent Mac"),
+    ("UT", "\u{b5}Torrent"),
+    ("UW", "\u{b5}Torrent Web"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+    ("ZT", "ZipTorrent")
+];
+
+// Shadow-style client letters, Cvvvvv followed by dashes
+static SHADOW_CLIENTS: [(u8, &str); 7] = [
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent")
+];
+
+// Version digits of Shadow-style ids
+static SHADOW_DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz.-";
+
+// Join version components, dropping trailing zeros beyond major.minor.patch
+fn version(mut parts: Vec<u32>) -> String {
+    while parts.len() > 3 && parts.last() == Some(&0) {
+        parts.pop();
+    }
+    parts.iter().map(|part| part.to_string()).collect::<Vec<_>>().join(".")
+}
+
+// Name and version of the client that generated a peer id, for the common conventions
+pub fn decode_peer_id(id: &[u8; 20]) -> Option<String> {
+
+    // Azureus, -XXvvvv-
+    if id[0] == b'-' && id[7] == b'-' && id[1..7].iter().all(|c| c.is_ascii_alphanumeric()) {
+        let code = std::str::from_utf8(&id[1..3]).ok()?;
+        let name = AZUREUS_CLIENTS.iter().find(|(c, _)| *c == code).map(|(_, name)| name.to_string())
+            .unwrap_or_else(|| code.to_string());
+        let parts = id[3..7].iter().map(|c| (*c as char).to_digit(36).unwrap()).collect();
+        return Some(format!("{} {}", name, version(parts)));
+    }
+
+    // Mainline, Mx-y-z-- with multi-digit numbers allowed
+    if id[0] == b'M' && id[1].is_ascii_digit() {
+        let head = id[1..8].split(|c| *c == b'-').collect::<Vec<_>>();
+        if head.len() >= 3 && head[..3].iter().all(|part| !part.is_empty() && part.iter().all(u8::is_ascii_digit)) {
+            let parts = head[..3].iter().map(|part| std::str::from_utf8(part).unwrap().parse().unwrap_or(0)).collect();
+            return Some(format!("BitTorrent {}", version(parts)));
+        }
+    }
+
+    // Shadow, Cvvvvv padded with dashes
+    let name = SHADOW_CLIENTS.iter().find(|(c, _)| *c == id[0]).map(|(_, name)| *name)?;
+    let end = id[1..6].iter().position(|c| *c == b'-').map(|pos| pos + 1).unwrap_or(6);
+    if end > 1 && id[end..end + 3] == *b"---" {
+        let parts: Option<Vec<u32>> = id[1..end].iter().map(|c| SHADOW_DIGITS.iter().position(|d| d == c).map(|pos| pos as u32)).collect();
+        return Some(format!("{} {}", name, version(parts?)));
+    }
+
+    None
+}
+
+// Client name for the peer list, the extension handshake's own name wins over the decoded peer id
+pub fn client_name(id: &[u8; 20], v: Option<&str>) -> Option<String> {
+    match v {
+        Some(v) if !v.is_empty() => Some(v.to_string()),
+        _ => decode_peer_id(id)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use crate::download::TransportKind;
+    use super::{client_name, decode_peer_id, BanList, PeerSource, PeerState};
+
+    #[test]
+    fn flags_test() {
+
+        let mut state = PeerState::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 6881)), [0; 20], PeerSource::Dht, TransportKind::Utp, true);
+        state.am_interested = true;
+        state.peer_choking = false;
+        state.peer_interested = true;
+        assert_eq!(state.flags(), "DuEPH");
+        assert_eq!(state.address(), "127.0.0.1:6881");
+
+        let state = PeerState::new(SocketAddr::from((Ipv6Addr::LOCALHOST, 1)), *b"-FT0100-abcdefghijkl", PeerSource::Tracker, TransportKind::Tcp, false);
+        assert_eq!(state.flags(), "");
+        assert_eq!(state.address(), "[::1]:1");
+        assert_eq!(state.client.unwrap(), "FastTorrent 0.1.0");
+    }
+
+    #[test]
+    fn decode_peer_id_test() {
+
+        let id = |s: &str| {
+            let mut id = [b'x'; 20];
+            id[..s.len()].copy_from_slice(s.as_bytes());
+            id
+        };
+
+        assert_eq!(decode_peer_id(&id("-FT0100-")).unwrap(), "FastTorrent 0.1.0");
+        assert_eq!(decode_peer_id(&id("-qB4250-")).unwrap(), "qBittorrent 4.2.5");
+        assert_eq!(decode_peer_id(&id("-XX1234-")).unwrap(), "XX 1.2.3.4");
+        assert_eq!(decode_peer_id(&id("M4-3-6--")).unwrap(), "BitTorrent 4.3.6");
+        assert_eq!(decode_peer_id(&id("M4-20-8-")).unwrap(), "BitTorrent 4.20.8");
+        assert_eq!(decode_peer_id(&id("T03I-----")).unwrap(), "BitTornado 0.3.18");
+        assert_eq!(decode_peer_id(&id("S58B-----")).unwrap(), "Shadow 5.8.11");
+        assert_eq!(decode_peer_id(&[0xAB; 20]), None);
+
+        assert_eq!(client_name(&id("-FT0100-"), Some("FastTorrent 0.1.0")).unwrap(), "FastTorrent 0.1.0");
+        assert_eq!(client_name(&id("-TR2940-"), None).unwrap(), "Transmission 2.9.4");
+    }
+
+    #[test]
+    fn ban_list_test() {
+
+        let (a, b) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from(Ipv6Addr::LOCALHOST));
+        let mut bans = BanList::default();
+        assert!(!bans.strike(a));
+        assert!(!bans.strike(a));
+        assert!(bans.strike(a));
+        assert!(!bans.is_banned(b));
+
+        bans.ban(b);
+        assert!(bans.is_banned(b));
+        assert_eq!(bans.len(), 2);
+    }
+}
@@ -0,0 +1,75 @@
+use std::{fs, path::{Path, PathBuf}};
+use crate::{
+    bencoded_parser::{Bencode, Element},
+    helpers,
+    tracker::Tracker
+};
+
+// State of a torrent kept between runs, next to the DHT state
+#[derive(Debug, Default, PartialEq)]
+pub struct ResumeData {
+    // Tracker tiers as last edited, these replace the ones in the torrent file
+    pub trackers: Vec<Vec<String>>
+}
+
+impl ResumeData {
+
+    // Resume file of a torrent, named after its info hash
+    pub fn path(info_hash: &[u8; 20]) -> PathBuf {
+        helpers::state_dir().join("resume").join(format!("{}.resume", hex::encode(info_hash)))
+    }
+
+    // Tiers of the tracker list as it stands, in order, without the ones added for this run
+    pub fn from_trackers(trackers: &[Tracker]) -> ResumeData {
+        let trackers: Vec<&Tracker> = trackers.iter().filter(|tracker| !tracker.session).collect();
+        let trackers = trackers.chunk_by(|a, b| a.tier == b.tier)
+            .map(|tier| tier.iter().map(|tracker| tracker.url.clone()).collect())
+            .collect();
+        ResumeData { trackers }
+    }
+
+    pub fn load(path: &Path) -> Option<ResumeData> {
+        let state = Bencode::decode_u8(fs::read(path).ok()?).ok()?;
+        let trackers = state.get("trackers")?.as_list()?.iter().map(|tier| {
+            tier.as_list().into_iter().flatten()
+                .filter_map(|url| String::from_utf8(url.as_bytes()?.to_vec()).ok())
+                .collect()
+        }).collect();
+        Some(ResumeData { trackers })
+    }
+
+    pub fn save(&self, path: &Path) {
+
+        let tiers = self.trackers.iter().map(|tier| {
+            Element::List(tier.iter().map(|url| Element::ByteString(url.as_bytes().to_vec())).collect())
+        }).collect();
+        let state = Element::Dict([(b"trackers".to_vec(), Element::List(tiers))].into_iter().collect());
+
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        let _ = fs::write(path, Bencode::encode(&state));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+    use crate::tracker::Tracker;
+    use super::ResumeData;
+
+    #[test]
+    fn save_load_test() {
+
+        let trackers = vec![Tracker::new("a".to_string(), 0), Tracker::new("b".to_string(), 0), Tracker::new("c".to_string(), 2)];
+        let resume = ResumeData::from_trackers(&trackers);
+        assert_eq!(resume.trackers, vec![vec!["a", "b"], vec!["c"]]);
+
+        let path = env::temp_dir().join(format!("ft_resume_{}", rand::random::<u32>())).join("x.resume");
+        resume.save(&path);
+        assert_eq!(ResumeData::load(&path), Some(resume));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(ResumeData::load(&path), None);
+    }
+}
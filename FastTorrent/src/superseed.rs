@@ -0,0 +1,67 @@
+use std::{collections::HashSet, net::IpAddr};
+
+// Super seeding (BEP 16): each peer is shown one piece at a time, and only gets a new one
+// after the last piece it was shown turns up at another peer
+pub struct SuperSeed {
+    // Peers known to have each piece, from their Have and bitfield messages
+    holders: Vec<HashSet<IpAddr>>,
+    // Times each piece has been offered
+    offers: Vec<u32>
+}
+
+impl SuperSeed {
+
+    pub fn new(piece_no: usize) -> SuperSeed {
+        SuperSeed {
+            holders: vec![HashSet::new(); piece_no],
+            offers: vec![0; piece_no]
+        }
+    }
+
+    pub fn on_have(&mut self, index: u32, peer: IpAddr) {
+        if let Some(holders) = self.holders.get_mut(index as usize) {
+            holders.insert(peer);
+        }
+    }
+
+    // Piece offered to peer has been seen at some other peer
+    pub fn spread(&self, index: u32, peer: IpAddr) -> bool {
+        self.holders[index as usize].iter().any(|holder| *holder != peer)
+    }
+
+    // Rarest piece the peer lacks, least offered first so pieces are handed out evenly
+    pub fn offer(&mut self, peer_has: &[bool]) -> Option<u32> {
+
+        let index = (0..self.holders.len())
+            .filter(|index| !peer_has[*index])
+            .min_by_key(|index| (self.holders[*index].len(), self.offers[*index]))?;
+
+        self.offers[index] += 1;
+        Some(index as u32)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use super::SuperSeed;
+
+    #[test]
+    fn offer_test() {
+
+        let mut seed = SuperSeed::new(3);
+        assert_eq!(seed.offer(&[false; 3]), Some(0));
+        assert_eq!(seed.offer(&[false; 3]), Some(1));
+
+        // Peer a got piece 0, it has not spread until someone else announces it
+        let (a, b) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+        seed.on_have(0, a);
+        assert!(!seed.spread(0, a));
+        seed.on_have(0, b);
+        assert!(seed.spread(0, a));
+
+        assert_eq!(seed.offer(&[true, false, false]), Some(2));
+        assert_eq!(seed.offer(&[true; 3]), None);
+    }
+}
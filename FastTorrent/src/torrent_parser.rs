@@ -1,263 +1,548 @@
-use std::{
-    collections::{VecDeque, HashSet}, sync::Arc, {fmt,fs::File}
-};
-use tokio::sync::Mutex;
-use crate:: {
-    bencoded_parser::{Bencode, Element},
-    helpers::{self, BLOCK_SIZE}
-};
-
-pub struct Torrent {
-    pub announce_url: Option<String>,
-    pub announce_list: Option<Vec<String>>,
-    pub name: String,
-    pub length: u64,
-    pub info_hash: [u8; 20],
-    pub peer_list: Arc<Mutex<VecDeque<(u32,u16)>>>,
-    pub peer_id: [u8; 20],
-    pub piece_freq: Arc<Mutex<Vec<Piece>>>,
-    pub downloaded: Arc<Mutex<u64>>,
-    pub uploaded: Arc<Mutex<u64>>,
-    pub connections: Arc<Mutex<HashSet<(u32,u16)>>>,
-    pub file_list: Option<Vec<(String, u64)>>,
-    pub piece_hashes: Arc<Vec<Vec<u8>>>,
-    pub piece_left: Arc<Mutex<u16>>
-}
-
-#[derive(Clone)]
-#[derive(Debug)]
-pub struct Piece {
-    pub ref_no: u16,
-    pub length: u64,
-    pub blocks: Vec<Block>,
-    pub completed: bool
-}
-
-#[derive(Clone)]
-#[derive(Debug)]
-pub struct Block {
-    pub is_req: bool,
-    pub length: u64,
-    pub offset: u64
-}
-
-impl Torrent {
-
-    pub async fn parse_decoded(file: &mut File) -> Result<Torrent, InvalidTorrentFile> {
-
-        let (decoded, info_hash) = Bencode::decode(file).unwrap();
-        let (announce_url, announce_list, name, piece_length, hashes, length, piece_no, file_list) = Torrent::parse_decoded_helper(&decoded)?;
-
-        let mut no_blocks = piece_length/(BLOCK_SIZE as u64);
-        if piece_length/(BLOCK_SIZE as u64) != 0 { no_blocks += 1; }
-
-        let torrent = Torrent { 
-            announce_url, 
-            announce_list, 
-            name, 
-            length, 
-            info_hash, 
-            peer_list: Arc::new(Mutex::new(VecDeque::new())), 
-            peer_id: helpers::gen_random_id(), 
-            piece_freq: Arc::new(Mutex::new(Torrent::build_piece_freq(no_blocks, piece_no, piece_length, length))),
-            downloaded: Arc::new(Mutex::new(0)),
-            uploaded: Arc::new(Mutex::new(0)),
-            connections: Arc::new(Mutex::new(HashSet::new())),
-            file_list,
-            piece_hashes: Arc::new(hashes),
-            piece_left: Arc::new(Mutex::new(piece_no as u16))
-        };
-
-        Ok(torrent)
-
-    }
-
-    // Function to return Announce Url, name, piece length and hashes from a decoded torrent file
-    fn parse_decoded_helper(decoded: &Element) -> Result<(Option<String>, Option<Vec<String>>, String, u64, Vec<Vec<u8>>, u64, usize, Option<Vec<(String, u64)>>), InvalidTorrentFile> {
-
-        let mut announce = None;
-        let mut announce_list = None;
-        let mut name = String::new();
-        let mut piece_length = 0;
-        let mut hashes = Vec::new();
-        let mut length: u64 = 0;
-        let mut files: Option<Vec<(String, u64)>> = None;
-        let mut piece_no: usize = 0;
-
-        match decoded {
-            Element::Dict(mp) => {
-
-                // Get List of announce urls
-                if mp.contains_key("announce-list".as_bytes()) {
-                    let mut tmp = Vec::new();
-                    if let Element::List(l) = &mp["announce-list".as_bytes()] {
-                        for i in l {
-                            if let Element::List(l1) = i {
-                                if let Element::ByteString(s) = &l1[0] {
-                                    tmp.push(String::from_utf8(s.to_owned()).unwrap());
-                                }
-                            } 
-                        }
-                    }
-                    announce_list = Some(tmp);
-                }
-                else { 
-                    // Get Announce url of torrent file
-                    if mp.contains_key("announce".as_bytes()) {
-                        if let Element::ByteString(s) = &mp["announce".as_bytes()] { announce = Some(String::from_utf8(s.to_owned()).unwrap()); } 
-                        else { return Err(InvalidTorrentFile{case: 0}); }
-                    } 
-                    else { return Err(InvalidTorrentFile{case: 1}); }
-                }
-
-                // Get info of torrent file
-                if mp.contains_key("info".as_bytes()) {
-
-                    match &mp["info".as_bytes()] {
-                        Element::Dict(info_mp) => {
-                            if !info_mp.contains_key("name".as_bytes()) || !info_mp.contains_key("piece length".as_bytes()) || !info_mp.contains_key("pieces".as_bytes()) || (!info_mp.contains_key("length".as_bytes()) && !info_mp.contains_key("files".as_bytes())) { return Err(InvalidTorrentFile{case: 6}); }
-
-                            // Name
-                            if let Element::ByteString(s) = &info_mp["name".as_bytes()] { name += &String::from_utf8_lossy(s); }
-                            
-                            // Piece Length
-                            if let Element::Integer(l) = &info_mp["piece length".as_bytes()] { piece_length += l; }
-                            
-                            // Piece Hashes
-                            if let Element::ByteString(s) = &info_mp["pieces".as_bytes()] {
-                                piece_no = s.len()/20;
-
-                                for i in (0..s.len()).step_by(20) {
-                                    let tmp = s[i..(i+20)].to_vec();
-                                    hashes.push(tmp);
-                                }
-
-                            }
-
-                            // Length for single file
-                            if info_mp.contains_key("length".as_bytes()) {
-                                if let Element::Integer(l) = &info_mp["length".as_bytes()] { length += l.abs() as u64; }
-                            }
-
-                            // Length for multiple files
-                            if info_mp.contains_key("files".as_bytes()) {
-
-                                let mut file_list = Vec::new();
-
-                                if let Element::List(files) = &info_mp["files".as_bytes()] {
-                                    for file in files {
-                                        if let Element::Dict(file_mp) = file {
-                                            
-                                            if let Element::Integer(l) = file_mp["length".as_bytes()] {
-
-                                                length += l.abs() as u64;
-                                                let mut path = String::new();
-
-                                                if let Element::List(v) = &file_mp["path".as_bytes()] {
-                                                    for s in v {
-                                                        if let Element::ByteString(part) = s {
-                                                            path += &(String::from_utf8(part.to_owned()).unwrap() + "/");
-                                                        }
-                                                    }
-                                                }
-                                                path.pop();
-                                                file_list.push((path, l.abs() as u64));
-                                            }
-
-
-                                        }
-                                    }
-                                }
-                                files = Some(file_list);
-                            }
-
-                        }
-                        _ => { return Err(InvalidTorrentFile{case: 3}); }
-                    }
-                }
-                else { return Err(InvalidTorrentFile { case: 4 }); }
-
-            },
-            _ => { return Err(InvalidTorrentFile{case: 5}); }
-            
-        }
-        Ok((announce,announce_list,name,piece_length as u64, hashes, length, piece_no, files))
-    }
-
-    // Function to build the piece frequency array used by download
-    fn build_piece_freq(no_blocks: u64, piece_no: usize, piece_length: u64, length: u64) -> Vec<Piece> {
-        
-        // Vector of all pieces, for each piece contains all blocks for each block a bool and the size of the block
-        let mut piece_freq = vec! [
-            Piece {
-                ref_no: 0,
-                length: piece_length,
-                blocks: vec![
-                        Block {
-                            is_req: false,
-                            length: BLOCK_SIZE as u64,
-                            offset: 0
-                        }; 
-                        no_blocks as usize
-                    ],
-                completed: false
-            };
-            piece_no
-        ];
-        
-        // Check whether pieces can be perfectly divided into blocks or last block of piece should be lesser in size
-        if piece_length%(BLOCK_SIZE as u64) != 0 {
-            for piece in &mut piece_freq {
-                piece.blocks.last_mut().unwrap().length = piece_length%(BLOCK_SIZE as u64);
-            }
-        }
-        
-        // Check whether last piece has same number of blocks as other pieces
-        let last_piece_length = length%piece_length;
-        if last_piece_length != 0 {
-            
-            piece_freq.last_mut().unwrap().length = last_piece_length;
-
-            let last_piece_block_no = last_piece_length/(BLOCK_SIZE as u64);
-
-            while piece_freq.last().unwrap().blocks.len() != last_piece_block_no as usize {
-                piece_freq.last_mut().unwrap().blocks.pop();
-            }
-            
-            // Check whether last pieces last block is of BLOCK_SIZE or not
-            if last_piece_length%(BLOCK_SIZE as u64) != 0 { 
-                piece_freq.last_mut().unwrap().blocks.push(
-                    Block {
-                        is_req: false, 
-                        length: (last_piece_length as u64)%(BLOCK_SIZE as u64), 
-                        offset: 0
-                    }
-                );
-            }
-    
-        }
-
-        let mut curr: u64 = 0;
-
-        for piece in &mut piece_freq {
-            for block in &mut piece.blocks {
-                (*block).offset = curr;
-                curr += block.length;
-            }
-        }
-    
-        piece_freq
-    }
-
-}
-
-#[derive(Debug)]
-pub struct InvalidTorrentFile {
-    case: i32
-}
-
-impl fmt::Display for InvalidTorrentFile {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Keys missing in torrent file {}", self.case)
-    }
-}
\ No newline at end of file
+use std::{
+    collections::{VecDeque, HashMap}, net::IpAddr, path::PathBuf, sync::Arc, {fmt,fs::File}
+};
+use tokio::sync::Mutex;
+use crate:: {
+    bencoded_parser::{Bencode, Element},
+    helpers::{self, BLOCK_SIZE},
+    peer::{BanList, PeerList, PeerRegistry, PeerSource},
+    resume::ResumeData,
+    superseed::SuperSeed,
+    tracker::{Tracker, TrackerList}
+};
+
+#[derive(Clone)]
+pub struct Torrent {
+    pub announce_url: Option<String>,
+    // Tiers of tracker urls (BEP 12)
+    pub announce_list: Option<Vec<Vec<String>>>,
+    pub name: String,
+    pub length: u64,
+    pub info_hash: [u8; 20],
+    pub peer_list: PeerList,
+    pub peer_id: [u8; 20],
+    // Port peers reach us on as bound at startup, told to trackers, peers, the DHT and LSD
+    pub listen_port: u16,
+    pub piece_freq: Arc<Mutex<Vec<Piece>>>,
+    pub downloaded: Arc<Mutex<u64>>,
+    // Bytes verified on disk at startup, not reported to trackers as downloaded
+    pub existing: u64,
+    pub uploaded: Arc<Mutex<u64>>,
+    pub connections: PeerRegistry,
+    pub file_list: Option<Vec<(String, u64)>>,
+    // Web seeds, GetRight style (BEP 19) and Hoffman style (BEP 17)
+    pub url_list: Vec<String>,
+    pub http_seeds: Vec<String>,
+    pub piece_hashes: Arc<Vec<Vec<u8>>>,
+    pub piece_left: Arc<Mutex<u16>>,
+    // Missing pieces of the selected files, zero once we hold everything we want
+    pub wanted_left: Arc<Mutex<u16>>,
+    pub bans: Arc<Mutex<BanList>>,
+    // Set when super seeding is enabled
+    pub super_seed: Option<Arc<Mutex<SuperSeed>>>,
+    // Announce state of each tracker
+    pub trackers: TrackerList,
+    // Private torrent (BEP 27), peers come from its trackers only
+    pub private: bool,
+    // Where tracker edits are saved, none until resumed
+    pub resume_path: Option<PathBuf>
+}
+
+// Announce url, announce list, name, piece length, hashes, length, number of pieces, file list
+type ParsedTorrent = (Option<String>, Option<Vec<Vec<String>>>, String, u64, Vec<Vec<u8>>, u64, usize, Option<Vec<(String, u64)>>);
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Piece {
+    pub ref_no: u16,
+    pub length: u64,
+    pub blocks: Vec<Block>,
+    pub completed: bool,
+    // Failed its hash check with blocks from several peers, so the next attempt comes from one peer only
+    pub single_source: bool,
+    // Peer downloading a single source piece
+    pub owner: Option<IpAddr>,
+    // Overlaps a file selected for download
+    pub wanted: bool
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Block {
+    pub is_req: bool,
+    pub received: bool,
+    // IP of the peer the block came from
+    pub from: Option<IpAddr>,
+    pub length: u64,
+    pub offset: u64
+}
+
+impl Torrent {
+
+    pub async fn parse_decoded(file: &mut File) -> Result<Torrent, InvalidTorrentFile> {
+
+        let (decoded, info_hash) = Bencode::decode(file).unwrap();
+        let (announce_url, announce_list, name, piece_length, hashes, length, piece_no, file_list) = Torrent::parse_decoded_helper(&decoded)?;
+
+        let no_blocks = piece_length.div_ceil(BLOCK_SIZE as u64);
+        // A torrent with an announce list ignores the announce key
+        let tiers = match &announce_list {
+            Some(tiers) => tiers.clone(),
+            None => announce_url.iter().map(|url| vec![url.clone()]).collect()
+        };
+        let trackers = Tracker::from_tiers(tiers);
+        let (url_list, http_seeds) = (Torrent::url_strings(decoded.get("url-list")), Torrent::url_strings(decoded.get("httpseeds")));
+        let private = decoded.get("info").and_then(|info| info.get("private")).and_then(Element::as_int) == Some(1);
+
+        let torrent = Torrent { 
+            announce_url, 
+            announce_list, 
+            name, 
+            length, 
+            info_hash, 
+            peer_list: Arc::new(Mutex::new(VecDeque::new())), 
+            peer_id: helpers::gen_peer_id(helpers::PEER_ID_PREFIX), 
+            listen_port: helpers::LISTEN_PORT,
+            piece_freq: Arc::new(Mutex::new(Torrent::build_piece_freq(no_blocks, piece_no, piece_length, length))),
+            downloaded: Arc::new(Mutex::new(0)),
+            existing: 0,
+            uploaded: Arc::new(Mutex::new(0)),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            file_list,
+            url_list,
+            http_seeds,
+            piece_hashes: Arc::new(hashes),
+            piece_left: Arc::new(Mutex::new(piece_no as u16)),
+            wanted_left: Arc::new(Mutex::new(piece_no as u16)),
+            bans: Arc::new(Mutex::new(BanList::default())),
+            super_seed: None,
+            trackers: Arc::new(Mutex::new(trackers)),
+            private,
+            resume_path: None
+        };
+
+        Ok(torrent)
+
+    }
+
+    // Function to return Announce Url, name, piece length and hashes from a decoded torrent file
+    fn parse_decoded_helper(decoded: &Element) -> Result<ParsedTorrent, InvalidTorrentFile> {
+
+        let mut announce = None;
+        let mut announce_list = None;
+        let mut name = String::new();
+        let mut piece_length = 0;
+        let mut hashes = Vec::new();
+        let mut length: u64 = 0;
+        let mut files: Option<Vec<(String, u64)>> = None;
+        let mut piece_no: usize = 0;
+
+        match decoded {
+            Element::Dict(mp) => {
+
+                // Get List of announce urls
+                if mp.contains_key("announce-list".as_bytes()) {
+                    let mut tmp = Vec::new();
+                    if let Element::List(l) = &mp["announce-list".as_bytes()] {
+                        for i in l {
+                            if let Element::List(l1) = i {
+                                let tier: Vec<String> = l1.iter().filter_map(Element::as_bytes).filter_map(|s| String::from_utf8(s.to_owned()).ok()).collect();
+                                if !tier.is_empty() {
+                                    tmp.push(tier);
+                                }
+                            } 
+                        }
+                    }
+                    announce_list = Some(tmp);
+                }
+                else { 
+                    // Get Announce url of torrent file
+                    if mp.contains_key("announce".as_bytes()) {
+                        if let Element::ByteString(s) = &mp["announce".as_bytes()] { announce = Some(String::from_utf8(s.to_owned()).unwrap()); } 
+                        else { return Err(InvalidTorrentFile{case: 0}); }
+                    } 
+                    else { return Err(InvalidTorrentFile{case: 1}); }
+                }
+
+                // Get info of torrent file
+                if mp.contains_key("info".as_bytes()) {
+
+                    match &mp["info".as_bytes()] {
+                        Element::Dict(info_mp) => {
+                            if !info_mp.contains_key("name".as_bytes()) || !info_mp.contains_key("piece length".as_bytes()) || !info_mp.contains_key("pieces".as_bytes()) || (!info_mp.contains_key("length".as_bytes()) && !info_mp.contains_key("files".as_bytes())) { return Err(InvalidTorrentFile{case: 6}); }
+
+                            // Name
+                            if let Element::ByteString(s) = &info_mp["name".as_bytes()] { name += &String::from_utf8_lossy(s); }
+                            
+                            // Piece Length
+                            if let Element::Integer(l) = &info_mp["piece length".as_bytes()] { piece_length += l; }
+                            
+                            // Piece Hashes
+                            if let Element::ByteString(s) = &info_mp["pieces".as_bytes()] {
+                                piece_no = s.len()/20;
+
+                                for i in (0..s.len()).step_by(20) {
+                                    let tmp = s[i..(i+20)].to_vec();
+                                    hashes.push(tmp);
+                                }
+
+                            }
+
+                            // Length for single file
+                            if info_mp.contains_key("length".as_bytes()) {
+                                if let Element::Integer(l) = &info_mp["length".as_bytes()] { length += l.unsigned_abs(); }
+                            }
+
+                            // Length for multiple files
+                            if info_mp.contains_key("files".as_bytes()) {
+
+                                let mut file_list = Vec::new();
+
+                                if let Element::List(files) = &info_mp["files".as_bytes()] {
+                                    for file in files {
+                                        if let Element::Dict(file_mp) = file {
+                                            
+                                            if let Element::Integer(l) = file_mp["length".as_bytes()] {
+
+                                                length += l.unsigned_abs();
+                                                let mut path = String::new();
+
+                                                if let Element::List(v) = &file_mp["path".as_bytes()] {
+                                                    for s in v {
+                                                        if let Element::ByteString(part) = s {
+                                                            path += &(String::from_utf8(part.to_owned()).unwrap() + "/");
+                                                        }
+                                                    }
+                                                }
+                                                path.pop();
+                                                file_list.push((path, l.unsigned_abs()));
+                                            }
+
+
+                                        }
+                                    }
+                                }
+                                files = Some(file_list);
+                            }
+
+                        }
+                        _ => { return Err(InvalidTorrentFile{case: 3}); }
+                    }
+                }
+                else { return Err(InvalidTorrentFile { case: 4 }); }
+
+            },
+            _ => { return Err(InvalidTorrentFile{case: 5}); }
+            
+        }
+        Ok((announce,announce_list,name,piece_length as u64, hashes, length, piece_no, files))
+    }
+
+    // Snapshot of every tracker's announce state
+    pub async fn trackers(&self) -> Vec<Tracker> {
+        self.trackers.lock().await.clone()
+    }
+
+    // Take the trackers saved by an earlier run, and save there after every change
+    pub async fn resume(&mut self, path: PathBuf) {
+        if let Some(resume) = ResumeData::load(&path) {
+            *self.trackers.lock().await = Tracker::from_tiers(resume.trackers);
+        }
+        self.resume_path = Some(path);
+    }
+
+    // Add a tracker at the end of its tier, false if we already have it
+    pub async fn add_tracker(&self, url: String, tier: usize) -> bool {
+        let mut trackers = self.trackers.lock().await;
+        let added = Torrent::insert_tracker(&mut trackers, Tracker::new(url, tier));
+        if added {
+            self.save_trackers(&trackers);
+        }
+        added
+    }
+
+    // Same, but only for this run, like the ones given on the command line
+    pub async fn add_session_tracker(&self, url: String, tier: usize) -> bool {
+        let tracker = Tracker { session: true, ..Tracker::new(url, tier) };
+        Torrent::insert_tracker(&mut *self.trackers.lock().await, tracker)
+    }
+
+    fn insert_tracker(trackers: &mut Vec<Tracker>, tracker: Tracker) -> bool {
+        if trackers.iter().any(|other| other.url == tracker.url) {
+            return false;
+        }
+        let at = trackers.iter().position(|other| other.tier > tracker.tier).unwrap_or(trackers.len());
+        trackers.insert(at, tracker);
+        true
+    }
+
+    pub async fn remove_tracker(&self, url: &str) -> bool {
+        let mut trackers = self.trackers.lock().await;
+        let count = trackers.len();
+        trackers.retain(|tracker| tracker.url != url);
+        let removed = trackers.len() != count;
+        if removed {
+            self.save_trackers(&trackers);
+        }
+        removed
+    }
+
+    // Point a tracker at a new url in the same tier, it starts over there and stays saved or for this run only
+    pub async fn edit_tracker(&self, url: &str, new_url: String) -> bool {
+        let mut trackers = self.trackers.lock().await;
+        if trackers.iter().any(|tracker| tracker.url == new_url) {
+            return false;
+        }
+        let Some(tracker) = trackers.iter_mut().find(|tracker| tracker.url == url) else {
+            return false;
+        };
+        *tracker = Tracker { session: tracker.session, ..Tracker::new(new_url, tracker.tier) };
+        self.save_trackers(&trackers);
+        true
+    }
+
+    // Announce to one tracker, or all of them, without waiting for the schedule
+    pub async fn reannounce(&self, url: Option<&str>) -> bool {
+        let mut found = false;
+        for tracker in self.trackers.lock().await.iter_mut().filter(|tracker| url.is_none_or(|url| tracker.url == url)) {
+            tracker.forced = true;
+            found = true;
+        }
+        found
+    }
+
+    fn save_trackers(&self, trackers: &[Tracker]) {
+        if let Some(path) = &self.resume_path {
+            ResumeData::from_trackers(trackers).save(path);
+        }
+    }
+
+    // Whether peers found this way may be used, a private torrent ignores DHT, PEX and LSD
+    pub fn accepts(&self, source: PeerSource) -> bool {
+        !self.private || matches!(source, PeerSource::Tracker | PeerSource::Incoming)
+    }
+
+    // Urls given as a single string or a list of strings
+    fn url_strings(element: Option<&Element>) -> Vec<String> {
+        let urls = match element {
+            Some(Element::List(list)) => list.iter().filter_map(Element::as_bytes).collect(),
+            Some(element) => element.as_bytes().into_iter().collect(),
+            None => Vec::new()
+        };
+        urls.into_iter().filter_map(|url| String::from_utf8(url.to_vec()).ok()).filter(|url| !url.is_empty()).collect()
+    }
+
+    // Only download pieces overlapping the given files, must run before checking existing data
+    pub async fn select_files(&self, selected: &[usize]) {
+
+        let sizes: Vec<u64> = match &self.file_list {
+            Some(files) => files.iter().map(|(_, size)| *size).collect(),
+            None => vec![self.length]
+        };
+
+        let mut freq = self.piece_freq.lock().await;
+        let piece_length = freq[0].length;
+        let wanted = Torrent::wanted_pieces(&sizes, selected, piece_length, freq.len());
+        for (piece, wanted) in freq.iter_mut().zip(&wanted) {
+            piece.wanted = *wanted;
+        }
+        *self.wanted_left.lock().await = wanted.iter().filter(|wanted| **wanted).count() as u16;
+    }
+
+    // Pieces holding any byte of the selected files
+    fn wanted_pieces(sizes: &[u64], selected: &[usize], piece_length: u64, piece_no: usize) -> Vec<bool> {
+
+        let mut wanted = vec![false; piece_no];
+        let mut start = 0;
+        for (index, size) in sizes.iter().enumerate() {
+            if *size > 0 && selected.contains(&index) {
+                let first = (start / piece_length) as usize;
+                let last = ((start + size - 1) / piece_length) as usize;
+                for piece in wanted.iter_mut().take(last + 1).skip(first) {
+                    *piece = true;
+                }
+            }
+            start += size;
+        }
+        wanted
+    }
+
+    // Function to build the piece frequency array used by download
+    pub(crate) fn build_piece_freq(no_blocks: u64, piece_no: usize, piece_length: u64, length: u64) -> Vec<Piece> {
+        
+        // Vector of all pieces, for each piece contains all blocks for each block a bool and the size of the block
+        let mut piece_freq = vec! [
+            Piece {
+                ref_no: 0,
+                length: piece_length,
+                blocks: vec![
+                        Block {
+                            is_req: false,
+                            received: false,
+                            from: None,
+                            length: BLOCK_SIZE as u64,
+                            offset: 0
+                        }; 
+                        no_blocks as usize
+                    ],
+                completed: false,
+                single_source: false,
+                owner: None,
+                wanted: true
+            };
+            piece_no
+        ];
+        
+        // Check whether pieces can be perfectly divided into blocks or last block of piece should be lesser in size
+        if !piece_length.is_multiple_of(BLOCK_SIZE as u64) {
+            for piece in &mut piece_freq {
+                piece.blocks.last_mut().unwrap().length = piece_length%(BLOCK_SIZE as u64);
+            }
+        }
+        
+        // Check whether last piece has same number of blocks as other pieces
+        let last_piece_length = length%piece_length;
+        if last_piece_length != 0 {
+            
+            piece_freq.last_mut().unwrap().length = last_piece_length;
+
+            let last_piece_block_no = last_piece_length/(BLOCK_SIZE as u64);
+
+            while piece_freq.last().unwrap().blocks.len() != last_piece_block_no as usize {
+                piece_freq.last_mut().unwrap().blocks.pop();
+            }
+            
+            // Check whether last pieces last block is of BLOCK_SIZE or not
+            if !last_piece_length.is_multiple_of(BLOCK_SIZE as u64) { 
+                piece_freq.last_mut().unwrap().blocks.push(
+                    Block {
+                        is_req: false, 
+                        received: false,
+                        from: None,
+                        length: last_piece_length%(BLOCK_SIZE as u64), 
+                        offset: 0
+                    }
+                );
+            }
+    
+        }
+
+        let mut curr: u64 = 0;
+
+        for piece in &mut piece_freq {
+            for block in &mut piece.blocks {
+                block.offset = curr;
+                curr += block.length;
+            }
+        }
+    
+        piece_freq
+    }
+
+}
+
+#[derive(Debug)]
+pub struct InvalidTorrentFile {
+    case: i32
+}
+
+impl fmt::Display for InvalidTorrentFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Keys missing in torrent file {}", self.case)
+    }
+}
+
+
+// Torrent of single block pieces over one file, for tests elsewhere in the crate
+#[cfg(test)]
+pub(crate) async fn test_torrent(announce: &str, pieces: usize) -> Torrent {
+
+    let path = std::env::temp_dir().join(format!("ft_torrent_{}", rand::random::<u32>()));
+    let info = format!("d6:lengthi{}e4:name1:a12:piece lengthi16384e6:pieces{}:{}e", pieces * 16384, pieces * 20, "x".repeat(pieces * 20));
+    std::fs::write(&path, format!("d8:announce{}:{}4:info{}e", announce.len(), announce, info)).unwrap();
+    let torrent = Torrent::parse_decoded(&mut File::open(&path).unwrap()).await.unwrap();
+    std::fs::remove_file(path).unwrap();
+    torrent
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::{self, File}, env};
+    use crate::{peer::PeerSource, resume::ResumeData};
+    use super::Torrent;
+
+    #[test]
+    fn piece_freq_test() {
+
+        // Two full pieces of two blocks and a last piece of one and a half blocks
+        let freq = Torrent::build_piece_freq(2, 3, 32768, 90112);
+        assert_eq!(freq.iter().map(|piece| piece.blocks.len()).collect::<Vec<_>>(), vec![2, 2, 2]);
+        assert_eq!(freq[2].length, 24576);
+        assert_eq!(freq[2].blocks[1].length, 8192);
+        assert_eq!(freq[2].blocks[1].offset, 81920);
+
+        // Files of 10, 20 and 5 bytes over pieces of 8, the middle one spans pieces 1 to 3
+        assert_eq!(Torrent::wanted_pieces(&[10, 20, 5], &[1], 8, 5), vec![false, true, true, true, false]);
+        assert_eq!(Torrent::wanted_pieces(&[10, 20, 5], &[0, 2], 8, 5), vec![true, true, false, true, true]);
+
+    }
+
+    #[tokio::test]
+    async fn private_test() {
+
+        let path = env::temp_dir().join(format!("private-{}.torrent", std::process::id()));
+        let mut torrent = None;
+        for flag in ["", "7:privatei1e"] {
+            let info = format!("d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:{}{}e", "x".repeat(20), flag);
+            fs::write(&path, format!("d8:announce14:http://t/a/ann4:info{}e", info)).unwrap();
+            torrent = Some(Torrent::parse_decoded(&mut File::open(&path).unwrap()).await.unwrap());
+            assert_eq!(torrent.as_ref().unwrap().private, !flag.is_empty());
+        }
+        fs::remove_file(&path).unwrap();
+
+        // Only tracker and incoming peers for a private torrent
+        let torrent = torrent.unwrap();
+        assert!(torrent.accepts(PeerSource::Tracker) && torrent.accepts(PeerSource::Incoming));
+        assert!(!torrent.accepts(PeerSource::Dht) && !torrent.accepts(PeerSource::Pex) && !torrent.accepts(PeerSource::Lsd));
+    }
+
+    #[tokio::test]
+    async fn tracker_edit_test() {
+
+        let dir = env::temp_dir().join(format!("ft_edit_{}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.torrent");
+        fs::write(&path, format!("d8:announce8:http://a4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:{}ee", "x".repeat(20))).unwrap();
+        let mut torrent = Torrent::parse_decoded(&mut File::open(&path).unwrap()).await.unwrap();
+        torrent.resume(dir.join("a.resume")).await;
+
+        assert!(torrent.add_tracker("udp://b:1".to_string(), 1).await);
+        assert!(!torrent.add_tracker("http://a".to_string(), 2).await);
+        assert!(torrent.add_tracker("http://c".to_string(), 0).await);
+        assert!(torrent.edit_tracker("udp://b:1", "udp://d:1".to_string()).await);
+        assert!(torrent.remove_tracker("http://a").await && !torrent.remove_tracker("http://a").await);
+        assert!(torrent.reannounce(Some("udp://d:1")).await && !torrent.reannounce(Some("http://a")).await);
+        let trackers = torrent.trackers().await;
+        assert_eq!(trackers.iter().map(|tracker| (tracker.url.as_str(), tracker.forced)).collect::<Vec<_>>(), vec![("http://c", false), ("udp://d:1", true)]);
+
+        // A later run picks up the edited list instead of the torrent's own
+        assert_eq!(ResumeData::load(&dir.join("a.resume")).unwrap().trackers, vec![vec!["http://c"], vec!["udp://d:1"]]);
+        let mut torrent = Torrent::parse_decoded(&mut File::open(&path).unwrap()).await.unwrap();
+        torrent.resume(dir.join("a.resume")).await;
+        assert_eq!(torrent.trackers().await.iter().map(|tracker| tracker.url.as_str()).collect::<Vec<_>>(), vec!["http://c", "udp://d:1"]);
+
+        // Trackers for this run only are not saved, edited or not, even when another edit is
+        assert!(torrent.add_session_tracker("http://e".to_string(), 2).await);
+        assert!(torrent.edit_tracker("http://e", "http://g".to_string()).await);
+        assert!(torrent.add_tracker("http://f".to_string(), 0).await);
+        assert_eq!(torrent.trackers().await.len(), 4);
+        let mut torrent = Torrent::parse_decoded(&mut File::open(&path).unwrap()).await.unwrap();
+        torrent.resume(dir.join("a.resume")).await;
+        // Tiers are shuffled on load
+        assert_eq!(ResumeData::load(&dir.join("a.resume")).unwrap().trackers.concat().len(), 3);
+        let mut urls: Vec<_> = torrent.trackers().await.into_iter().map(|tracker| (tracker.tier, tracker.url)).collect();
+        urls.sort();
+        assert_eq!(urls, vec![(0, "http://c".to_string()), (0, "http://f".to_string()), (1, "udp://d:1".to_string())]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
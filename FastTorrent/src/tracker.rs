@@ -1,357 +1,1499 @@
-use std::{collections::{VecDeque, HashSet}, sync::Arc};
-use tokio::{sync::Mutex, time::{sleep, self}};
-use crate::helpers::CONN_LIMIT;
-
-mod udp_tracker {
-
-    use tokio::{net::UdpSocket, time::timeout};
-    use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
-    use url::{Url, Host};
-
-    struct Request {
-        connection_id: u64,
-        action: u32,
-        transaction_id: u32,
-        info_hash: [u8; 20],
-        peer_id: [u8; 20],
-        downloaded: u64,
-        left: u64,
-        uploaded: u64,
-        event: u32, // 0: none; 1: completed; 2: started; 3: stopped
-        ip_addr: u32, // 0 default
-        key: u32, // random
-        num_want: i32, //-1 defualt
-        port: u16 // Official spec says port number should be between 6881 and 6889
-    }
-
-    impl Request {
-        fn to_buf(&self) -> Vec<u8> {
-
-            let mut buf = Vec::new();
-
-            buf.write_u64::<BigEndian>(self.connection_id).unwrap(); //Connection id
-            buf.write_u32::<BigEndian>(self.action).unwrap(); // Action
-            buf.write_u32::<BigEndian>(self.transaction_id).unwrap(); // Transaction id
-            for byte in self.info_hash { buf.write_u8(byte).unwrap(); } // Info hash
-            for byte in self.peer_id { buf.write_u8(byte).unwrap(); } // Peer id
-            buf.write_u64::<BigEndian>(self.downloaded).unwrap(); // downloaded
-            buf.write_u64::<BigEndian>(self.left).unwrap(); // left
-            buf.write_u64::<BigEndian>(self.uploaded).unwrap(); // uploaded
-            buf.write_u32::<BigEndian>(self.event).unwrap(); // event
-            buf.write_u32::<BigEndian>(self.ip_addr).unwrap(); // ip_addr
-            buf.write_u32::<BigEndian>(self.key).unwrap(); // key
-            buf.write_i32::<BigEndian>(self.num_want).unwrap();
-            buf.write_u16::<BigEndian>(self.port).unwrap();
-            
-            buf
-        }
-    }
-
-    struct Response {
-        _action: u32,
-        transaction_id: u32,
-        _interval: u32,
-        _leechers: u32,
-        seeders: u32,
-        peer_list: Vec<(u32,u16)>
-    }
-
-    // Function to build a request for announce
-    fn build_announce_req(conn_id: u64, info_hash: &[u8; 20], length: &u64, peer_id:&[u8;20], downloaded: u64, port: u16) -> (Vec<u8>, u32) {
-
-        let req = Request {
-            connection_id: conn_id,
-            action: 1,
-            transaction_id: rand::random(),
-            info_hash: info_hash.clone(),
-            peer_id: *peer_id,
-            downloaded,
-            left: *length,
-            uploaded: 0,
-            event: 0,
-            ip_addr: 0,
-            key: rand::random(),
-            num_want: -1,
-            port // 6881 - 6889
-        };
-
-        (req.to_buf(), req.transaction_id)
-    }
-
-    // Return Initial Connection request buffer
-    fn build_connection_req() -> (Vec<u8>, u32) {
-
-        let mut buf:Vec<u8> = Vec::new();
-
-        // Connection id
-        buf.write_u64::<BigEndian>(0x41727101980).unwrap();
-
-        // action
-        buf.write_u32::<BigEndian>(0).unwrap();
-
-        // transaction id
-        let transaction_id: u32 = rand::random();
-        buf.write_u32::<BigEndian>(transaction_id).unwrap();
-
-        (buf, transaction_id)
-
-    }
-
-    // Convert Url into connect format
-    fn parse_url(announce_url: String) -> (String, String) {
-
-        let parsed_url = Url::parse(&announce_url).unwrap();
-        let mut remote_addr = String::new();
-        if let Host::Domain(s) = parsed_url.host().unwrap() {
-            remote_addr.push_str(s);
-        }
-        remote_addr.push(':');
-        remote_addr.push_str(parsed_url.port().unwrap().to_string().as_mut());
-
-        // let fin_addr = remote_addr.to_socket_addrs().unwrap().next().unwrap();
-
-        (remote_addr, parsed_url.path().to_owned())
-    }
-
-    // Return action, transaction id, and connection id
-    fn parse_connection_resp(mut buf: &[u8]) -> (u32, u32, u64) {
-        (
-            buf.read_u32::<BigEndian>().unwrap(), //action
-            buf.read_u32::<BigEndian>().unwrap(), //transaction_id
-            buf.read_u64::<BigEndian>().unwrap()  //connection_id
-        )
-    }
-
-    // Parse response of announce request
-    fn parse_announce_resp(mut buf: &[u8]) -> Response {
-
-        let mut parsed = Response { 
-            _action: buf.read_u32::<BigEndian>().unwrap(),
-            transaction_id: buf.read_u32::<BigEndian>().unwrap(), 
-            _interval:buf.read_u32::<BigEndian>().unwrap(),
-            _leechers: buf.read_u32::<BigEndian>().unwrap(), 
-            seeders: buf.read_u32::<BigEndian>().unwrap(),
-            peer_list: Vec::new()
-        };
-
-        for _ in 0..parsed.seeders {
-            let ip = buf.read_u32::<BigEndian>().unwrap();
-            let port = buf.read_u16::<BigEndian>().unwrap();
-            parsed.peer_list.push((ip,port));
-        }
-
-        parsed
-
-    }
-
-    pub async fn peer_list_helper(info_hash: &[u8; 20], length: &u64, peer_id:&[u8;20], announce_url: String, port: u16, downloaded: u64) -> Option<Vec<(u32,u16)>> {
-
-        let (remote_addr, _path) = parse_url(announce_url);
-
-
-        // Connect to remote addr
-        let socket = UdpSocket::bind("0.0.0.0:".to_string() + "0").await.unwrap();
-
-        if let Ok(()) = socket.connect(&remote_addr).await { }
-        else {
-            return None;
-        }
-
-        let mut res:[u8; 16] = [0; 16];
-        let (connect_request, connect_transaction_id) = build_connection_req();
-        
-
-        // Send Connection request
-        if let Ok(_) = socket.send(&connect_request).await { }
-        else {
-            return None;
-        }
-
-        // Recieve intital response
-        if let Ok(bytes_read) = timeout(tokio::time::Duration::from_secs(6),socket.recv(&mut res)).await {
-            
-            if let Ok(_) = bytes_read { }
-            else {
-                return None;
-            }
-
-        }
-        else {
-            return None;
-        }
-
-        // Parse Initial Response
-        let (_, transaction_id, connection_id) = parse_connection_resp(&res);
-
-        if transaction_id != connect_transaction_id {
-            return None;
-        }
-        
-        let mut res = [0; 8192];
-        let (announce_req, announce_transaction_id) = build_announce_req(connection_id, info_hash, length, peer_id, downloaded, port);
-        
-        for t in 0..8 {
-            // Make announce request
-            socket.send(&announce_req).await.unwrap();
-            
-            if let Ok(_) = timeout(tokio::time::Duration::from_secs((2u64.pow(t)) * 15),socket.recv(&mut res)).await {
-                break;
-            }
-        }
-        
-        // Parse Announce Response
-        let resp = parse_announce_resp(&mut res);
-
-        if resp.transaction_id != announce_transaction_id {
-            return None;
-        }
-
-        Some(resp.peer_list)
-
-    }
-
-}
-
-mod http_tracker {
-
-    use byteorder::{BigEndian, ReadBytesExt};
-    use crate::bencoded_parser::Element;
-
-    // use std::str;
-    use crate::{
-        helpers::u8_to_url,
-        bencoded_parser::Bencode
-    };
-
-    fn url_parser(info_hash: [u8; 20], peer_id:[u8;20], announce_url: String, port: u16, uploaded: u64, downloaded: u64, left: u64, compact: bool, event: &str, numwant: Option<u64>) -> String {
-        let mut ret = announce_url + "?" +
-            "info_hash=" + &u8_to_url(info_hash.to_owned()) + 
-            "&peer_id=" + &u8_to_url(peer_id.to_owned()) + 
-            "&port=" + &port.to_string() +
-            "&uploaded=" + &uploaded.to_string() +
-            "&downloaded=" + &downloaded.to_string() +
-            "&left=" + &left.to_string() +
-            "&compact=" + if compact {"1"} else {"0"} +
-            "&event=" + event;
-        if numwant != None {
-            ret.push_str(&("&numwant=".to_owned()+&numwant.unwrap().to_string()));
-        }
-        ret
-    }
-
-    pub async fn peer_list_helper(info_hash: &[u8; 20], length: &u64, peer_id:&[u8;20], announce_url: String, port: u16, downloaded: u64) -> Vec<(u32,u16)> {
-        
-        let request = url_parser(info_hash.to_owned(), peer_id.to_owned(), announce_url, port, 0, downloaded, length.to_owned() - downloaded, true, "started", Some(50));
-        
-        let res = reqwest::get(request)
-                        .await
-                        .unwrap()
-                        .bytes()
-                        .await
-                        .unwrap()
-                        .to_vec();
-
-        let decoded = Bencode::decode_u8(res).unwrap();
-
-        let mut ret = Vec::new();
-        let mut peers = Vec::new();
-
-        match decoded {
-            Element::Dict(d) => {
-                if d.contains_key("peers".as_bytes()) {
-                    match &d["peers".as_bytes()] {
-                        Element::ByteString(s) => { peers = s.to_owned(); }
-                        _ => {}
-                    }
-                }
-            }
-            _ => {}
-        }
-
-        for i in (0..peers.len()).step_by(6) {
-            let ip = (&(peers.as_slice())[i..(i+4)]).read_u32::<BigEndian>().unwrap();
-            let po = (&(peers.as_slice())[(i+4)..(i+6)]).read_u16::<BigEndian>().unwrap();
-            ret.push((ip,po));
-        }
-
-        ret
-
-    }
-}
-
-async fn peer_list_helper(info_hash: &[u8; 20], length: &u64, peer_id:&[u8;20], announce_url: String, port: u16, tor_ref: Arc<Mutex<VecDeque<(u32,u16)>>>, downloaded: Arc<Mutex<u64>>) {
-
-    let mut res = None;
-    let download = *downloaded.lock().await;
-
-    if announce_url[0..=5].as_bytes() == "udp://".as_bytes() {
-        res = udp_tracker::peer_list_helper(info_hash, length, peer_id, announce_url, port, download).await;
-    }
-    else if announce_url[0..4].as_bytes() == "http".as_bytes() {
-        res = Some(http_tracker::peer_list_helper(info_hash, length, peer_id, announce_url, port, download).await);
-    }
-
-    if let Some(peers) = res {
-        
-        let mut tor = tor_ref.lock().await;
-        for peer in peers {
-            (*tor).push_back(peer);
-        }
-
-    }
-}
-
-// Function to get peer list
-pub async fn get_peers(info_hash: [u8; 20], length: u64, peer_id: [u8;20], announce_url: Option<String>, peer_list: Arc<Mutex<VecDeque<(u32, u16)>>>, announce_list: Option<Vec<String>>, connections: Arc<Mutex<HashSet<(u32,u16)>>>, downloaded: Arc<Mutex<u64>>, piece_left: Arc<Mutex<u16>>) {
-
-    loop {
-        if *(piece_left.lock().await) == 0 {
-            break;
-        }
-
-        while (*(connections.lock().await)).len() as u32 >= CONN_LIMIT || !peer_list.lock().await.is_empty() {}
-
-        // Create udp socket
-        let mut port: u16 = 6881;
-        let mut handles = vec![];
-
-        // Check for announce_url and announce_list
-        if let Some(announce_url) = announce_url.clone() {
-            
-            let tor_ref = peer_list.clone();
-            let downloaded = downloaded.clone();
-
-            let h = tokio::spawn(async move{
-                peer_list_helper(&info_hash, &length, &peer_id, announce_url, port, tor_ref, downloaded).await;
-            });   
-
-            handles.push(h);
-            port += 1;
-            
-        }
-
-        if let Some(announce_list) = announce_list.clone() {
-
-            for announce_url in announce_list {
-                
-                let tor_ref = peer_list.clone();
-                let downloaded = downloaded.clone();
-
-                let h = tokio::spawn(async move{
-                    peer_list_helper(&info_hash, &length, &peer_id, announce_url, port, tor_ref, downloaded).await;
-                });   
-
-                handles.push(h);
-                port += 1;
-                
-            }
-            
-        }
-        
-        for handle in handles {
-            handle.await.unwrap();
-        }
-
-        sleep(time::Duration::from_secs(5)).await;
-    }
-}
\ No newline at end of file
+use std::{collections::HashMap, fmt, fs, io, net::{IpAddr, SocketAddr}, sync::Arc, time::{Duration, Instant}};
+use rand::{seq::SliceRandom, Rng};
+use reqwest::{Certificate, Client};
+use tokio::{sync::Mutex, time::{timeout, self}};
+use crate::{
+    config::Config,
+    helpers::CONN_LIMIT,
+    peer::{PeerList, PeerSource},
+    torrent_parser::Torrent
+};
+
+// Seconds between announces when the tracker does not say
+static DEFAULT_INTERVAL: u64 = 30 * 60;
+// Seconds before we ask a tracker again for more peers, if it has no min interval
+static DEFAULT_MIN_INTERVAL: u64 = 5 * 60;
+
+// Wait after a failed announce in seconds, doubled for each failure in a row
+static RETRY_MIN: u64 = 15;
+static RETRY_MAX: u64 = 60 * 60;
+
+// Longest we wait after completion for every tracker which knows us to hear about it
+static COMPLETED_WAIT: Duration = Duration::from_secs(60);
+
+// Limits for one HTTP tracker request
+static HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+static CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Announce event, None for regular announces
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    None,
+    Started,
+    Completed,
+    Stopped,
+    // Partial seed (BEP 21), only HTTP trackers know it
+    Paused
+}
+
+impl Event {
+
+    // Value of the HTTP event parameter, left out for regular announces
+    fn name(&self) -> Option<&'static str> {
+        match self {
+            Event::None => None,
+            Event::Started => Some("started"),
+            Event::Completed => Some("completed"),
+            Event::Stopped => Some("stopped"),
+            Event::Paused => Some("paused")
+        }
+    }
+
+    // Event field of a UDP announce
+    fn udp_code(&self) -> u32 {
+        match self {
+            Event::None | Event::Paused => 0,
+            Event::Completed => 1,
+            Event::Started => 2,
+            Event::Stopped => 3
+        }
+    }
+}
+
+// What we tell a tracker in an announce
+#[derive(Clone, Debug)]
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Event,
+    // Echoed back to the HTTP tracker which gave it to us
+    pub tracker_id: Option<String>,
+    // Random number proving to a tracker that announces from a new address are still us
+    pub key: u32,
+    // Address the tracker should hand out instead of the one it sees
+    pub ip: Option<IpAddr>
+}
+
+impl Announce {
+
+    // Announce with our current totals, data found on disk at startup does not count as downloaded
+    pub async fn new(torrent: &Torrent, event: Event) -> Announce {
+
+        let left = torrent.piece_freq.lock().await.iter().filter(|piece| !piece.completed).map(|piece| piece.length).sum();
+        Announce {
+            info_hash: torrent.info_hash,
+            peer_id: torrent.peer_id,
+            port: torrent.listen_port,
+            uploaded: *(torrent.uploaded.lock().await),
+            downloaded: (*(torrent.downloaded.lock().await)).saturating_sub(torrent.existing),
+            left,
+            event,
+            tracker_id: None,
+            key: 0,
+            ip: None
+        }
+    }
+}
+
+// What a tracker answered to an announce
+#[derive(Debug, Default, PartialEq)]
+pub struct Response {
+    pub peers: Vec<SocketAddr>,
+    pub interval: Option<u64>,
+    pub min_interval: Option<u64>,
+    pub tracker_id: Option<String>,
+    pub warning: Option<String>,
+    // Swarm size as the tracker counts it, when it says
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TrackerError {
+    // Tracker refused the announce and said why
+    Failure(String),
+    // No usable answer
+    Unreachable,
+    // HTTP status other than success, without a failure reason
+    Http(u16),
+    // Reply we could not make sense of
+    Malformed,
+    // The announce task died before it got an answer
+    Panicked,
+    // Announce url has no announce segment to turn into a scrape url
+    NoScrape
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackerError::Failure(reason) => write!(f, "{}", reason),
+            TrackerError::Unreachable => write!(f, "no answer"),
+            TrackerError::Http(status) => write!(f, "HTTP status {}", status),
+            TrackerError::Malformed => write!(f, "malformed reply"),
+            TrackerError::Panicked => write!(f, "announce panicked"),
+            TrackerError::NoScrape => write!(f, "scrape not supported")
+        }
+    }
+}
+
+// Swarm counts a tracker keeps for a torrent
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    // Peers which finished the download since the tracker started counting
+    pub completed: u32,
+    pub leechers: u32
+}
+
+// Announce state of one tracker
+#[derive(Clone, Debug)]
+pub struct Tracker {
+    pub url: String,
+    // Position of the tier in the announce list, earlier tiers are preferred
+    pub tier: usize,
+    // Tracker accepted our started event and has not been told we stopped
+    pub started: bool,
+    // Completed event was reported
+    pub completed: bool,
+    // Paused event was reported, we hold every piece we want (BEP 21)
+    pub paused: bool,
+    // Seconds between regular announces, and the least it lets us wait when asking for more peers
+    pub interval: u64,
+    pub min_interval: u64,
+    pub next_announce: Instant,
+    // Last successful announce, and the last one whatever its result
+    pub last_announce: Option<Instant>,
+    pub last_attempt: Option<Instant>,
+    // Peers and swarm counts from the last successful announce
+    pub peers: usize,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    // Failed announces in a row
+    pub failures: u32,
+    pub tracker_id: Option<String>,
+    pub key: u32,
+    // Peer id of our first announce, kept so the tracker always sees the same client
+    pub peer_id: Option<[u8; 20]>,
+    // Last failure and warning, shown to the user
+    pub error: Option<String>,
+    pub warning: Option<String>,
+    // An announce is on its way
+    pub updating: bool,
+    // Announce asked for outside the schedule
+    pub forced: bool,
+    // Added for this run only, left out of the resume data
+    pub session: bool
+}
+
+impl Tracker {
+
+    pub fn new(url: String, tier: usize) -> Tracker {
+        Tracker {
+            url,
+            tier,
+            started: false,
+            completed: false,
+            paused: false,
+            interval: DEFAULT_INTERVAL,
+            min_interval: DEFAULT_MIN_INTERVAL,
+            next_announce: Instant::now(),
+            last_announce: None,
+            last_attempt: None,
+            peers: 0,
+            seeders: None,
+            leechers: None,
+            failures: 0,
+            tracker_id: None,
+            key: rand::random(),
+            peer_id: None,
+            error: None,
+            warning: None,
+            updating: false,
+            forced: false,
+            session: false
+        }
+    }
+
+    // Tracker id, key and peer id to announce with, the same for the life of the tracker
+    pub fn identity(&mut self, peer_id: [u8; 20]) -> (Option<String>, u32, [u8; 20]) {
+        (self.tracker_id.clone(), self.key, *self.peer_id.get_or_insert(peer_id))
+    }
+
+    // Trackers of every tier in order, shuffled within their tier as BEP 12 asks
+    pub fn from_tiers(tiers: Vec<Vec<String>>) -> Vec<Tracker> {
+        let mut rng = rand::thread_rng();
+        tiers.into_iter().enumerate().flat_map(|(tier, mut urls)| {
+            urls.shuffle(&mut rng);
+            urls.into_iter().map(move |url| Tracker::new(url, tier))
+        }).collect()
+    }
+
+    // One word summary of the last announce
+    pub fn status(&self) -> &'static str {
+        match (self.updating, &self.error, self.last_attempt) {
+            (true, _, _) => "updating",
+            (_, Some(_), _) => "failed",
+            (_, None, Some(_)) => "working",
+            (_, None, None) => "waiting"
+        }
+    }
+
+    // Time for a regular announce, or an early one when we are short of peers
+    fn due(&self, now: Instant, short_of_peers: bool) -> bool {
+        if self.updating {
+            return false;
+        }
+        let early = short_of_peers && self.failures == 0
+            && self.last_announce.is_some_and(|last| now >= last + Duration::from_secs(self.min_interval));
+        now >= self.next_announce || early
+    }
+
+    // Take in the result of an announce and schedule the next one
+    fn update(&mut self, res: &Result<Response, TrackerError>, event: Event) {
+
+        let now = Instant::now();
+        self.updating = false;
+        self.last_attempt = Some(now);
+        match res {
+            Ok(res) => {
+                match event {
+                    Event::Started => self.started = true,
+                    Event::Completed => self.completed = true,
+                    Event::Paused => self.paused = true,
+                    Event::Stopped => self.started = false,
+                    _ => {}
+                }
+                self.interval = res.interval.unwrap_or(DEFAULT_INTERVAL);
+                self.min_interval = res.min_interval.unwrap_or(DEFAULT_MIN_INTERVAL).min(self.interval);
+                if res.tracker_id.is_some() {
+                    self.tracker_id = res.tracker_id.clone();
+                }
+                self.warning = res.warning.clone();
+                self.peers = res.peers.len();
+                self.seeders = res.seeders.or(self.seeders);
+                self.leechers = res.leechers.or(self.leechers);
+                self.error = None;
+                self.failures = 0;
+                self.last_announce = Some(now);
+                self.next_announce = now + Duration::from_secs(self.interval);
+            },
+            Err(err) => {
+                self.error = Some(err.to_string());
+                self.next_announce = now + retry_delay(self.failures);
+                self.failures += 1;
+            }
+        }
+    }
+}
+
+pub type TrackerList = Arc<Mutex<Vec<Tracker>>>;
+
+// Settings every announce and scrape shares, with the HTTP client built from them
+#[derive(Clone, Debug)]
+pub struct TrackerClient {
+    http: Client,
+    // Shared by every UDP tracker, none if no UDP socket could be opened
+    udp: Option<Arc<udp_tracker::UdpClient>>,
+    ip: Option<IpAddr>,
+    all_tiers: bool
+}
+
+impl TrackerClient {
+
+    // Fails when the CA bundle for HTTPS trackers cannot be read. Call from within the runtime
+    pub fn new(config: &Config) -> io::Result<TrackerClient> {
+
+        let mut builder = Client::builder()
+            .user_agent(&config.user_agent)
+            .timeout(HTTP_TIMEOUT)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .gzip(true)
+            .redirect(http_tracker::redirect_policy());
+        if let Some(path) = &config.tracker_ca {
+            for cert in Certificate::from_pem_bundle(&fs::read(path)?).map_err(io::Error::other)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        Ok(TrackerClient {
+            http: builder.build().map_err(io::Error::other)?,
+            udp: udp_tracker::UdpClient::new(udp_tracker::RETRY_BASE).ok().map(Arc::new),
+            ip: config.announce_ip,
+            all_tiers: config.announce_all
+        })
+    }
+}
+
+// Next tracker to announce to out of these, if one is due. The first tracker that works is used,
+// one waiting out a failure is passed over for the next, which may be in a later tier
+fn pick(trackers: &[Tracker], indices: &[usize], now: Instant, short_of_peers: bool) -> Option<usize> {
+    for &i in indices {
+        let tracker = &trackers[i];
+        if tracker.updating {
+            return None;
+        }
+        if tracker.failures == 0 || now >= tracker.next_announce {
+            return Some(i).filter(|_| tracker.due(now, short_of_peers));
+        }
+    }
+    None
+}
+
+// Trackers due for an announce, one for the torrent or one from each tier when announcing to all
+fn due_trackers(trackers: &[Tracker], now: Instant, short_of_peers: bool, all_tiers: bool) -> Vec<usize> {
+    let indices: Vec<usize> = (0..trackers.len()).collect();
+    if !all_tiers {
+        return pick(trackers, &indices, now, short_of_peers).into_iter().collect();
+    }
+    indices.chunk_by(|a, b| trackers[*a].tier == trackers[*b].tier)
+        .filter_map(|tier| pick(trackers, tier, now, short_of_peers)).collect()
+}
+
+// Wait after the given number of failures in a row, spread by a quarter either way so clients do not retry together
+fn retry_delay(failures: u32) -> Duration {
+    let wait = RETRY_MIN.saturating_mul(1 << failures.min(16)).min(RETRY_MAX);
+    Duration::from_secs_f64(wait as f64 * rand::thread_rng().gen_range(0.75..=1.25))
+}
+
+mod udp_tracker {
+
+    use std::{
+        collections::HashMap,
+        fmt, io,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        sync::{Arc, Mutex},
+        time::{Duration, Instant}
+    };
+    use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
+    use socket2::{Domain, Socket, Type};
+    use tokio::{net::{lookup_host, UdpSocket}, sync::{mpsc, oneshot}, task::JoinHandle, time::timeout};
+    use url::{Host, Url};
+    use crate::helpers;
+    use super::{Announce, Response as AnnounceResponse, ScrapeStats, TrackerError};
+
+    static PROTOCOL_ID: u64 = 0x41727101980;
+    static CONNECT: u32 = 0;
+    static ANNOUNCE: u32 = 1;
+    static SCRAPE: u32 = 2;
+    static ERROR: u32 = 3;
+
+    // A connection id may be used for a minute after the tracker handed it out
+    static CONNECTION_LIFETIME: Duration = Duration::from_secs(60);
+
+    // Attempt n waits 15 * 2^n seconds for a reply (BEP 15). The spec allows 8 attempts,
+    // after 4 the announce scheduler's backoff takes over
+    pub(super) static RETRY_BASE: Duration = Duration::from_secs(15);
+    static MAX_ATTEMPTS: u32 = 4;
+
+    // Most info hashes a scrape request may carry
+    static SCRAPE_BATCH: usize = 74;
+
+    // BEP 41 option types
+    static OPTION_URL_DATA: u8 = 2;
+
+    #[derive(Debug, PartialEq)]
+    pub enum UdpTrackerError {
+        // Not a udp://host:port url
+        InvalidUrl,
+        // Host name did not resolve to an address we can reach
+        Resolve,
+        Io,
+        // Tracker did not answer any attempt
+        Timeout,
+        // Reply too short or for another action
+        Malformed,
+        // Error reply (action 3) with the tracker's message
+        Tracker(String)
+    }
+
+    impl fmt::Display for UdpTrackerError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                UdpTrackerError::InvalidUrl => write!(f, "invalid tracker url"),
+                UdpTrackerError::Resolve => write!(f, "could not resolve tracker"),
+                UdpTrackerError::Io => write!(f, "socket error"),
+                UdpTrackerError::Timeout => write!(f, "no answer"),
+                UdpTrackerError::Malformed => write!(f, "malformed reply"),
+                UdpTrackerError::Tracker(message) => write!(f, "{}", message)
+            }
+        }
+    }
+
+    impl From<UdpTrackerError> for TrackerError {
+        fn from(err: UdpTrackerError) -> TrackerError {
+            match err {
+                UdpTrackerError::Tracker(message) => TrackerError::Failure(message),
+                UdpTrackerError::Malformed => TrackerError::Malformed,
+                _ => TrackerError::Unreachable
+            }
+        }
+    }
+
+    // Host, port, and the path and query sent along as BEP 41 url data
+    fn parse_url(announce_url: &str) -> Result<(Host<String>, u16, String), UdpTrackerError> {
+
+        let url = Url::parse(announce_url).map_err(|_| UdpTrackerError::InvalidUrl)?;
+        if url.scheme() != "udp" {
+            return Err(UdpTrackerError::InvalidUrl);
+        }
+        let host = url.host().ok_or(UdpTrackerError::InvalidUrl)?.to_owned();
+        let port = url.port().ok_or(UdpTrackerError::InvalidUrl)?;
+
+        let mut path = url.path().to_string();
+        if let Some(query) = url.query() {
+            path = path + "?" + query;
+        }
+        if path == "/" {
+            path.clear();
+        }
+
+        Ok((host, port, path))
+    }
+
+    async fn resolve(host: &Host<String>, port: u16) -> Result<Vec<SocketAddr>, UdpTrackerError> {
+        match host {
+            Host::Domain(domain) => Ok(lookup_host((domain.as_str(), port)).await.map_err(|_| UdpTrackerError::Resolve)?.collect()),
+            Host::Ipv4(ip) => Ok(vec![SocketAddr::from((*ip, port))]),
+            Host::Ipv6(ip) => Ok(vec![SocketAddr::from((*ip, port))])
+        }
+    }
+
+    fn connect_req(transaction_id: u32) -> Vec<u8> {
+
+        let mut buf = Vec::new();
+        buf.write_u64::<BigEndian>(PROTOCOL_ID).unwrap();
+        buf.write_u32::<BigEndian>(CONNECT).unwrap();
+        buf.write_u32::<BigEndian>(transaction_id).unwrap();
+        buf
+    }
+
+    fn announce_req(connection_id: u64, transaction_id: u32, announce: &Announce, path: &str) -> Vec<u8> {
+
+        let mut buf = Vec::new();
+        buf.write_u64::<BigEndian>(connection_id).unwrap();
+        buf.write_u32::<BigEndian>(ANNOUNCE).unwrap();
+        buf.write_u32::<BigEndian>(transaction_id).unwrap();
+        buf.extend_from_slice(&announce.info_hash);
+        buf.extend_from_slice(&announce.peer_id);
+        buf.write_u64::<BigEndian>(announce.downloaded).unwrap();
+        buf.write_u64::<BigEndian>(announce.left).unwrap();
+        buf.write_u64::<BigEndian>(announce.uploaded).unwrap();
+        buf.write_u32::<BigEndian>(announce.event.udp_code()).unwrap();
+        // Only an IPv4 address fits, 0 for the sender's
+        let ip = match announce.ip {
+            Some(IpAddr::V4(ip)) => u32::from(ip),
+            _ => 0
+        };
+        buf.write_u32::<BigEndian>(ip).unwrap();
+        buf.write_u32::<BigEndian>(announce.key).unwrap();
+        buf.write_i32::<BigEndian>(-1).unwrap(); // num_want, -1 for the tracker's default
+        buf.write_u16::<BigEndian>(announce.port).unwrap();
+
+        // Path and query in chunks of at most 255 bytes (BEP 41)
+        for chunk in path.as_bytes().chunks(255) {
+            buf.push(OPTION_URL_DATA);
+            buf.push(chunk.len() as u8);
+            buf.extend_from_slice(chunk);
+        }
+        buf
+    }
+
+    fn scrape_req(connection_id: u64, transaction_id: u32, info_hashes: &[[u8; 20]]) -> Vec<u8> {
+
+        let mut buf = Vec::new();
+        buf.write_u64::<BigEndian>(connection_id).unwrap();
+        buf.write_u32::<BigEndian>(SCRAPE).unwrap();
+        buf.write_u32::<BigEndian>(transaction_id).unwrap();
+        for info_hash in info_hashes {
+            buf.extend_from_slice(info_hash);
+        }
+        buf
+    }
+
+    // Body of a reply after action and transaction id, an error reply carries the tracker's message
+    fn parse_reply(mut buf: &[u8], action: u32) -> Result<&[u8], UdpTrackerError> {
+
+        let reply_action = buf.read_u32::<BigEndian>().map_err(|_| UdpTrackerError::Malformed)?;
+        buf.read_u32::<BigEndian>().map_err(|_| UdpTrackerError::Malformed)?;
+
+        if reply_action == ERROR {
+            return Err(UdpTrackerError::Tracker(String::from_utf8_lossy(buf).into_owned()));
+        }
+        if reply_action != action {
+            return Err(UdpTrackerError::Malformed);
+        }
+        Ok(buf)
+    }
+
+    // Announce reply, peers are 6 bytes over IPv4 and 18 over IPv6 and fill the rest of the datagram
+    fn parse_announce(mut body: &[u8], ipv6: bool) -> Result<AnnounceResponse, UdpTrackerError> {
+
+        let interval = body.read_u32::<BigEndian>().map_err(|_| UdpTrackerError::Malformed)?;
+        let leechers = body.read_u32::<BigEndian>().map_err(|_| UdpTrackerError::Malformed)?;
+        let seeders = body.read_u32::<BigEndian>().map_err(|_| UdpTrackerError::Malformed)?;
+
+        let size = if ipv6 { 18 } else { 6 };
+        let peers = body.chunks_exact(size).map(|mut peer| {
+            let ip = if ipv6 {
+                Ipv6Addr::from(peer.read_u128::<BigEndian>().unwrap()).into()
+            }
+            else {
+                Ipv4Addr::from(peer.read_u32::<BigEndian>().unwrap()).into()
+            };
+            SocketAddr::new(ip, peer.read_u16::<BigEndian>().unwrap())
+        }).collect();
+
+        Ok(AnnounceResponse {
+            peers,
+            interval: Some(interval as u64).filter(|interval| *interval > 0),
+            seeders: Some(seeders),
+            leechers: Some(leechers),
+            ..Default::default()
+        })
+    }
+
+    // Counts for each info hash of the request, in the same order
+    fn parse_scrape(mut body: &[u8], count: usize) -> Result<Vec<ScrapeStats>, UdpTrackerError> {
+
+        let mut ret = Vec::new();
+        for _ in 0..count {
+            ret.push(ScrapeStats {
+                seeders: body.read_u32::<BigEndian>().map_err(|_| UdpTrackerError::Malformed)?,
+                completed: body.read_u32::<BigEndian>().map_err(|_| UdpTrackerError::Malformed)?,
+                leechers: body.read_u32::<BigEndian>().map_err(|_| UdpTrackerError::Malformed)?
+            });
+        }
+        Ok(ret)
+    }
+
+    type Pending = Mutex<HashMap<u32, (SocketAddr, oneshot::Sender<Vec<u8>>)>>;
+
+    // One socket per address family for all UDP trackers, replies find their request by transaction id
+    pub struct UdpClient {
+        v4: Option<Arc<UdpSocket>>,
+        v6: Option<Arc<UdpSocket>>,
+        pending: Arc<Pending>,
+        // Connection id and when we got it, for each tracker address
+        connections: Mutex<HashMap<SocketAddr, (u64, Instant)>>,
+        retry_base: Duration,
+        receivers: Vec<JoinHandle<()>>
+    }
+
+    impl fmt::Debug for UdpClient {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("UdpClient").field("v4", &self.v4).field("v6", &self.v6).finish()
+        }
+    }
+
+    impl Drop for UdpClient {
+        fn drop(&mut self) {
+            for receiver in &self.receivers {
+                receiver.abort();
+            }
+        }
+    }
+
+    impl UdpClient {
+
+        // Must be called from within a runtime, the receivers are tasks on it
+        pub fn new(retry_base: Duration) -> io::Result<UdpClient> {
+
+            let bind = |socket: Socket, addr: SocketAddr| {
+                socket.set_nonblocking(true)?;
+                socket.bind(&addr.into())?;
+                UdpSocket::from_std(socket.into()).map(Arc::new)
+            };
+            let v4 = Socket::new(Domain::IPV4, Type::DGRAM, None).and_then(|socket| bind(socket, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))));
+            let v6 = Socket::new(Domain::IPV6, Type::DGRAM, None).and_then(|socket| {
+                socket.set_only_v6(true)?;
+                bind(socket, SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))
+            });
+            if let (Err(err), Err(_)) = (&v4, &v6) {
+                return Err(io::Error::new(err.kind(), err.to_string()));
+            }
+
+            let mut client = UdpClient {
+                v4: v4.ok(),
+                v6: v6.ok(),
+                pending: Arc::new(Mutex::new(HashMap::new())),
+                connections: Mutex::new(HashMap::new()),
+                retry_base,
+                receivers: Vec::new()
+            };
+            let receivers = client.v4.iter().chain(client.v6.iter())
+                .map(|socket| tokio::spawn(UdpClient::receive(socket.clone(), client.pending.clone())))
+                .collect();
+            client.receivers = receivers;
+
+            Ok(client)
+        }
+
+        // Hand each reply to the request waiting on its transaction id, until the client is dropped
+        async fn receive(socket: Arc<UdpSocket>, pending: Arc<Pending>) {
+
+            let mut buf = vec![0; 65536];
+            loop {
+                let (len, from) = match socket.recv_from(&mut buf).await {
+                    Ok((len, from)) if len >= 8 => (len, helpers::canonical_addr(from)),
+                    _ => continue
+                };
+
+                let transaction_id = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+                let mut pending = pending.lock().unwrap();
+                if pending.get(&transaction_id).is_some_and(|(addr, _)| *addr == from) {
+                    let (_, tx) = pending.remove(&transaction_id).unwrap();
+                    let _ = tx.send(buf[..len].to_vec());
+                }
+            }
+        }
+
+        fn socket(&self, to: SocketAddr) -> Option<&Arc<UdpSocket>> {
+            if to.is_ipv4() { self.v4.as_ref() } else { self.v6.as_ref() }
+        }
+
+        // Send one packet and wait for its reply, None on timeout
+        async fn exchange(&self, to: SocketAddr, packet: impl Fn(u32) -> Vec<u8>, wait: Duration) -> Result<Option<Vec<u8>>, UdpTrackerError> {
+
+            let socket = self.socket(to).ok_or(UdpTrackerError::Io)?;
+            let (tx, rx) = oneshot::channel();
+            let transaction_id = {
+                let mut pending = self.pending.lock().unwrap();
+                let mut transaction_id = rand::random();
+                while pending.contains_key(&transaction_id) {
+                    transaction_id = rand::random();
+                }
+                pending.insert(transaction_id, (to, tx));
+                transaction_id
+            };
+
+            let res = match socket.send_to(&packet(transaction_id), to).await {
+                Ok(_) => timeout(wait, rx).await.ok().and_then(Result::ok),
+                Err(_) => {
+                    self.pending.lock().unwrap().remove(&transaction_id);
+                    return Err(UdpTrackerError::Io);
+                }
+            };
+            self.pending.lock().unwrap().remove(&transaction_id);
+            Ok(res)
+        }
+
+        // Cached connection id while it is fresh, otherwise a new one from the tracker
+        async fn connection_id(&self, to: SocketAddr, wait: Duration) -> Result<Option<u64>, UdpTrackerError> {
+
+            if let Some((id, at)) = self.connections.lock().unwrap().get(&to) {
+                if at.elapsed() < CONNECTION_LIFETIME {
+                    return Ok(Some(*id));
+                }
+            }
+
+            let reply = match self.exchange(to, connect_req, wait).await? {
+                Some(reply) => reply,
+                None => return Ok(None)
+            };
+            let id = parse_reply(&reply, CONNECT)?.read_u64::<BigEndian>().map_err(|_| UdpTrackerError::Malformed)?;
+            self.connections.lock().unwrap().insert(to, (id, Instant::now()));
+            Ok(Some(id))
+        }
+
+        // Make a request, connecting again when needed, waiting twice as long after each silent attempt
+        async fn request(&self, to: SocketAddr, action: u32, packet: impl Fn(u64, u32) -> Vec<u8>) -> Result<Vec<u8>, UdpTrackerError> {
+
+            for n in 0..MAX_ATTEMPTS {
+                let wait = self.retry_base * 2u32.pow(n);
+                let connection_id = match self.connection_id(to, wait).await? {
+                    Some(id) => id,
+                    None => continue
+                };
+
+                if let Some(reply) = self.exchange(to, |transaction_id| packet(connection_id, transaction_id), wait).await? {
+                    let res = parse_reply(&reply, action).map(<[u8]>::to_vec);
+                    // The tracker may have refused an expired connection id
+                    if res.is_err() {
+                        self.connections.lock().unwrap().remove(&to);
+                    }
+                    return res;
+                }
+            }
+            Err(UdpTrackerError::Timeout)
+        }
+
+        // Announce over each address family the tracker has, so it learns both our addresses
+        pub async fn announce(self: &Arc<Self>, announce_url: &str, announce: &Announce) -> Result<AnnounceResponse, UdpTrackerError> {
+            let (host, port, path) = parse_url(announce_url)?;
+            let addrs = resolve(&host, port).await?;
+            self.announce_addrs(&addrs, path, announce).await
+        }
+
+        // The first family to answer gives the reply. The other one may be silently dropped on the way,
+        // so it goes on in the background rather than holding the reply through its retries
+        async fn announce_addrs(self: &Arc<Self>, addrs: &[SocketAddr], path: String, announce: &Announce) -> Result<AnnounceResponse, UdpTrackerError> {
+
+            let v4 = addrs.iter().find(|addr| addr.is_ipv4() && self.v4.is_some());
+            let v6 = addrs.iter().find(|addr| addr.is_ipv6() && self.v6.is_some());
+
+            let (tx, mut rx) = mpsc::channel(2);
+            for addr in v4.into_iter().chain(v6).copied() {
+                let (client, tx, path, announce) = (self.clone(), tx.clone(), path.clone(), announce.clone());
+                tokio::spawn(async move {
+                    let body = client.request(addr, ANNOUNCE, |connection_id, transaction_id| announce_req(connection_id, transaction_id, &announce, &path)).await;
+                    let _ = tx.send(body.and_then(|body| parse_announce(&body, addr.is_ipv6()))).await;
+                });
+            }
+            drop(tx);
+
+            let mut error = UdpTrackerError::Resolve;
+            while let Some(res) = rx.recv().await {
+                match res {
+                    Ok(res) => return Ok(res),
+                    Err(err) => error = err
+                }
+            }
+            Err(error)
+        }
+
+        // Scrape in batches, the counts come back in the order of info_hashes
+        pub async fn scrape(&self, announce_url: &str, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, UdpTrackerError> {
+
+            let (host, port, _) = parse_url(announce_url)?;
+            let to = resolve(&host, port).await?.into_iter().find(|addr| self.socket(*addr).is_some()).ok_or(UdpTrackerError::Resolve)?;
+
+            let mut ret = Vec::new();
+            for batch in info_hashes.chunks(SCRAPE_BATCH) {
+                let body = self.request(to, SCRAPE, |connection_id, transaction_id| scrape_req(connection_id, transaction_id, batch)).await?;
+                ret.extend(parse_scrape(&body, batch.len())?);
+            }
+            Ok(ret)
+        }
+    }
+
+    fn client(client: Option<&Arc<UdpClient>>) -> Result<&Arc<UdpClient>, UdpTrackerError> {
+        client.ok_or(UdpTrackerError::Io)
+    }
+
+    pub async fn peer_list_helper(udp: Option<&Arc<UdpClient>>, announce_url: String, announce: &Announce) -> Result<AnnounceResponse, TrackerError> {
+        Ok(client(udp)?.announce(&announce_url, announce).await?)
+    }
+
+    pub async fn scrape(udp: Option<&Arc<UdpClient>>, announce_url: String, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        Ok(client(udp)?.scrape(&announce_url, info_hashes).await?)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{net::SocketAddr, sync::{atomic::{AtomicU32, Ordering}, Arc}, time::Duration};
+        use byteorder::{BigEndian, ReadBytesExt};
+        use tokio::{net::UdpSocket, time::timeout};
+        use url::Host;
+        use crate::tracker::{Announce, Event, ScrapeStats};
+        use super::{parse_announce, parse_url, UdpClient, UdpTrackerError};
+
+        // Tracker which drops the first announce, refuses info hash [9; 20] and counts connects
+        async fn mock_tracker(connects: Arc<AtomicU32>) -> String {
+
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+
+            tokio::spawn(async move {
+                let mut buf = [0; 2048];
+                let mut dropped = false;
+                loop {
+                    let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                    let mut req = &buf[..len];
+                    let connection_id = req.read_u64::<BigEndian>().unwrap();
+                    let action = req.read_u32::<BigEndian>().unwrap();
+                    let transaction = &buf[12..16];
+
+                    let mut res = Vec::new();
+                    match action {
+                        0 => {
+                            connects.fetch_add(1, Ordering::SeqCst);
+                            res.extend([0, 0, 0, 0]);
+                            res.extend(transaction);
+                            res.extend(77u64.to_be_bytes());
+                        },
+                        _ if connection_id != 77 => continue,
+                        1 if buf[16..36] == [9; 20] => {
+                            res.extend([0, 0, 0, 3]);
+                            res.extend(transaction);
+                            res.extend(b"banned");
+                        },
+                        1 => {
+                            if !dropped {
+                                dropped = true;
+                                continue;
+                            }
+                            // Seeders says 50, only the peers in the datagram count. BEP 41 path echoed as a peer port
+                            let path_len = buf.get(99).copied().unwrap_or(0) as u16;
+                            res.extend([0, 0, 0, 1]);
+                            res.extend(transaction);
+                            res.extend([0, 0, 7, 8, 0, 0, 0, 1, 0, 0, 0, 50]);
+                            res.extend([10, 0, 0, 1, 0x1A, 0xE1, 10, 0, 0, 2]);
+                            res.extend(path_len.to_be_bytes());
+                        },
+                        2 => {
+                            res.extend([0, 0, 0, 2]);
+                            res.extend(transaction);
+                            for _ in 0..(len - 16) / 20 {
+                                res.extend([0, 0, 0, 5, 0, 0, 0, 9, 0, 0, 0, 3]);
+                            }
+                        },
+                        _ => continue
+                    }
+                    socket.send_to(&res, from).await.unwrap();
+                }
+            });
+
+            format!("udp://{}", addr)
+        }
+
+        #[test]
+        fn parse_url_test() {
+
+            assert_eq!(parse_url("udp://t.example:80/announce?k=1"), Ok((Host::Domain("t.example".to_string()), 80, "/announce?k=1".to_string())));
+            assert_eq!(parse_url("udp://[::1]:6969").map(|url| url.2), Ok(String::new()));
+            assert!(matches!(parse_url("udp://[::1]:6969"), Ok((Host::Ipv6(_), 6969, _))));
+            assert_eq!(parse_url("udp://t.example/announce"), Err(UdpTrackerError::InvalidUrl));
+            assert_eq!(parse_url("http://t.example:80/"), Err(UdpTrackerError::InvalidUrl));
+        }
+
+        #[test]
+        fn parse_announce_test() {
+
+            let body = [0, 0, 0, 60, 0, 0, 0, 1, 0, 0, 0, 9, 1, 2, 3, 4, 0, 80, 5];
+            let res = parse_announce(&body, false).unwrap();
+            assert_eq!((res.interval, res.peers), (Some(60), vec!["1.2.3.4:80".parse().unwrap()]));
+            assert_eq!((res.leechers, res.seeders), (Some(1), Some(9)));
+            assert_eq!(parse_announce(&body[..8], false), Err(UdpTrackerError::Malformed));
+        }
+
+        #[tokio::test]
+        async fn mock_tracker_test() {
+
+            let connects = Arc::new(AtomicU32::new(0));
+            let url = mock_tracker(connects.clone()).await;
+            let client = Arc::new(UdpClient::new(Duration::from_millis(100)).unwrap());
+
+            // First announce is resent after the dropped one, connection id is reused
+            let mut announce = Announce { info_hash: [1; 20], peer_id: [2; 20], port: 6881, uploaded: 0, downloaded: 0, left: 10, event: Event::Started, tracker_id: None, key: 1, ip: None };
+            let res = client.announce(&(url.clone() + "/announce"), &announce).await.unwrap();
+            assert_eq!(res.interval, Some(0x708));
+            assert_eq!(res.peers, vec!["10.0.0.1:6881".parse().unwrap(), "10.0.0.2:9".parse().unwrap()]);
+            assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+            let stats = client.scrape(&url, &[[1; 20], [2; 20]]).await.unwrap();
+            assert_eq!(stats, vec![ScrapeStats { seeders: 5, completed: 9, leechers: 3 }; 2]);
+            assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+            announce.info_hash = [9; 20];
+            assert_eq!(client.announce(&url, &announce).await, Err(UdpTrackerError::Tracker("banned".to_string())));
+
+            // Nothing listening
+            let closed = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+            assert_eq!(client.scrape(&format!("udp://{}", closed), &[[1; 20]]).await, Err(UdpTrackerError::Timeout));
+        }
+
+        #[tokio::test]
+        async fn silent_family_test() {
+
+            // Needs IPv6 on the loopback
+            let silent = match UdpSocket::bind("[::1]:0").await {
+                Ok(socket) => socket,
+                Err(_) => return
+            };
+            let client = Arc::new(UdpClient::new(Duration::from_secs(1)).unwrap());
+            if client.v6.is_none() {
+                return;
+            }
+
+            // IPv4 answers after one resend, the IPv6 address never does and would take 15 seconds to give up on
+            let url = mock_tracker(Arc::new(AtomicU32::new(0))).await;
+            let addrs: Vec<SocketAddr> = vec![url["udp://".len()..].parse().unwrap(), silent.local_addr().unwrap()];
+            let announce = Announce { info_hash: [1; 20], peer_id: [2; 20], port: 6881, uploaded: 0, downloaded: 0, left: 10, event: Event::Started, tracker_id: None, key: 1, ip: None };
+            let res = timeout(Duration::from_secs(5), client.announce_addrs(&addrs, String::new(), &announce)).await.unwrap().unwrap();
+            assert_eq!(res.peers.len(), 2);
+        }
+    }
+
+}
+
+mod http_tracker {
+
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use byteorder::{BigEndian, ReadBytesExt};
+    use reqwest::{redirect::Policy, Client};
+    use url::form_urlencoded;
+    use crate::bencoded_parser::Element;
+
+    // use std::str;
+    use crate::{
+        helpers::u8_to_url,
+        bencoded_parser::Bencode
+    };
+    use super::{Announce, Response, ScrapeStats, TrackerError};
+
+    // Info hashes per scrape request, keeps the url a sane length
+    static SCRAPE_BATCH: usize = 50;
+
+    static MAX_REDIRECTS: usize = 5;
+
+    // Follow a few redirects, never from https down to http
+    pub(super) fn redirect_policy() -> Policy {
+        Policy::custom(|attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            }
+            else if attempt.url().scheme() == "http" && attempt.previous().iter().any(|url| url.scheme() == "https") {
+                attempt.stop()
+            }
+            else {
+                attempt.follow()
+            }
+        })
+    }
+
+    // Private trackers put a passkey in the query, our parameters go after it
+    pub(super) fn url_parser(announce_url: String, announce: &Announce, compact: bool, numwant: Option<u64>) -> String {
+        let separator = if announce_url.contains('?') { "&" } else { "?" };
+        let mut ret = announce_url + separator +
+            "info_hash=" + &u8_to_url(announce.info_hash) + 
+            "&peer_id=" + &u8_to_url(announce.peer_id) + 
+            "&port=" + &announce.port.to_string() +
+            "&uploaded=" + &announce.uploaded.to_string() +
+            "&downloaded=" + &announce.downloaded.to_string() +
+            "&left=" + &announce.left.to_string() +
+            "&compact=" + if compact {"1"} else {"0"} +
+            "&no_peer_id=1" +
+            "&key=" + &format!("{:08X}", announce.key);
+        if let Some(ip) = announce.ip {
+            ret.push_str(&("&ip=".to_owned()+&form_urlencoded::byte_serialize(ip.to_string().as_bytes()).collect::<String>()));
+        }
+        if let Some(event) = announce.event.name() {
+            ret.push_str(&("&event=".to_owned()+event));
+        }
+        if let Some(numwant) = numwant {
+            ret.push_str(&("&numwant=".to_owned()+&numwant.to_string()));
+        }
+        if let Some(tracker_id) = &announce.tracker_id {
+            ret.push_str(&("&trackerid=".to_owned()+&form_urlencoded::byte_serialize(tracker_id.as_bytes()).collect::<String>()));
+        }
+        ret
+    }
+
+    // Read an announce reply, a failure reason means the tracker turned us down
+    pub(super) fn parse_response(decoded: &Element) -> Result<Response, TrackerError> {
+
+        let text = |key| decoded.get(key).and_then(Element::as_bytes).map(|val| String::from_utf8_lossy(val).into_owned());
+        let seconds = |key| decoded.get(key).and_then(Element::as_int).filter(|val| *val > 0).map(|val| val as u64);
+        let count = |key| decoded.get(key).and_then(Element::as_int).and_then(|val| u32::try_from(val).ok());
+
+        if let Some(reason) = text("failure reason") {
+            return Err(TrackerError::Failure(reason));
+        }
+
+        let mut ret = Response {
+            peers: Vec::new(),
+            interval: seconds("interval"),
+            min_interval: seconds("min interval"),
+            tracker_id: text("tracker id"),
+            warning: text("warning message"),
+            seeders: count("complete"),
+            leechers: count("incomplete")
+        };
+
+        match decoded.get("peers") {
+            Some(Element::ByteString(peers)) => {
+                for mut peer in peers.chunks_exact(6) {
+                    let ip = Ipv4Addr::from(peer.read_u32::<BigEndian>().unwrap());
+                    ret.peers.push(SocketAddr::from((ip, peer.read_u16::<BigEndian>().unwrap())));
+                }
+            },
+            // Trackers ignoring compact send a list of dictionaries, ip may be a host name which we skip
+            Some(Element::List(peers)) => {
+                for peer in peers {
+                    let ip = peer.get("ip").and_then(Element::as_bytes).and_then(|ip| std::str::from_utf8(ip).ok()?.parse::<IpAddr>().ok());
+                    let port = peer.get("port").and_then(Element::as_int).and_then(|port| u16::try_from(port).ok());
+                    if let (Some(ip), Some(port)) = (ip, port) {
+                        ret.peers.push(SocketAddr::new(ip, port));
+                    }
+                }
+            },
+            _ => {}
+        }
+
+        // IPv6 peers come separately (BEP 7)
+        if let Some(Element::ByteString(peers)) = decoded.get("peers6") {
+            for mut peer in peers.chunks_exact(18) {
+                let ip = Ipv6Addr::from(peer.read_u128::<BigEndian>().unwrap());
+                ret.peers.push(SocketAddr::from((ip, peer.read_u16::<BigEndian>().unwrap())));
+            }
+        }
+
+        Ok(ret)
+
+    }
+
+    // Bencoded body of a reply. A tracker may send a failure reason with an error status, so the body is read regardless
+    async fn get(client: &Client, url: String) -> Result<Element, TrackerError> {
+
+        let res = client.get(url).send().await.map_err(|_| TrackerError::Unreachable)?;
+        let status = res.status();
+        let body = res.bytes().await.map_err(|_| TrackerError::Unreachable)?.to_vec();
+
+        match Bencode::decode_u8(body) {
+            Ok(decoded) if status.is_success() || decoded.get("failure reason").is_some() => Ok(decoded),
+            _ if !status.is_success() => Err(TrackerError::Http(status.as_u16())),
+            _ => Err(TrackerError::Malformed)
+        }
+    }
+
+    pub async fn peer_list_helper(client: &Client, announce_url: String, announce: &Announce) -> Result<Response, TrackerError> {
+        let decoded = get(client, url_parser(announce_url, announce, true, Some(50))).await?;
+        parse_response(&decoded)
+    }
+
+    // Scrape url of a tracker, which only exists when the last path segment starts with "announce" (BEP 48)
+    pub fn scrape_url(announce_url: &str) -> Option<String> {
+        let (path, query) = announce_url.split_once('?').map_or((announce_url, None), |(path, query)| (path, Some(query)));
+        let (base, last) = path.rsplit_once('/')?;
+        let rest = last.strip_prefix("announce")?;
+        Some(format!("{}/scrape{}", base, rest) + &query.map(|query| format!("?{}", query)).unwrap_or_default())
+    }
+
+    // Counts for the info hashes the tracker knows
+    pub(super) fn parse_scrape(decoded: &Element) -> Result<Vec<([u8; 20], ScrapeStats)>, TrackerError> {
+
+        if let Some(reason) = decoded.get("failure reason").and_then(Element::as_bytes) {
+            return Err(TrackerError::Failure(String::from_utf8_lossy(reason).into_owned()));
+        }
+        let files = match decoded.get("files") {
+            Some(Element::Dict(files)) => files,
+            _ => return Err(TrackerError::Malformed)
+        };
+
+        let count = |stats: &Element, key| stats.get(key).and_then(Element::as_int).unwrap_or(0).clamp(0, u32::MAX as i64) as u32;
+        Ok(files.iter().filter_map(|(info_hash, stats)| {
+            let stats = ScrapeStats { seeders: count(stats, "complete"), completed: count(stats, "downloaded"), leechers: count(stats, "incomplete") };
+            Some((info_hash.as_slice().try_into().ok()?, stats))
+        }).collect())
+    }
+
+    pub async fn scrape(client: &Client, announce_url: &str, info_hashes: &[[u8; 20]]) -> Result<Vec<([u8; 20], ScrapeStats)>, TrackerError> {
+
+        let url = scrape_url(announce_url).ok_or(TrackerError::NoScrape)?;
+        let separator = if url.contains('?') { "&" } else { "?" };
+
+        let mut ret = Vec::new();
+        for batch in info_hashes.chunks(SCRAPE_BATCH) {
+            let query: Vec<String> = batch.iter().map(|info_hash| "info_hash=".to_owned() + &u8_to_url(*info_hash)).collect();
+            let decoded = get(client, url.clone() + separator + &query.join("&")).await?;
+            ret.extend(parse_scrape(&decoded)?);
+        }
+
+        Ok(ret)
+    }
+}
+
+// Announce to a tracker
+pub async fn announce_to(client: &TrackerClient, announce_url: &str, announce: &Announce) -> Result<Response, TrackerError> {
+
+    // UDP trackers have no paused event
+    if announce_url.starts_with("udp://") {
+        udp_tracker::peer_list_helper(client.udp.as_ref(), announce_url.to_string(), announce).await
+    }
+    else if announce_url.starts_with("http") {
+        http_tracker::peer_list_helper(&client.http, announce_url.to_string(), announce).await
+    }
+    else {
+        Err(TrackerError::Unreachable)
+    }
+}
+
+// Seeders, leechers and completed counts of each torrent from a tracker, torrents it does not know are left out
+pub async fn scrape(client: &TrackerClient, announce_url: &str, info_hashes: &[[u8; 20]]) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+
+    if announce_url.starts_with("udp://") {
+        let stats = udp_tracker::scrape(client.udp.as_ref(), announce_url.to_string(), info_hashes).await?;
+        Ok(info_hashes.iter().copied().zip(stats).collect())
+    }
+    else if announce_url.starts_with("http") {
+        Ok(http_tracker::scrape(&client.http, announce_url, info_hashes).await?.into_iter().collect())
+    }
+    else {
+        Err(TrackerError::Unreachable)
+    }
+}
+
+// Announce to one tracker, queue the peers it gave us and schedule its next announce
+async fn update_tracker(client: TrackerClient, trackers: TrackerList, peer_list: PeerList, url: String, announce: Announce) {
+
+    // A panic in the announce still has to reach the tracker state, or it stays updating for good
+    let event = announce.event;
+    let res = {
+        let url = url.clone();
+        tokio::spawn(async move { announce_to(&client, &url, &announce).await }).await.unwrap_or(Err(TrackerError::Panicked))
+    };
+    if let Ok(res) = &res {
+        let mut list = peer_list.lock().await;
+        for peer in &res.peers {
+            (*list).push_back((*peer, PeerSource::Tracker));
+        }
+    }
+
+    let mut trackers = trackers.lock().await;
+    if let Some(i) = trackers.iter().position(|tracker| tracker.url == url) {
+        trackers[i].update(&res, event);
+
+        // A tracker which answered moves to the front of its tier
+        if res.is_ok() {
+            let front = trackers.iter().position(|tracker| tracker.tier == trackers[i].tier).unwrap_or(i);
+            let tracker = trackers.remove(i);
+            trackers.insert(front, tracker);
+        }
+    }
+}
+
+// Announce to trackers as they come due, until the torrent is complete
+pub async fn get_peers(torrent: Torrent, client: TrackerClient) {
+
+    let mut tick = time::interval(Duration::from_secs(1));
+    let mut completed_at = None;
+
+    loop {
+        tick.tick().await;
+
+        // A super seed keeps announcing after completion
+        let complete = *(torrent.piece_left.lock().await) == 0 && torrent.super_seed.is_none();
+        if complete && completed_at.is_none() {
+            completed_at = Some(Instant::now());
+        }
+
+        // Holding every wanted piece of an incomplete torrent, we stay on as a partial seed
+        let paused = !complete && *(torrent.wanted_left.lock().await) == 0;
+
+        let short_of_peers = (*(torrent.connections.lock().await)).len() < CONN_LIMIT as usize && torrent.peer_list.lock().await.is_empty();
+
+        let now = Instant::now();
+        let mut due = vec![];
+        {
+            let mut trackers = torrent.trackers.lock().await;
+
+            // Done once every tracker which knows us heard completed, including ones whose started was still on its way
+            if let Some(at) = completed_at {
+                let waiting = trackers.iter().any(|tracker| tracker.updating || (tracker.started && !tracker.completed));
+                if !waiting || at.elapsed() >= COMPLETED_WAIT {
+                    break;
+                }
+            }
+
+            // Completed goes out regardless of schedule, a failed one is retried after the usual backoff
+            let mut chosen = if complete {
+                (0..trackers.len()).filter(|i| {
+                    let tracker = &trackers[*i];
+                    tracker.started && !tracker.completed && !tracker.updating && (tracker.failures == 0 || tracker.next_announce <= now)
+                }).collect()
+            } else {
+                due_trackers(&trackers, now, short_of_peers, client.all_tiers)
+            };
+
+            // Trackers which know us hear about the pause right away, unless that announce failed
+            if paused {
+                for (i, tracker) in trackers.iter().enumerate() {
+                    if tracker.started && !tracker.paused && !tracker.updating && tracker.failures == 0 && !chosen.contains(&i) {
+                        chosen.push(i);
+                    }
+                }
+            }
+
+            // Forced announces go out whatever the tier or backoff
+            for (i, tracker) in trackers.iter().enumerate() {
+                if tracker.forced && !tracker.updating && !chosen.contains(&i) {
+                    chosen.push(i);
+                }
+            }
+            for i in chosen {
+                let tracker = &mut trackers[i];
+                tracker.forced = false;
+
+                // Trackers hear started first, completed once when the download finishes, paused while we are a partial seed
+                let event = match (complete, tracker.started) {
+                    (true, true) if !tracker.completed => Event::Completed,
+                    (true, _) => continue,
+                    (false, false) => Event::Started,
+                    (false, true) if paused => Event::Paused,
+                    (false, true) => Event::None
+                };
+
+                tracker.updating = true;
+                due.push((tracker.url.clone(), tracker.identity(torrent.peer_id), event));
+            }
+        }
+
+        for (url, (tracker_id, key, peer_id), event) in due {
+            let announce = Announce { peer_id, tracker_id, key, ip: client.ip, ..Announce::new(&torrent, event).await };
+            tokio::spawn(update_tracker(client.clone(), torrent.trackers.clone(), torrent.peer_list.clone(), url, announce));
+        }
+    }
+}
+
+// Tell the trackers which know us that we are leaving, without letting a slow one hold up shutdown
+pub async fn stop(torrent: &Torrent, client: &TrackerClient) {
+
+    let announce = Announce::new(torrent, Event::Stopped).await;
+    let started: Vec<_> = torrent.trackers.lock().await.iter_mut()
+        .filter(|tracker| tracker.started).map(|tracker| (tracker.url.clone(), tracker.identity(torrent.peer_id))).collect();
+
+    let handles: Vec<_> = started.into_iter().map(|(url, (tracker_id, key, peer_id))| {
+        let (client, trackers) = (client.clone(), torrent.trackers.clone());
+        let announce = Announce { peer_id, tracker_id, key, ip: client.ip, ..announce.clone() };
+        tokio::spawn(async move {
+            if let Ok(res) = timeout(Duration::from_secs(5), announce_to(&client, &url, &announce)).await {
+                for tracker in trackers.lock().await.iter_mut().filter(|tracker| tracker.url == url) {
+                    tracker.update(&res, Event::Stopped);
+                }
+            }
+        })
+    }).collect();
+
+    for handle in handles {
+        let _ = handle.await;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::{Duration, Instant}};
+    use reqwest::Client;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener, sync::Mutex};
+    use crate::{bencoded_parser::Bencode, config::Config, torrent_parser::test_torrent};
+    use super::{get_peers, http_tracker::{parse_response, parse_scrape, redirect_policy, scrape_url, url_parser}, announce_to, due_trackers, scrape, ScrapeStats, retry_delay, Announce, Event, Tracker, TrackerClient, TrackerError};
+
+    // Stub HTTP tracker, /old redirects to /announce which answers with dictionary peers. Keeps the request heads
+    async fn stub_tracker(requests: Arc<Mutex<Vec<String>>>) -> String {
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = vec![0; 4096];
+                let n = stream.read(&mut buf).await.unwrap();
+                let req = String::from_utf8_lossy(&buf[..n]).to_string();
+                let target = req.split(' ').nth(1).unwrap().to_string();
+                requests.lock().await.push(req);
+
+                let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+                let (status, headers, body): (&str, String, &[u8]) = match path {
+                    "/old" => ("302 Found", format!("Location: /announce?{}\r\n", query), b""),
+                    "/announce" => ("200 OK", String::new(), b"d8:intervali900e5:peersld2:ip8:10.0.0.14:porti6881eed2:ip3:::14:porti80eed2:ip4:host4:porti1eeee"),
+                    "/refuse" => ("400 Bad Request", String::new(), b"d14:failure reason3:no!e"),
+                    _ => ("404 Not Found", String::new(), b"")
+                };
+                let mut res = format!("HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n", status, headers, body.len()).into_bytes();
+                res.extend(body);
+                stream.write_all(&res).await.unwrap();
+            }
+        });
+
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn paused_test() {
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let base = stub_tracker(requests.clone()).await;
+        let mut torrent = test_torrent(&format!("{}/announce", base), 2).await;
+        torrent.listen_port = 7000;
+        *torrent.wanted_left.lock().await = 0;
+
+        // A partial seed starts, tells the tracker it is paused and keeps going
+        let announcer = tokio::spawn(get_peers(torrent.clone(), TrackerClient::new(&Config::default()).unwrap()));
+        for _ in 0..50 {
+            if torrent.trackers().await[0].paused {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let events: Vec<bool> = requests.lock().await.iter().map(|req| req.contains("event=paused")).collect();
+        assert_eq!(events, vec![false, true]);
+        assert!(requests.lock().await[0].contains("event=started"));
+        assert!(requests.lock().await[0].contains("&port=7000&"));
+        assert!(!announcer.is_finished());
+        announcer.abort();
+    }
+
+    #[tokio::test]
+    async fn completed_test() {
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let base = stub_tracker(requests.clone()).await;
+        let torrent = test_torrent(&format!("{}/announce", base), 2).await;
+        *torrent.piece_left.lock().await = 0;
+        {
+            // One tracker knows us, the started announce to the other is still out
+            let mut trackers = torrent.trackers.lock().await;
+            trackers[0].started = true;
+            trackers.push(Tracker { updating: true, ..Tracker::new(format!("{}/announce?t=2", base), 1) });
+        }
+
+        let announcer = tokio::spawn(get_peers(torrent.clone(), TrackerClient::new(&Config::default()).unwrap()));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(requests.lock().await.len(), 1);
+        assert!(requests.lock().await[0].contains("event=completed"));
+        assert!(!announcer.is_finished());
+
+        // Once the started announce lands, that tracker hears completed too and we are done
+        {
+            let mut trackers = torrent.trackers.lock().await;
+            trackers[1].updating = false;
+            trackers[1].started = true;
+        }
+        tokio::time::timeout(Duration::from_secs(5), announcer).await.unwrap().unwrap();
+        let requests = requests.lock().await;
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].starts_with("GET /announce?t=2&") && requests[1].contains("event=completed"));
+        assert!(torrent.trackers().await.iter().all(|tracker| tracker.completed));
+    }
+
+    #[test]
+    fn url_parser_test() {
+
+        let mut announce = Announce { info_hash: [0x41; 20], peer_id: [0x42; 20], port: 6881, uploaded: 10, downloaded: 20, left: 30, event: Event::Started, tracker_id: None, key: 0xBEEF, ip: None };
+        let url = url_parser("http://t/announce".to_string(), &announce, true, None);
+        assert_eq!(url, format!("http://t/announce?info_hash={}&peer_id={}&port=6881&uploaded=10&downloaded=20&left=30&compact=1&no_peer_id=1&key=0000BEEF&event=started", "A".repeat(20), "B".repeat(20)));
+
+        // Passkey urls already have a query
+        announce.ip = Some("::1".parse().unwrap());
+        assert!(url_parser("http://t/a?pk=1".to_string(), &announce, true, None).starts_with("http://t/a?pk=1&info_hash="));
+        assert!(url_parser("http://t/a".to_string(), &announce, true, None).contains("&ip=%3A%3A1&"));
+
+        // Regular announces leave the event out
+        announce.event = Event::None;
+        assert!(!url_parser("http://t/announce".to_string(), &announce, true, Some(50)).contains("event"));
+
+        announce.tracker_id = Some("a b".to_string());
+        assert!(url_parser("http://t/announce".to_string(), &announce, true, None).ends_with("&trackerid=a+b"));
+    }
+
+    #[tokio::test]
+    async fn http_tracker_test() {
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let base = stub_tracker(requests.clone()).await;
+        let http = Client::builder().no_proxy().user_agent("Test/1.0").redirect(redirect_policy()).build().unwrap();
+        let client = TrackerClient { http, udp: None, ip: None, all_tiers: false };
+        let announce = Announce { info_hash: [1; 20], peer_id: [2; 20], port: 6881, uploaded: 0, downloaded: 0, left: 1, event: Event::None, tracker_id: None, key: 7, ip: None };
+
+        // Redirect followed, host names among the peers skipped
+        let res = announce_to(&client, &format!("{}/old", base), &announce).await.unwrap();
+        assert_eq!(res.interval, Some(900));
+        assert_eq!(res.peers, vec!["10.0.0.1:6881".parse().unwrap(), "[::1]:80".parse().unwrap()]);
+        let requests = requests.lock().await;
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].starts_with("GET /announce?info_hash=") && requests[1].contains("&key=00000007"));
+        assert!(requests[1].to_ascii_lowercase().contains("user-agent: test/1.0"));
+        drop(requests);
+
+        assert_eq!(announce_to(&client, &format!("{}/refuse", base), &announce).await, Err(TrackerError::Failure("no!".to_string())));
+        assert_eq!(announce_to(&client, &format!("{}/gone", base), &announce).await, Err(TrackerError::Http(404)));
+
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        assert_eq!(announce_to(&client, &format!("http://{}/announce", closed), &announce).await, Err(TrackerError::Unreachable));
+
+        // A tracker without an announce segment cannot be scraped, which is not the same as down
+        let err = scrape(&client, &format!("{}/a", base), &[[1; 20]]).await.unwrap_err();
+        assert_eq!(err, TrackerError::NoScrape);
+        assert_eq!(err.to_string(), "scrape not supported");
+    }
+
+    #[test]
+    fn response_test() {
+
+        let decoded = Bencode::decode_u8(b"d8:intervali1800e12:min intervali60e10:tracker id2:id15:warning message4:slow8:completei3e10:incompletei4e5:peers6:\x7f\x00\x00\x01\x1a\xe1e".to_vec()).unwrap();
+        let res = parse_response(&decoded).unwrap();
+        assert_eq!((res.interval, res.min_interval, res.tracker_id.as_deref(), res.warning.as_deref()), (Some(1800), Some(60), Some("id"), Some("slow")));
+        assert_eq!(res.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+        assert_eq!((res.seeders, res.leechers), (Some(3), Some(4)));
+
+        let decoded = Bencode::decode_u8(b"d14:failure reason6:bannede".to_vec()).unwrap();
+        assert_eq!(parse_response(&decoded), Err(TrackerError::Failure("banned".to_string())));
+    }
+
+    #[test]
+    fn scrape_test() {
+
+        assert_eq!(scrape_url("http://t/announce").as_deref(), Some("http://t/scrape"));
+        assert_eq!(scrape_url("http://t/x/announce.php?key=1/2").as_deref(), Some("http://t/x/scrape.php?key=1/2"));
+        assert_eq!(scrape_url("http://t/a"), None);
+
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend([7; 20]);
+        body.extend(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let stats = parse_scrape(&Bencode::decode_u8(body).unwrap()).unwrap();
+        assert_eq!(stats, vec![([7; 20], ScrapeStats { seeders: 5, completed: 50, leechers: 10 })]);
+    }
+
+    #[test]
+    fn schedule_test() {
+
+        let mut tracker = Tracker::new("udp://t:1".to_string(), 0);
+        let now = Instant::now();
+        assert!(tracker.due(now, false));
+        assert_eq!(tracker.status(), "waiting");
+
+        // Failures back off, with jitter
+        tracker.update(&Err(TrackerError::Unreachable), Event::Started);
+        tracker.update(&Err(TrackerError::Unreachable), Event::Started);
+        assert_eq!((tracker.failures, tracker.error.as_deref(), tracker.started), (2, Some("no answer"), false));
+        assert_eq!((tracker.status(), tracker.last_announce), ("failed", None));
+        assert!(!tracker.due(Instant::now(), true));
+        let delay = retry_delay(3);
+        assert!(delay >= Duration::from_secs(90) && delay <= Duration::from_secs(150));
+        assert!(retry_delay(40) <= Duration::from_secs(75 * 60));
+
+        // Success resets the backoff and follows the tracker's intervals
+        let res = super::Response { interval: Some(600), min_interval: Some(900), peers: vec!["1.2.3.4:5".parse().unwrap()], seeders: Some(7), ..Default::default() };
+        tracker.update(&Ok(res), Event::Started);
+        assert_eq!((tracker.failures, tracker.error.is_none(), tracker.started), (0, true, true));
+        assert_eq!((tracker.status(), tracker.peers, tracker.seeders, tracker.leechers), ("working", 1, Some(7), None));
+        assert_eq!((tracker.interval, tracker.min_interval), (600, 600));
+        assert!(!tracker.due(Instant::now(), true));
+        assert!(tracker.due(Instant::now() + Duration::from_secs(600), false));
+    }
+
+    #[test]
+    fn tiers_test() {
+
+        let tiers = vec![vec!["a".to_string(), "b".to_string()], vec!["c".to_string()]];
+        let mut trackers = Tracker::from_tiers(tiers);
+        assert_eq!(trackers.iter().map(|tracker| tracker.tier).collect::<Vec<_>>(), vec![0, 0, 1]);
+        assert_eq!(trackers[2].url, "c");
+
+        // First tracker is tried alone, or one from each tier
+        let now = Instant::now();
+        assert_eq!(due_trackers(&trackers, now, false, false), vec![0]);
+        assert_eq!(due_trackers(&trackers, now, false, true), vec![0, 2]);
+
+        // Failed trackers are passed over until their backoff ends, falling through to the next tier
+        trackers[0].update(&Err(TrackerError::Unreachable), Event::Started);
+        assert_eq!(due_trackers(&trackers, now, false, false), vec![1]);
+        trackers[1].update(&Err(TrackerError::Unreachable), Event::Started);
+        assert_eq!(due_trackers(&trackers, now, false, false), vec![2]);
+
+        // A working tracker holds its place while it is not due
+        trackers[2].update(&Ok(Default::default()), Event::Started);
+        assert!(due_trackers(&trackers, Instant::now(), false, false).is_empty());
+        assert_eq!(due_trackers(&trackers, Instant::now() + Duration::from_secs(3600), false, false), vec![0]);
+
+        // Key and peer id stay what the tracker first saw
+        let (_, key, peer_id) = trackers[2].identity([1; 20]);
+        assert_eq!(trackers[2].identity([2; 20]), (None, key, peer_id));
+        assert_eq!(peer_id, [1; 20]);
+    }
+}
@@ -0,0 +1,675 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant}
+};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rand::seq::SliceRandom;
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::Mutex,
+    time::{self, timeout}
+};
+use crate::{
+    bencoded_parser::{Bencode, Element},
+    helpers,
+    tracker::{Event, ScrapeStats}
+};
+
+// Seconds between announces we ask clients for
+pub static ANNOUNCE_INTERVAL: u64 = 30 * 60;
+
+// Peers which missed two announces are dropped
+static PEER_EXPIRY: Duration = Duration::from_secs(2 * ANNOUNCE_INTERVAL);
+
+// Peers handed out when the client does not say how many it wants, and the most we ever give
+static DEFAULT_NUMWANT: usize = 50;
+static MAX_NUMWANT: usize = 200;
+
+// UDP connection ids stay valid a little longer than the minute clients use them for
+static CONNECTION_LIFETIME: Duration = Duration::from_secs(2 * 60);
+
+// Largest HTTP request head we read
+static MAX_REQUEST: usize = 8192;
+
+static PROTOCOL_ID: u64 = 0x41727101980;
+
+// Who may use the tracker
+#[derive(Debug, Default)]
+pub struct ServerOptions {
+    // Info hashes the tracker serves, any torrent when None
+    pub whitelist: Option<HashSet<[u8; 20]>>,
+    // Keys users put in front of the path, /<passkey>/announce, none needed when None
+    pub passkeys: Option<HashSet<String>>
+}
+
+impl ServerOptions {
+
+    // Whitelist has one hex info hash per line, the passkey file one key per line
+    pub fn load(whitelist: Option<&Path>, passkeys: Option<&Path>) -> io::Result<ServerOptions> {
+
+        let lines = |path: &Path| -> io::Result<Vec<String>> {
+            Ok(fs::read_to_string(path)?.lines().map(str::trim).filter(|line| !line.is_empty()).map(str::to_string).collect())
+        };
+
+        let whitelist = match whitelist {
+            Some(path) => Some(lines(path)?.iter().map(|line| {
+                let mut info_hash = [0; 20];
+                hex::decode_to_slice(line, &mut info_hash).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("bad info hash {}", line)))?;
+                Ok(info_hash)
+            }).collect::<io::Result<_>>()?),
+            None => None
+        };
+        let passkeys = match passkeys {
+            Some(path) => Some(lines(path)?.into_iter().collect()),
+            None => None
+        };
+
+        Ok(ServerOptions { whitelist, passkeys })
+    }
+}
+
+// An announce as either protocol delivers it
+#[derive(Debug)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub addr: SocketAddr,
+    pub left: u64,
+    pub event: Event,
+    pub numwant: Option<usize>,
+    pub passkey: Option<String>
+}
+
+#[derive(Clone, Debug)]
+struct PeerEntry {
+    peer_id: [u8; 20],
+    addr: SocketAddr,
+    seed: bool,
+    last_seen: Instant
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], PeerEntry>,
+    // Completed events received
+    completed: u32
+}
+
+impl Swarm {
+    fn stats(&self) -> ScrapeStats {
+        let seeders = self.peers.values().filter(|peer| peer.seed).count() as u32;
+        ScrapeStats { seeders, completed: self.completed, leechers: self.peers.len() as u32 - seeders }
+    }
+}
+
+// Peers and counts for one announce
+#[derive(Debug)]
+struct AnnounceReply {
+    peers: Vec<([u8; 20], SocketAddr)>,
+    stats: ScrapeStats
+}
+
+// Tracker serving HTTP and UDP announces and scrapes from one peer table
+pub struct TrackerServer {
+    options: ServerOptions,
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+    // UDP connection ids we handed out and when
+    connections: Mutex<HashMap<u64, Instant>>
+}
+
+impl TrackerServer {
+
+    pub fn new(options: ServerOptions) -> TrackerServer {
+        TrackerServer { options, swarms: Mutex::new(HashMap::new()), connections: Mutex::new(HashMap::new()) }
+    }
+
+    fn check_passkey(&self, passkey: Option<&str>) -> Result<(), String> {
+        match (&self.options.passkeys, passkey) {
+            (None, _) => Ok(()),
+            (Some(keys), Some(key)) if keys.contains(key) => Ok(()),
+            _ => Err("unknown passkey".to_string())
+        }
+    }
+
+    fn check_torrent(&self, info_hash: &[u8; 20]) -> Result<(), String> {
+        match &self.options.whitelist {
+            Some(list) if !list.contains(info_hash) => Err("torrent not allowed".to_string()),
+            _ => Ok(())
+        }
+    }
+
+    // Record the peer and pick others for it, seeds get no seeds
+    async fn announce(&self, req: &AnnounceRequest) -> Result<AnnounceReply, String> {
+
+        self.check_passkey(req.passkey.as_deref())?;
+        self.check_torrent(&req.info_hash)?;
+
+        let mut swarms = self.swarms.lock().await;
+        let swarm = swarms.entry(req.info_hash).or_default();
+
+        if req.event == Event::Stopped {
+            swarm.peers.remove(&req.peer_id);
+        }
+        else {
+            swarm.peers.insert(req.peer_id, PeerEntry { peer_id: req.peer_id, addr: req.addr, seed: req.left == 0, last_seen: Instant::now() });
+        }
+        if req.event == Event::Completed {
+            swarm.completed += 1;
+        }
+
+        let seed = req.left == 0;
+        let mut peers: Vec<([u8; 20], SocketAddr)> = swarm.peers.values()
+            .filter(|peer| peer.peer_id != req.peer_id && !(seed && peer.seed))
+            .map(|peer| (peer.peer_id, peer.addr)).collect();
+        peers.shuffle(&mut rand::thread_rng());
+        peers.truncate(req.numwant.unwrap_or(DEFAULT_NUMWANT).min(MAX_NUMWANT));
+
+        Ok(AnnounceReply { peers, stats: swarm.stats() })
+    }
+
+    // Counts for the given torrents on the whitelist, or every torrent when none are given.
+    // Passkeys are checked by the caller, UDP scrapes have none and are refused when keys are set
+    async fn scrape(&self, info_hashes: &[[u8; 20]]) -> Vec<([u8; 20], ScrapeStats)> {
+
+        let swarms = self.swarms.lock().await;
+        if info_hashes.is_empty() {
+            return swarms.iter().map(|(info_hash, swarm)| (*info_hash, swarm.stats())).collect();
+        }
+        info_hashes.iter().filter(|info_hash| self.check_torrent(info_hash).is_ok())
+            .map(|info_hash| (*info_hash, swarms.get(info_hash).map(Swarm::stats).unwrap_or_default())).collect()
+    }
+
+    // Drop peers which stopped announcing, and stale connection ids
+    async fn expire(&self) {
+        let mut swarms = self.swarms.lock().await;
+        for swarm in swarms.values_mut() {
+            swarm.peers.retain(|_, peer| peer.last_seen.elapsed() < PEER_EXPIRY);
+        }
+        swarms.retain(|_, swarm| !swarm.peers.is_empty() || swarm.completed > 0);
+        self.connections.lock().await.retain(|_, at| at.elapsed() < CONNECTION_LIFETIME);
+    }
+
+    // Bencoded reply to a GET of path and query
+    async fn http_reply(&self, target: &str, from: SocketAddr) -> Vec<u8> {
+
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let params = query_pairs(query);
+        let param = |key: &str| params.iter().find(|(name, _)| name == key).map(|(_, value)| value.as_slice());
+        let text = |key: &str| param(key).and_then(|value| std::str::from_utf8(value).ok());
+
+        // /announce, or /<passkey>/announce
+        let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+        let (passkey, action) = match segments.as_slice() {
+            [action] => (None, *action),
+            [passkey, action] => (Some(passkey.to_string()), *action),
+            _ => return failure("not found")
+        };
+
+        match action {
+            "announce" => {
+                let info_hash = param("info_hash").and_then(|value| value.try_into().ok());
+                let peer_id = param("peer_id").and_then(|value| value.try_into().ok());
+                let port = text("port").and_then(|port| port.parse::<u16>().ok());
+                // Without left we cannot tell a seed from a leecher
+                let left = text("left").and_then(|left| left.parse::<u64>().ok());
+                let (info_hash, peer_id, port, left) = match (info_hash, peer_id, port, left) {
+                    (Some(info_hash), Some(peer_id), Some(port), Some(left)) => (info_hash, peer_id, port, left),
+                    _ => return failure("invalid announce")
+                };
+
+                let req = AnnounceRequest {
+                    info_hash,
+                    peer_id,
+                    addr: SocketAddr::new(from.ip(), port),
+                    left,
+                    event: match text("event") {
+                        Some("started") => Event::Started,
+                        Some("completed") => Event::Completed,
+                        Some("stopped") => Event::Stopped,
+                        Some("paused") => Event::Paused,
+                        _ => Event::None
+                    },
+                    numwant: text("numwant").and_then(|numwant| numwant.parse().ok()),
+                    passkey
+                };
+
+                match self.announce(&req).await {
+                    Ok(reply) => Bencode::encode(&announce_dict(&reply, text("compact") == Some("1"), text("no_peer_id") == Some("1"))),
+                    Err(reason) => failure(&reason)
+                }
+            },
+            "scrape" => {
+                let info_hashes: Vec<[u8; 20]> = params.iter().filter(|(name, _)| name == "info_hash")
+                    .filter_map(|(_, value)| value.as_slice().try_into().ok()).collect();
+                if let Err(reason) = self.check_passkey(passkey.as_deref()) {
+                    return failure(&reason);
+                }
+                let files = self.scrape(&info_hashes).await.into_iter().map(|(info_hash, stats)| (info_hash.to_vec(), dict(vec![
+                    ("complete", Element::Integer(stats.seeders as i64)),
+                    ("downloaded", Element::Integer(stats.completed as i64)),
+                    ("incomplete", Element::Integer(stats.leechers as i64))
+                ]))).collect();
+                Bencode::encode(&dict(vec![("files", Element::Dict(files))]))
+            },
+            _ => failure("not found")
+        }
+    }
+
+    async fn handle_http(&self, mut stream: TcpStream, from: SocketAddr) -> io::Result<()> {
+
+        // Scoped here, byteorder has methods of the same names
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut buf = Vec::new();
+        let mut chunk = [0; 2048];
+        while !buf.windows(4).any(|window| window == b"\r\n\r\n") {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 || buf.len() > MAX_REQUEST {
+                return Ok(());
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+
+        let head = String::from_utf8_lossy(&buf);
+        let mut request_line = head.split_whitespace();
+        let body = match (request_line.next(), request_line.next()) {
+            (Some("GET"), Some(target)) => self.http_reply(target, helpers::canonical_addr(from)).await,
+            _ => failure("bad request")
+        };
+
+        let mut res = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes();
+        res.extend(body);
+        stream.write_all(&res).await
+    }
+
+    pub async fn run_http(self: Arc<Self>, listener: TcpListener) {
+        while let Ok((stream, from)) = listener.accept().await {
+            let server = self.clone();
+            tokio::spawn(async move {
+                let _ = timeout(Duration::from_secs(10), server.handle_http(stream, from)).await;
+            });
+        }
+    }
+
+    // Reply to one UDP datagram (BEP 15), None when it deserves no answer
+    async fn udp_reply(&self, mut buf: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+
+        let connection_id = buf.read_u64::<BigEndian>().ok()?;
+        let action = buf.read_u32::<BigEndian>().ok()?;
+        let transaction_id = buf.read_u32::<BigEndian>().ok()?;
+
+        let mut res = Vec::new();
+        if action == 0 {
+            if connection_id != PROTOCOL_ID {
+                return None;
+            }
+            let id: u64 = rand::random();
+            self.connections.lock().await.insert(id, Instant::now());
+            res.write_u32::<BigEndian>(0).unwrap();
+            res.write_u32::<BigEndian>(transaction_id).unwrap();
+            res.write_u64::<BigEndian>(id).unwrap();
+            return Some(res);
+        }
+
+        if self.connections.lock().await.get(&connection_id).is_none_or(|at| at.elapsed() >= CONNECTION_LIFETIME) {
+            return Some(udp_error(transaction_id, "connection id expired"));
+        }
+
+        match action {
+            1 => {
+                if buf.len() < 82 {
+                    return Some(udp_error(transaction_id, "invalid announce"));
+                }
+                let info_hash: [u8; 20] = buf[..20].try_into().unwrap();
+                let peer_id: [u8; 20] = buf[20..40].try_into().unwrap();
+                buf = &buf[40..];
+                let _downloaded = buf.read_u64::<BigEndian>().ok()?;
+                let left = buf.read_u64::<BigEndian>().ok()?;
+                let _uploaded = buf.read_u64::<BigEndian>().ok()?;
+                let event = match buf.read_u32::<BigEndian>().ok()? {
+                    1 => Event::Completed,
+                    2 => Event::Started,
+                    3 => Event::Stopped,
+                    _ => Event::None
+                };
+                let _ip = buf.read_u32::<BigEndian>().ok()?;
+                let _key = buf.read_u32::<BigEndian>().ok()?;
+                let numwant = buf.read_i32::<BigEndian>().ok()?;
+                let port = buf.read_u16::<BigEndian>().ok()?;
+
+                let req = AnnounceRequest {
+                    info_hash,
+                    peer_id,
+                    addr: SocketAddr::new(from.ip(), port),
+                    left,
+                    event,
+                    numwant: usize::try_from(numwant).ok(),
+                    passkey: url_data_passkey(buf)
+                };
+                let reply = match self.announce(&req).await {
+                    Ok(reply) => reply,
+                    Err(reason) => return Some(udp_error(transaction_id, &reason))
+                };
+
+                res.write_u32::<BigEndian>(1).unwrap();
+                res.write_u32::<BigEndian>(transaction_id).unwrap();
+                res.write_u32::<BigEndian>(ANNOUNCE_INTERVAL as u32).unwrap();
+                res.write_u32::<BigEndian>(reply.stats.leechers).unwrap();
+                res.write_u32::<BigEndian>(reply.stats.seeders).unwrap();
+
+                // Peers of the address family the request came over
+                for (_, addr) in reply.peers {
+                    match (addr.ip(), from.is_ipv4()) {
+                        (IpAddr::V4(ip), true) => res.extend(ip.octets()),
+                        (IpAddr::V6(ip), false) => res.extend(ip.octets()),
+                        _ => continue
+                    }
+                    res.write_u16::<BigEndian>(addr.port()).unwrap();
+                }
+                Some(res)
+            },
+            2 => {
+                let info_hashes: Vec<[u8; 20]> = buf.chunks_exact(20).map(|info_hash| info_hash.try_into().unwrap()).collect();
+                if info_hashes.is_empty() {
+                    return Some(udp_error(transaction_id, "invalid scrape"));
+                }
+                // Info hashes run to the end of the packet, there is no room for url data with a passkey
+                if let Err(reason) = self.check_passkey(None) {
+                    return Some(udp_error(transaction_id, &reason));
+                }
+                let stats = self.scrape(&info_hashes).await;
+
+                res.write_u32::<BigEndian>(2).unwrap();
+                res.write_u32::<BigEndian>(transaction_id).unwrap();
+                // Torrents off the whitelist count as empty, so the reply lines up with the request
+                for info_hash in &info_hashes {
+                    let stats = stats.iter().find(|(hash, _)| hash == info_hash).map(|(_, stats)| *stats).unwrap_or_default();
+                    res.write_u32::<BigEndian>(stats.seeders).unwrap();
+                    res.write_u32::<BigEndian>(stats.completed).unwrap();
+                    res.write_u32::<BigEndian>(stats.leechers).unwrap();
+                }
+                Some(res)
+            },
+            _ => Some(udp_error(transaction_id, "unknown action"))
+        }
+    }
+
+    pub async fn run_udp(self: Arc<Self>, socket: UdpSocket) {
+        let mut buf = [0; 2048];
+        while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+            if let Some(res) = self.udp_reply(&buf[..len], helpers::canonical_addr(from)).await {
+                let _ = socket.send_to(&res, from).await;
+            }
+        }
+    }
+
+    // Drop stale peers every minute
+    pub async fn run_expiry(self: Arc<Self>) {
+        let mut interval = time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            self.expire().await;
+        }
+    }
+}
+
+// Serve HTTP and UDP on the same address until the process ends
+pub async fn serve(options: ServerOptions, listen: SocketAddr) -> io::Result<()> {
+
+    let listener = TcpListener::bind(listen).await?;
+    let socket = UdpSocket::bind(listen).await?;
+    let server = Arc::new(TrackerServer::new(options));
+
+    tokio::join!(server.clone().run_http(listener), server.clone().run_udp(socket), server.run_expiry());
+    Ok(())
+}
+
+fn dict(pairs: Vec<(&str, Element)>) -> Element {
+    Element::Dict(pairs.into_iter().map(|(key, value)| (key.as_bytes().to_vec(), value)).collect())
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    Bencode::encode(&dict(vec![("failure reason", Element::ByteString(reason.as_bytes().to_vec()))]))
+}
+
+fn udp_error(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut res = Vec::new();
+    res.write_u32::<BigEndian>(3).unwrap();
+    res.write_u32::<BigEndian>(transaction_id).unwrap();
+    res.extend(message.as_bytes());
+    res
+}
+
+// Announce reply, compact peers are IPv4 in peers and IPv6 in peers6 (BEP 7, BEP 23)
+fn announce_dict(reply: &AnnounceReply, compact: bool, no_peer_id: bool) -> Element {
+
+    let mut pairs = vec![
+        ("interval", Element::Integer(ANNOUNCE_INTERVAL as i64)),
+        ("complete", Element::Integer(reply.stats.seeders as i64)),
+        ("incomplete", Element::Integer(reply.stats.leechers as i64))
+    ];
+
+    if compact {
+        let (mut peers, mut peers6) = (Vec::new(), Vec::new());
+        for (_, addr) in &reply.peers {
+            match addr.ip() {
+                IpAddr::V4(ip) => peers.extend(ip.octets().iter().chain(&addr.port().to_be_bytes())),
+                IpAddr::V6(ip) => peers6.extend(ip.octets().iter().chain(&addr.port().to_be_bytes()))
+            }
+        }
+        pairs.push(("peers", Element::ByteString(peers)));
+        if !peers6.is_empty() {
+            pairs.push(("peers6", Element::ByteString(peers6)));
+        }
+    }
+    else {
+        let peers = reply.peers.iter().map(|(peer_id, addr)| {
+            let mut peer = vec![
+                ("ip", Element::ByteString(addr.ip().to_string().into_bytes())),
+                ("port", Element::Integer(addr.port() as i64))
+            ];
+            if !no_peer_id {
+                peer.push(("peer id", Element::ByteString(peer_id.to_vec())));
+            }
+            dict(peer)
+        }).collect();
+        pairs.push(("peers", Element::List(peers)));
+    }
+
+    dict(pairs)
+}
+
+// Query parameters with values percent decoded to bytes, info hashes are binary
+fn query_pairs(query: &str) -> Vec<(String, Vec<u8>)> {
+    query.split('&').filter_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        Some((String::from_utf8_lossy(&percent_decode(key)).into_owned(), percent_decode(value)))
+    }).collect()
+}
+
+fn percent_decode(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut ret = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok()).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                ret.push(byte);
+                i += 3;
+            },
+            (b'+', _) => {
+                ret.push(b' ');
+                i += 1;
+            },
+            (byte, _) => {
+                ret.push(byte);
+                i += 1;
+            }
+        }
+    }
+    ret
+}
+
+// Passkey from the path in BEP 41 url data options, /<passkey>/announce
+fn url_data_passkey(mut options: &[u8]) -> Option<String> {
+
+    let mut path = Vec::new();
+    while let Some((&kind, rest)) = options.split_first() {
+        match kind {
+            0 => break,
+            1 => options = rest,
+            _ => {
+                let (&len, rest) = rest.split_first()?;
+                let data = rest.get(..len as usize)?;
+                if kind == 2 {
+                    path.extend_from_slice(data);
+                }
+                options = &rest[len as usize..];
+            }
+        }
+    }
+
+    let path = String::from_utf8(path).ok()?;
+    let path = path.split('?').next()?;
+    let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+    match segments.as_slice() {
+        [passkey, _] => Some(passkey.to_string()),
+        _ => None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Arc, time::{Duration, Instant}};
+    use tokio::net::{TcpListener, UdpSocket};
+    use crate::{
+        config::Config,
+        tracker::{self, Announce, Event, ScrapeStats, TrackerClient, TrackerError}
+    };
+    use super::{percent_decode, url_data_passkey, AnnounceRequest, ServerOptions, TrackerServer, PEER_EXPIRY};
+
+    // Server with both protocols on loopback, returns the HTTP and UDP base urls
+    async fn start(options: ServerOptions) -> (String, String) {
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let urls = (format!("http://{}", listener.local_addr().unwrap()), format!("udp://{}", socket.local_addr().unwrap()));
+
+        let server = Arc::new(TrackerServer::new(options));
+        tokio::spawn(server.clone().run_http(listener));
+        tokio::spawn(server.run_udp(socket));
+        urls
+    }
+
+    fn announce(info_hash: [u8; 20], peer: u8, port: u16, left: u64) -> Announce {
+        Announce { info_hash, peer_id: [peer; 20], port, uploaded: 0, downloaded: 0, left, event: Event::Started, tracker_id: None, key: 0, ip: None }
+    }
+
+    #[test]
+    fn decode_test() {
+        assert_eq!(percent_decode("a%20b+c%zz%4"), b"a b c%zz%4");
+        assert_eq!(url_data_passkey(&[2, 5, b'/', b'k', b'e', b'y', b'/', 2, 8, b'a', b'n', b'n', b'o', b'u', b'n', b'c', b'e']), Some("key".to_string()));
+        assert_eq!(url_data_passkey(&[2, 9, b'/', b'a', b'n', b'n', b'o', b'u', b'n', b'c', b'e']), None);
+    }
+
+    #[tokio::test]
+    async fn server_test() {
+
+        let allowed = [1; 20];
+        let options = ServerOptions { whitelist: Some(HashSet::from([allowed])), passkeys: Some(HashSet::from(["secret".to_string()])) };
+        let (http, udp) = start(options).await;
+        let client = TrackerClient::new(&Config::default()).unwrap();
+
+        // A seed over HTTP, then a leecher over UDP finds it
+        let res = tracker::announce_to(&client, &format!("{}/secret/announce", http), &announce(allowed, 1, 7000, 0)).await.unwrap();
+        assert!(res.peers.is_empty());
+        let res = tracker::announce_to(&client, &format!("{}/secret/announce", udp), &announce(allowed, 2, 7001, 10)).await.unwrap();
+        assert_eq!(res.peers, vec!["127.0.0.1:7000".parse().unwrap()]);
+        let res = tracker::announce_to(&client, &format!("{}/secret/announce", http), &announce(allowed, 1, 7000, 0)).await.unwrap();
+        assert_eq!(res.peers, vec!["127.0.0.1:7001".parse().unwrap()]);
+
+        let stats = tracker::scrape(&client, &format!("{}/secret/announce", http), &[allowed]).await.unwrap();
+        assert_eq!(stats.get(&allowed), Some(&ScrapeStats { seeders: 1, completed: 0, leechers: 1 }));
+
+        // Passkey and whitelist are enforced, UDP scrapes cannot carry a passkey
+        let refused = tracker::scrape(&client, &format!("{}/secret/announce", udp), &[allowed]).await;
+        assert_eq!(refused, Err(TrackerError::Failure("unknown passkey".to_string())));
+        let refused = tracker::scrape(&client, &format!("{}/wrong/announce", http), &[allowed]).await;
+        assert_eq!(refused, Err(TrackerError::Failure("unknown passkey".to_string())));
+        let refused = tracker::announce_to(&client, &format!("{}/wrong/announce", http), &announce(allowed, 3, 7002, 10)).await;
+        assert_eq!(refused, Err(TrackerError::Failure("unknown passkey".to_string())));
+        let refused = tracker::announce_to(&client, &format!("{}/secret/announce", udp), &announce([2; 20], 3, 7002, 10)).await;
+        assert_eq!(refused, Err(TrackerError::Failure("torrent not allowed".to_string())));
+
+        // Stopped peers leave the swarm
+        let mut stop = announce(allowed, 2, 7001, 10);
+        stop.event = Event::Stopped;
+        tracker::announce_to(&client, &format!("{}/secret/announce", http), &stop).await.unwrap();
+        let res = tracker::announce_to(&client, &format!("{}/secret/announce", http), &announce(allowed, 1, 7000, 0)).await.unwrap();
+        assert!(res.peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn non_compact_test() {
+
+        let (http, _) = start(ServerOptions::default()).await;
+        let server = TrackerServer::new(ServerOptions::default());
+        let from = "10.0.0.5:1".parse().unwrap();
+        server.http_reply(&format!("/announce?info_hash={}&peer_id={}&port=80&left=5", "%01".repeat(20), "a".repeat(20)), from).await;
+        let body = server.http_reply(&format!("/announce?info_hash={}&peer_id={}&port=81&left=5&compact=0", "%01".repeat(20), "b".repeat(20)), from).await;
+        let refused = server.http_reply(&format!("/announce?info_hash={}&peer_id={}&port=82", "%01".repeat(20), "c".repeat(20)), from).await;
+        assert_eq!(refused, b"d14:failure reason16:invalid announcee");
+        assert!(body.windows(15).any(|window| window == b"d2:ip8:10.0.0.5"));
+        assert!(body.windows(10).any(|window| window == b"4:porti80e"));
+        assert!(body.windows(9).any(|window| window == b"7:peer id"));
+
+        let body = reqwest::get(format!("{}/scrape", http)).await.unwrap().bytes().await.unwrap();
+        assert_eq!(&body[..], b"d5:filesdee");
+    }
+
+    #[tokio::test]
+    async fn expire_test() {
+
+        let server = TrackerServer::new(ServerOptions::default());
+        let mut req = AnnounceRequest { info_hash: [1; 20], peer_id: [1; 20], addr: "10.0.0.1:1".parse().unwrap(), left: 0, event: Event::Started, numwant: None, passkey: None };
+        server.announce(&req).await.unwrap();
+        req.peer_id = [2; 20];
+        server.announce(&req).await.unwrap();
+        req.info_hash = [2; 20];
+        server.announce(&req).await.unwrap();
+        server.connections.lock().await.insert(1, Instant::now() - Duration::from_secs(3 * 60));
+        server.connections.lock().await.insert(2, Instant::now());
+
+        // One peer of the first swarm and the only peer of the second stopped announcing
+        {
+            let mut swarms = server.swarms.lock().await;
+            swarms.get_mut(&[1; 20]).unwrap().peers.get_mut(&[1; 20]).unwrap().last_seen -= PEER_EXPIRY;
+            swarms.get_mut(&[2; 20]).unwrap().peers.get_mut(&[2; 20]).unwrap().last_seen -= PEER_EXPIRY;
+        }
+        server.expire().await;
+
+        let swarms = server.swarms.lock().await;
+        assert_eq!(swarms[&[1; 20]].peers.keys().collect::<Vec<_>>(), vec![&[2; 20]]);
+        assert!(!swarms.contains_key(&[2; 20]));
+        assert_eq!(server.connections.lock().await.keys().collect::<Vec<_>>(), vec![&2]);
+    }
+
+    #[tokio::test]
+    async fn udp_scrape_test() {
+
+        // Without passkeys UDP scrapes are answered, off-whitelist torrents as empty
+        let allowed = [1; 20];
+        let (_, udp) = start(ServerOptions { whitelist: Some(HashSet::from([allowed])), passkeys: None }).await;
+        let client = TrackerClient::new(&Config::default()).unwrap();
+        tracker::announce_to(&client, &format!("{}/announce", udp), &announce(allowed, 1, 7000, 0)).await.unwrap();
+        let stats = tracker::scrape(&client, &format!("{}/announce", udp), &[allowed, [2; 20]]).await.unwrap();
+        assert_eq!(stats.get(&allowed), Some(&ScrapeStats { seeders: 1, completed: 0, leechers: 0 }));
+        assert_eq!(stats.get(&[2; 20]), Some(&ScrapeStats::default()));
+    }
+}
@@ -0,0 +1,811 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant}
+};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+    time::{self, timeout}
+};
+
+// uTP (BEP 29) with LEDBAT congestion control
+static HEADER_LEN: usize = 20;
+static VERSION: u8 = 1;
+static MSS: usize = 1400;
+static MIN_CWND: usize = 2 * 1400;
+static MAX_CWND: usize = 1 << 20;
+static RECV_WINDOW: usize = 1 << 20;
+
+// LEDBAT target queuing delay and maximum window growth per RTT, in microseconds and bytes
+static TARGET_DELAY: f64 = 100_000.0;
+static MAX_CWND_INCREASE: f64 = 3000.0;
+
+static INITIAL_RTO: Duration = Duration::from_millis(1000);
+static MIN_RTO: Duration = Duration::from_millis(500);
+static MAX_RTO: Duration = Duration::from_secs(16);
+static MAX_TRANSMISSIONS: u32 = 6;
+static CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+static IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+static KEEPALIVE: Duration = Duration::from_secs(29);
+static TICK: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PacketType {
+    Data,
+    Fin,
+    State,
+    Reset,
+    Syn
+}
+
+impl PacketType {
+    fn from_u8(val: u8) -> Option<PacketType> {
+        match val {
+            0 => Some(PacketType::Data),
+            1 => Some(PacketType::Fin),
+            2 => Some(PacketType::State),
+            3 => Some(PacketType::Reset),
+            4 => Some(PacketType::Syn),
+            _ => None
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            PacketType::Data => 0,
+            PacketType::Fin => 1,
+            PacketType::State => 2,
+            PacketType::Reset => 3,
+            PacketType::Syn => 4
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Header {
+    kind: PacketType,
+    conn_id: u16,
+    timestamp: u32,
+    timestamp_diff: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16
+}
+
+impl Header {
+
+    fn to_buf(&self, payload: &[u8]) -> Vec<u8> {
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+
+        buf.write_u8(self.kind.to_u8() << 4 | VERSION).unwrap(); // type and version
+        buf.write_u8(0).unwrap(); // no extension
+        buf.write_u16::<BigEndian>(self.conn_id).unwrap();
+        buf.write_u32::<BigEndian>(self.timestamp).unwrap();
+        buf.write_u32::<BigEndian>(self.timestamp_diff).unwrap();
+        buf.write_u32::<BigEndian>(self.wnd_size).unwrap();
+        buf.write_u16::<BigEndian>(self.seq_nr).unwrap();
+        buf.write_u16::<BigEndian>(self.ack_nr).unwrap();
+        buf.extend_from_slice(payload);
+
+        buf
+    }
+
+    // Parse header, skipping any extensions, and return it with the payload
+    fn parse(buf: &[u8]) -> Option<(Header, &[u8])> {
+
+        if buf.len() < HEADER_LEN || buf[0] & 0x0F != VERSION {
+            return None;
+        }
+
+        let mut rd = &buf[2..HEADER_LEN];
+        let header = Header {
+            kind: PacketType::from_u8(buf[0] >> 4)?,
+            conn_id: rd.read_u16::<BigEndian>().ok()?,
+            timestamp: rd.read_u32::<BigEndian>().ok()?,
+            timestamp_diff: rd.read_u32::<BigEndian>().ok()?,
+            wnd_size: rd.read_u32::<BigEndian>().ok()?,
+            seq_nr: rd.read_u16::<BigEndian>().ok()?,
+            ack_nr: rd.read_u16::<BigEndian>().ok()?
+        };
+
+        let mut extension = buf[1];
+        let mut pos = HEADER_LEN;
+        while extension != 0 {
+            if pos + 2 > buf.len() {
+                return None;
+            }
+            extension = buf[pos];
+            pos += 2 + buf[pos + 1] as usize;
+        }
+        if pos > buf.len() {
+            return None;
+        }
+
+        Some((header, &buf[pos..]))
+    }
+}
+
+// a comes before b in wrapping sequence space
+fn seq_less(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ConnState {
+    SynSent,
+    Connected,
+    // Closed locally, kept until our FIN is acknowledged
+    Closed,
+    Reset
+}
+
+struct Sent {
+    seq: u16,
+    kind: PacketType,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32
+}
+
+struct Conn {
+    addr: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    state: ConnState,
+    seq_nr: u16,
+    ack_nr: u16,
+
+    // Sending side
+    unacked: VecDeque<Sent>,
+    in_flight: usize,
+    cwnd: usize,
+    peer_wnd: usize,
+    rtt: Option<(f64, f64)>,
+    rto: Duration,
+    base_delays: [u32; 2],
+    base_rotated: Instant,
+    last_ack: u16,
+    dup_acks: u32,
+    fin_sent: bool,
+
+    // Receiving side
+    reply_micro: u32,
+    recv_buf: VecDeque<u8>,
+    out_of_order: HashMap<u16, Vec<u8>>,
+    fin_seq: Option<u16>,
+    eof: bool,
+
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    connected: Option<oneshot::Sender<()>>,
+    last_recv: Instant,
+    last_sent: Instant
+}
+
+impl Conn {
+
+    fn new(addr: SocketAddr, recv_id: u16, send_id: u16, state: ConnState) -> Conn {
+        Conn {
+            addr,
+            recv_id,
+            send_id,
+            state,
+            seq_nr: 1,
+            ack_nr: 0,
+            unacked: VecDeque::new(),
+            in_flight: 0,
+            cwnd: MIN_CWND,
+            peer_wnd: MSS,
+            rtt: None,
+            rto: INITIAL_RTO,
+            base_delays: [u32::MAX; 2],
+            base_rotated: Instant::now(),
+            last_ack: 0,
+            dup_acks: 0,
+            fin_sent: false,
+            reply_micro: 0,
+            recv_buf: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            fin_seq: None,
+            eof: false,
+            read_waker: None,
+            write_waker: None,
+            connected: None,
+            last_recv: Instant::now(),
+            last_sent: Instant::now()
+        }
+    }
+
+    fn send(&mut self, shared: &Shared, kind: PacketType, seq_nr: u16, payload: &[u8]) {
+
+        let header = Header {
+            kind,
+            conn_id: if kind == PacketType::Syn { self.recv_id } else { self.send_id },
+            timestamp: shared.micros(),
+            timestamp_diff: self.reply_micro,
+            wnd_size: RECV_WINDOW.saturating_sub(self.recv_buf.len()) as u32,
+            seq_nr,
+            ack_nr: self.ack_nr
+        };
+
+        // Lost datagrams are recovered by retransmission
+        let _ = shared.socket.try_send_to(&header.to_buf(payload), self.addr);
+        self.last_sent = Instant::now();
+    }
+
+    // Send packet which consumes a sequence number and must be acknowledged
+    fn send_tracked(&mut self, shared: &Shared, kind: PacketType, payload: Vec<u8>) {
+        let seq = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.send(shared, kind, seq, &payload);
+        self.in_flight += payload.len();
+        self.unacked.push_back(Sent { seq, kind, payload, sent_at: Instant::now(), transmissions: 1 });
+    }
+
+    fn ack(&mut self, shared: &Shared) {
+        self.send(shared, PacketType::State, self.seq_nr, &[]);
+    }
+
+    fn resend_front(&mut self, shared: &Shared) {
+        let (kind, seq, payload) = match self.unacked.front_mut() {
+            Some(sent) => {
+                sent.sent_at = Instant::now();
+                sent.transmissions += 1;
+                (sent.kind, sent.seq, sent.payload.clone())
+            },
+            None => return
+        };
+        self.send(shared, kind, seq, &payload);
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn window(&self) -> usize {
+        self.cwnd.min(self.peer_wnd.max(MSS))
+    }
+
+    fn on_packet(&mut self, shared: &Shared, header: &Header, payload: &[u8]) {
+
+        self.last_recv = Instant::now();
+        self.reply_micro = shared.micros().wrapping_sub(header.timestamp);
+        self.peer_wnd = header.wnd_size as usize;
+
+        if header.kind == PacketType::Reset {
+            self.state = ConnState::Reset;
+            self.wake();
+            return;
+        }
+
+        if self.state == ConnState::SynSent {
+            if header.kind != PacketType::State {
+                return;
+            }
+            self.state = ConnState::Connected;
+            self.ack_nr = header.seq_nr.wrapping_sub(1);
+            if let Some(tx) = self.connected.take() {
+                let _ = tx.send(());
+            }
+        }
+
+        self.on_ack(header.ack_nr, header.timestamp_diff);
+
+        match header.kind {
+            PacketType::Data => {
+                self.receive(header.seq_nr, payload);
+                self.ack(shared);
+            },
+            PacketType::Fin => {
+                self.fin_seq = Some(header.seq_nr);
+                self.receive(header.seq_nr, &[]);
+                self.ack(shared);
+            },
+            _ => {}
+        }
+    }
+
+    fn receive(&mut self, seq: u16, payload: &[u8]) {
+
+        let next = self.ack_nr.wrapping_add(1);
+
+        if seq == next {
+            self.recv_buf.extend(payload);
+            self.ack_nr = seq;
+
+            while let Some(data) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+                self.recv_buf.extend(data);
+                self.ack_nr = self.ack_nr.wrapping_add(1);
+            }
+        }
+        else if seq_less(next, seq) && seq.wrapping_sub(next) as usize <= RECV_WINDOW / MSS {
+            self.out_of_order.insert(seq, payload.to_vec());
+        }
+
+        if self.fin_seq == Some(self.ack_nr) {
+            self.eof = true;
+        }
+
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn on_ack(&mut self, ack_nr: u16, delay: u32) {
+
+        let mut acked = 0;
+        let mut rtt_sample = None;
+
+        while let Some(sent) = self.unacked.front() {
+            if seq_less(ack_nr, sent.seq) {
+                break;
+            }
+            let sent = self.unacked.pop_front().unwrap();
+            acked += sent.payload.len();
+            self.in_flight -= sent.payload.len();
+
+            // Karn's algorithm, only packets sent once give RTT samples
+            if sent.transmissions == 1 {
+                rtt_sample = Some(sent.sent_at.elapsed());
+            }
+        }
+
+        if let Some(sample) = rtt_sample {
+            self.update_rtt(sample);
+        }
+
+        if acked > 0 {
+            self.dup_acks = 0;
+            if delay != 0 {
+                self.ledbat(acked, delay);
+            }
+            if let Some(waker) = self.write_waker.take() {
+                waker.wake();
+            }
+        }
+        else if ack_nr == self.last_ack && !self.unacked.is_empty() {
+            self.dup_acks += 1;
+        }
+        self.last_ack = ack_nr;
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let sample = sample.as_micros() as f64;
+        let (rtt, var) = match self.rtt {
+            None => (sample, sample / 2.0),
+            Some((rtt, var)) => (rtt + (sample - rtt) / 8.0, var + ((rtt - sample).abs() - var) / 4.0)
+        };
+        self.rtt = Some((rtt, var));
+        self.rto = Duration::from_micros((rtt + 4.0 * var) as u64).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    // Grow or shrink the window by how far the queuing delay is from the target
+    fn ledbat(&mut self, acked: usize, delay: u32) {
+
+        if self.base_rotated.elapsed() > Duration::from_secs(60) {
+            self.base_delays = [u32::MAX, self.base_delays[0]];
+            self.base_rotated = Instant::now();
+        }
+        self.base_delays[0] = self.base_delays[0].min(delay);
+        let base_delay = self.base_delays[0].min(self.base_delays[1]);
+
+        let queuing_delay = delay.wrapping_sub(base_delay) as f64;
+        let off_target = (TARGET_DELAY - queuing_delay) / TARGET_DELAY;
+        let gain = MAX_CWND_INCREASE * off_target * acked as f64 / self.cwnd as f64;
+
+        self.cwnd = ((self.cwnd as f64 + gain) as usize).clamp(MIN_CWND, MAX_CWND);
+    }
+
+    // Retransmissions and timeouts, returns false once the connection can be dropped
+    fn on_timer(&mut self, shared: &Shared) -> bool {
+
+        match self.state {
+            ConnState::Reset => return false,
+            ConnState::Closed if self.unacked.is_empty() => return false,
+            _ => {}
+        }
+
+        if self.last_recv.elapsed() > IDLE_TIMEOUT {
+            self.state = ConnState::Reset;
+            self.wake();
+            return false;
+        }
+
+        // Fast retransmit after three duplicate acks
+        if self.dup_acks >= 3 {
+            self.dup_acks = 0;
+            self.cwnd = (self.cwnd / 2).max(MIN_CWND);
+            self.resend_front(shared);
+        }
+
+        let timed_out = self.unacked.front().map(|sent| (sent.sent_at.elapsed() > self.rto, sent.transmissions));
+        match timed_out {
+            Some((true, transmissions)) => {
+                if transmissions >= MAX_TRANSMISSIONS {
+                    self.state = ConnState::Reset;
+                    self.wake();
+                    return false;
+                }
+                self.rto = (self.rto * 2).min(MAX_RTO);
+                self.cwnd = MIN_CWND;
+                self.resend_front(shared);
+            },
+            None if self.state == ConnState::Connected && self.last_sent.elapsed() > KEEPALIVE => {
+                self.ack(shared);
+            },
+            _ => {}
+        }
+
+        true
+    }
+}
+
+type ConnKey = (SocketAddr, u16);
+
+struct Shared {
+    socket: UdpSocket,
+    conns: Mutex<HashMap<ConnKey, Arc<Mutex<Conn>>>>,
+    incoming: mpsc::UnboundedSender<UtpStream>,
+    epoch: Instant
+}
+
+impl Shared {
+    fn micros(&self) -> u32 {
+        self.epoch.elapsed().as_micros() as u32
+    }
+}
+
+// UDP socket carrying all uTP connections, dispatching packets by connection id
+pub struct UtpSocket {
+    shared: Arc<Shared>,
+    incoming: tokio::sync::Mutex<mpsc::UnboundedReceiver<UtpStream>>
+}
+
+impl UtpSocket {
+
+    pub async fn bind(addr: SocketAddr) -> io::Result<Arc<UtpSocket>> {
+        UtpSocket::with_socket(UdpSocket::bind(addr).await?)
+    }
+
+    // Run over an already bound non-blocking socket, such as a dual stack one
+    pub fn from_std(socket: std::net::UdpSocket) -> io::Result<Arc<UtpSocket>> {
+        UtpSocket::with_socket(UdpSocket::from_std(socket)?)
+    }
+
+    fn with_socket(socket: UdpSocket) -> io::Result<Arc<UtpSocket>> {
+
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let shared = Arc::new(Shared {
+            socket,
+            conns: Mutex::new(HashMap::new()),
+            incoming: tx,
+            epoch: Instant::now()
+        });
+
+        tokio::spawn(recv_loop(shared.clone()));
+
+        Ok(Arc::new(UtpSocket { shared, incoming: tokio::sync::Mutex::new(rx) }))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+
+    pub async fn connect(&self, peer: SocketAddr) -> Option<UtpStream> {
+
+        let shared = &self.shared;
+        let (tx, rx) = oneshot::channel();
+
+        // A dual stack socket reaches IPv4 peers through mapped addresses, which is also how their packets arrive
+        let addr = match (shared.socket.local_addr(), peer) {
+            (Ok(SocketAddr::V6(_)), SocketAddr::V4(v4)) => SocketAddr::from((v4.ip().to_ipv6_mapped(), v4.port())),
+            _ => peer
+        };
+
+        let (key, conn) = {
+            let mut conns = shared.conns.lock().unwrap();
+            let mut recv_id: u16 = rand::random();
+            while conns.contains_key(&(addr, recv_id)) || conns.contains_key(&(addr, recv_id.wrapping_add(1))) {
+                recv_id = rand::random();
+            }
+
+            let mut conn = Conn::new(addr, recv_id, recv_id.wrapping_add(1), ConnState::SynSent);
+            conn.connected = Some(tx);
+            conn.send_tracked(shared, PacketType::Syn, Vec::new());
+
+            let conn = Arc::new(Mutex::new(conn));
+            conns.insert((addr, recv_id), conn.clone());
+            ((addr, recv_id), conn)
+        };
+
+        tokio::spawn(timer(shared.clone(), key, conn.clone()));
+
+        match timeout(CONNECT_TIMEOUT, rx).await {
+            Ok(Ok(())) => Some(UtpStream { shared: shared.clone(), conn, peer }),
+            _ => {
+                conn.lock().unwrap().state = ConnState::Reset;
+                shared.conns.lock().unwrap().remove(&key);
+                None
+            }
+        }
+    }
+
+    pub async fn accept(&self) -> Option<UtpStream> {
+        self.incoming.lock().await.recv().await
+    }
+}
+
+async fn recv_loop(shared: Arc<Shared>) {
+
+    let mut buf = [0; 2048];
+    loop {
+        let (len, from) = match shared.socket.recv_from(&mut buf).await {
+            Ok(res) => res,
+            Err(_) => {
+                time::sleep(TICK).await;
+                continue;
+            }
+        };
+
+        let (header, payload) = match Header::parse(&buf[..len]) {
+            Some(res) => res,
+            None => continue
+        };
+
+        let conn = shared.conns.lock().unwrap().get(&(from, header.conn_id)).cloned();
+        if let Some(conn) = conn {
+            conn.lock().unwrap().on_packet(&shared, &header, payload);
+            continue;
+        }
+
+        if header.kind != PacketType::Syn {
+            continue;
+        }
+
+        // Retransmitted SYN of a connection we already accepted
+        let key = (from, header.conn_id.wrapping_add(1));
+        let existing = shared.conns.lock().unwrap().get(&key).cloned();
+        if let Some(conn) = existing {
+            conn.lock().unwrap().ack(&shared);
+            continue;
+        }
+
+        let mut conn = Conn::new(from, key.1, header.conn_id, ConnState::Connected);
+        conn.seq_nr = rand::random();
+        conn.ack_nr = header.seq_nr;
+        conn.reply_micro = shared.micros().wrapping_sub(header.timestamp);
+        conn.peer_wnd = header.wnd_size as usize;
+        conn.ack(&shared);
+
+        let conn = Arc::new(Mutex::new(conn));
+        shared.conns.lock().unwrap().insert(key, conn.clone());
+        tokio::spawn(timer(shared.clone(), key, conn.clone()));
+
+        let peer = SocketAddr::new(from.ip().to_canonical(), from.port());
+        let _ = shared.incoming.send(UtpStream { shared: shared.clone(), conn, peer });
+    }
+}
+
+async fn timer(shared: Arc<Shared>, key: ConnKey, conn: Arc<Mutex<Conn>>) {
+
+    let mut interval = time::interval(TICK);
+    loop {
+        interval.tick().await;
+        if !conn.lock().unwrap().on_timer(&shared) {
+            shared.conns.lock().unwrap().remove(&key);
+            break;
+        }
+    }
+}
+
+pub struct UtpStream {
+    shared: Arc<Shared>,
+    conn: Arc<Mutex<Conn>>,
+    peer: SocketAddr
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl AsyncRead for UtpStream {
+
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+
+        let this = self.get_mut();
+        let mut conn = this.conn.lock().unwrap();
+
+        if !conn.recv_buf.is_empty() {
+            let was_full = conn.recv_buf.len() + MSS >= RECV_WINDOW;
+
+            let n = buf.remaining().min(conn.recv_buf.len());
+            let data: Vec<u8> = conn.recv_buf.drain(..n).collect();
+            buf.put_slice(&data);
+
+            // Let the peer know the window opened again
+            if was_full {
+                conn.ack(&this.shared);
+            }
+            return Poll::Ready(Ok(()));
+        }
+
+        if conn.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if conn.state == ConnState::Reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+
+        conn.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+
+        let this = self.get_mut();
+        let mut conn = this.conn.lock().unwrap();
+
+        match conn.state {
+            ConnState::Reset => return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into())),
+            ConnState::Closed => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            _ => {}
+        }
+        if conn.fin_sent {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        // Wait for acks while the congestion window is full
+        let window = conn.window();
+        if conn.in_flight >= window && !conn.unacked.is_empty() {
+            conn.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = buf.len().min(MSS).min(window.saturating_sub(conn.in_flight).max(1));
+        conn.send_tracked(&this.shared, PacketType::Data, buf[..len].to_vec());
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut conn = this.conn.lock().unwrap();
+        if conn.state == ConnState::Connected && !conn.fin_sent {
+            conn.fin_sent = true;
+            conn.send_tracked(&this.shared, PacketType::Fin, Vec::new());
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut conn = self.conn.lock().unwrap();
+        if conn.state == ConnState::Connected {
+            if !conn.fin_sent {
+                conn.fin_sent = true;
+                conn.send_tracked(&self.shared, PacketType::Fin, Vec::new());
+            }
+            conn.state = ConnState::Closed;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use socket2::Type;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::helpers;
+    use super::{Header, PacketType, UtpSocket, seq_less};
+
+    #[test]
+    fn header_test() {
+
+        let header = Header { kind: PacketType::Data, conn_id: 7, timestamp: 1, timestamp_diff: 2, wnd_size: 3, seq_nr: 4, ack_nr: 5 };
+        let buf = header.to_buf(b"abc");
+        assert_eq!(Header::parse(&buf), Some((header, &b"abc"[..])));
+
+        // Extension headers are skipped
+        let mut ext = buf[..20].to_vec();
+        ext[1] = 1;
+        ext.extend_from_slice(&[0, 4, 0, 0, 0, 0]);
+        ext.extend_from_slice(b"abc");
+        assert_eq!(Header::parse(&ext).unwrap().1, b"abc");
+
+        assert!(seq_less(65535, 1));
+        assert!(!seq_less(1, 65535));
+    }
+
+    #[tokio::test]
+    async fn transfer_test() {
+
+        let local = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let a = UtpSocket::bind(local).await.unwrap();
+        let b = UtpSocket::bind(local).await.unwrap();
+        let b_addr = b.local_addr().unwrap();
+
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let expected = data.clone();
+
+        let server = tokio::spawn(async move {
+            let mut stream = b.accept().await.unwrap();
+            let mut buf = vec![0; expected.len()];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, expected);
+            stream.write_all(b"done").await.unwrap();
+
+            // Remote closed after our reply
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).await.unwrap();
+            rest.len()
+        });
+
+        let mut stream = a.connect(b_addr).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"done");
+        drop(stream);
+
+        assert_eq!(server.await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn dual_stack_test() {
+
+        // IPv4 peers reach a dual stack socket and are reported with their plain IPv4 address
+        let a = UtpSocket::from_std(helpers::dual_stack(Type::DGRAM, 0).unwrap().into()).unwrap();
+        let a_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, a.local_addr().unwrap().port()));
+        let b = UtpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await.unwrap();
+        let b_addr = b.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let mut stream = a.accept().await.unwrap();
+            let mut buf = [0; 2];
+            stream.read_exact(&mut buf).await.unwrap();
+
+            // Outgoing connections from the dual stack socket to IPv4 work too
+            a.connect(b_addr).await.is_some() && stream.peer_addr() == b_addr
+        });
+
+        let mut stream = b.connect(a_addr).await.unwrap();
+        stream.write_all(b"hi").await.unwrap();
+        assert!(b.accept().await.is_some());
+        assert!(server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn connect_timeout_test() {
+        let a = UtpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await.unwrap();
+        let silent = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        assert!(a.connect(silent.local_addr().unwrap()).await.is_none());
+    }
+}
@@ -0,0 +1,375 @@
+use std::{fs::File, sync::Arc, time::Duration};
+use reqwest::{header::{RANGE, RETRY_AFTER}, Client, Response, StatusCode};
+use sha1_smol::Sha1;
+use tokio::{sync::Mutex, time::sleep};
+use url::Url;
+use crate::{
+    download,
+    helpers,
+    torrent_parser::{Piece, Torrent}
+};
+
+// Wait after a failed request in seconds, doubled for each failure in a row
+static RETRY_MIN: u64 = 5;
+static RETRY_MAX: u64 = 300;
+
+// Failures in a row, not counting busy replies, after which a web seed is dropped
+static FAILURE_LIMIT: u32 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WebSeedKind {
+    // Files served as they are, fetched with range requests (BEP 19)
+    UrlList,
+    // Script handing out pieces by info hash and piece index (BEP 17)
+    HttpSeed
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FetchError {
+    // Server is busy, try again after this many seconds
+    RetryAfter(u64),
+    Failed
+}
+
+// An HTTP server holding the whole torrent, used like a peer which has every piece
+pub struct WebSeed {
+    pub url: String,
+    pub kind: WebSeedKind,
+    name: String,
+    // Paths and sizes, a single file torrent has one file named after the torrent
+    files: Vec<(String, u64)>,
+    multi_file: bool,
+    info_hash: [u8; 20],
+    client: Client
+}
+
+impl WebSeed {
+
+    // Web seeds listed in the torrent, must run while the file list is still there
+    pub fn from_torrent(torrent: &Torrent) -> Vec<WebSeed> {
+
+        let urls = torrent.url_list.iter().map(|url| (url, WebSeedKind::UrlList));
+        let seeds = torrent.http_seeds.iter().map(|url| (url, WebSeedKind::HttpSeed));
+
+        urls.chain(seeds).map(|(url, kind)| WebSeed {
+            url: url.clone(),
+            kind,
+            name: torrent.name.clone(),
+            files: torrent.file_list.clone().unwrap_or_else(|| vec![(torrent.name.clone(), torrent.length)]),
+            multi_file: torrent.file_list.is_some(),
+            info_hash: torrent.info_hash,
+            client: Client::builder().timeout(Duration::from_secs(30)).build().unwrap_or_default()
+        }).collect()
+    }
+
+    // Url of a file, a url ending in a slash is the directory holding the torrent
+    fn file_url(&self, file: usize) -> Option<Url> {
+
+        let mut url = Url::parse(&self.url).ok()?;
+        if self.multi_file || self.url.ends_with('/') {
+            let mut segments = url.path_segments_mut().ok()?;
+            segments.pop_if_empty().push(&self.name);
+            if self.multi_file {
+                segments.extend(self.files[file].0.split('/'));
+            }
+        }
+        Some(url)
+    }
+
+    // Fetch a piece, with one range request for each file it spans
+    pub async fn fetch(&self, index: u32, offset: u64, length: u64) -> Result<Vec<u8>, FetchError> {
+
+        match self.kind {
+            WebSeedKind::UrlList => {
+                let mut buf = Vec::with_capacity(length as usize);
+                for (file, start, len) in segments(&self.files, offset, length) {
+
+                    let url = self.file_url(file).ok_or(FetchError::Failed)?;
+                    let range = format!("bytes={}-{}", start, start + len - 1);
+                    let res = self.client.get(url).header(RANGE, range).send().await.map_err(|_| FetchError::Failed)?;
+                    let data = body(res).await?;
+
+                    // A server ignoring the range sends the whole file
+                    if data.len() as u64 == len {
+                        buf.extend(data);
+                    }
+                    else if data.len() as u64 == self.files[file].1 {
+                        buf.extend(&data[start as usize..(start + len) as usize]);
+                    }
+                    else {
+                        return Err(FetchError::Failed);
+                    }
+                }
+                Ok(buf)
+            },
+            WebSeedKind::HttpSeed => {
+                let separator = if self.url.contains('?') { '&' } else { '?' };
+                let url = format!("{}{}info_hash={}&piece={}&ranges=0-{}", self.url, separator, helpers::u8_to_url(self.info_hash), index, length - 1);
+                let res = self.client.get(url).send().await.map_err(|_| FetchError::Failed)?;
+                let data = body(res).await?;
+                if data.len() as u64 != length {
+                    return Err(FetchError::Failed);
+                }
+                Ok(data)
+            }
+        }
+    }
+}
+
+// File index, offset in the file and length for each file a byte range of the torrent touches
+fn segments(files: &[(String, u64)], mut offset: u64, mut length: u64) -> Vec<(usize, u64, u64)> {
+
+    let mut ret = Vec::new();
+    for (index, (_, size)) in files.iter().enumerate() {
+        if length == 0 {
+            break;
+        }
+        if offset >= *size {
+            offset -= size;
+            continue;
+        }
+        let len = length.min(size - offset);
+        ret.push((index, offset, len));
+        offset = 0;
+        length -= len;
+    }
+    ret
+}
+
+// Body of a successful reply, a busy server says when to come back
+async fn body(res: Response) -> Result<Vec<u8>, FetchError> {
+
+    let status = res.status();
+    if status == StatusCode::SERVICE_UNAVAILABLE || status == StatusCode::TOO_MANY_REQUESTS {
+        // Url seeds use Retry-After, http seeds send the seconds as the body
+        let header = res.headers().get(RETRY_AFTER).and_then(|val| val.to_str().ok()).and_then(|val| val.trim().parse().ok());
+        let wait = match header {
+            Some(wait) => Some(wait),
+            None => res.text().await.ok().and_then(|text| text.trim().parse().ok())
+        };
+        return Err(wait.map(FetchError::RetryAfter).unwrap_or(FetchError::Failed));
+    }
+    if !status.is_success() {
+        return Err(FetchError::Failed);
+    }
+
+    res.bytes().await.map(|data| data.to_vec()).map_err(|_| FetchError::Failed)
+}
+
+// Claim a wanted piece no peer has started on, returns index, offset and length
+async fn claim_piece(freq_ref: &Arc<Mutex<Vec<Piece>>>) -> Option<(u32, u64, u64)> {
+
+    let mut freq = freq_ref.lock().await;
+    let (index, piece) = (*freq).iter_mut().enumerate()
+        .find(|(_, piece)| piece.wanted && !piece.completed && piece.owner.is_none() && piece.blocks.iter().all(|block| !block.is_req && !block.received))?;
+
+    for block in &mut piece.blocks {
+        block.is_req = true;
+    }
+    Some((index as u32, piece.blocks[0].offset, piece.length))
+}
+
+// Give a claimed piece back to the peers
+async fn release_piece(freq_ref: &Arc<Mutex<Vec<Piece>>>, index: u32) {
+    let mut freq = freq_ref.lock().await;
+    for block in &mut (*freq)[index as usize].blocks {
+        if !block.received {
+            block.is_req = false;
+        }
+    }
+}
+
+// Mark a verified piece done, unless a peer finished it first in endgame
+async fn finish_piece(torrent: &Torrent, index: u32) {
+
+    let mut freq = torrent.piece_freq.lock().await;
+    let piece = &mut (*freq)[index as usize];
+    if piece.completed {
+        return;
+    }
+
+    for block in &mut piece.blocks {
+        block.received = true;
+    }
+    piece.completed = true;
+
+    *(torrent.downloaded.lock().await) += piece.length;
+    *(torrent.piece_left.lock().await) -= 1;
+    if piece.wanted {
+        *(torrent.wanted_left.lock().await) -= 1;
+    }
+}
+
+// Write a fetched piece once it matches its hash, so a bad mirror never overwrites blocks peers delivered
+async fn store_piece(torrent: &Torrent, file: &[(File, u64)], index: u32, offset: u64, data: &[u8]) -> bool {
+
+    if torrent.piece_hashes[index as usize] != Sha1::from(data).digest().bytes() {
+        return false;
+    }
+    // Nothing to write when a peer finished the piece meanwhile
+    let completed = torrent.piece_freq.lock().await[index as usize].completed;
+    completed || download::write_files(file, offset, data)
+}
+
+// Download pieces from a web seed until we hold everything we want
+pub async fn download(seed: WebSeed, torrent: Torrent, file_ref: Arc<Vec<(File, u64)>>) {
+
+    let mut failures = 0;
+    let mut wait = RETRY_MIN;
+
+    loop {
+        if *(torrent.wanted_left.lock().await) == 0 {
+            break;
+        }
+
+        let (index, offset, length) = match claim_piece(&torrent.piece_freq).await {
+            Some(piece) => piece,
+            None => {
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let res = seed.fetch(index, offset, length).await;
+        let valid = match &res {
+            Ok(data) => store_piece(&torrent, &file_ref, index, offset, data).await,
+            Err(_) => false
+        };
+
+        if valid {
+            finish_piece(&torrent, index).await;
+            failures = 0;
+            wait = RETRY_MIN;
+            continue;
+        }
+
+        release_piece(&torrent.piece_freq, index).await;
+        let delay = match res {
+            // Capped, a server could ask us to wait forever
+            Err(FetchError::RetryAfter(secs)) => secs.min(RETRY_MAX),
+            _ => {
+                failures += 1;
+                if failures >= FAILURE_LIMIT {
+                    return;
+                }
+                let delay = wait;
+                wait = (wait * 2).min(RETRY_MAX);
+                delay
+            }
+        };
+        sleep(Duration::from_secs(delay)).await;
+    }
+}
+
+// Run every web seed of the torrent
+pub async fn download_all(seeds: Vec<WebSeed>, torrent: Torrent, file_ref: Arc<Vec<(File, u64)>>) {
+
+    let handles: Vec<_> = seeds.into_iter().map(|seed| tokio::spawn(download(seed, torrent.clone(), file_ref.clone()))).collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs::{self, OpenOptions}, sync::Arc};
+    use reqwest::Client;
+    use sha1_smol::Sha1;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+    use crate::torrent_parser::test_torrent;
+    use super::{segments, store_piece, FetchError, WebSeed, WebSeedKind};
+
+    // Serves files with range support, anything else gets a busy reply
+    async fn serve(files: HashMap<String, Vec<u8>>) -> String {
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let files = Arc::new(files);
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let files = files.clone();
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 4096];
+                    let n = stream.read(&mut buf).await.unwrap();
+                    let req = String::from_utf8_lossy(&buf[..n]).to_string();
+                    let path = req.split(' ').nth(1).unwrap().to_string();
+                    let range = req.lines().find_map(|line| line.to_ascii_lowercase().strip_prefix("range: bytes=").map(str::to_string));
+
+                    let res = match (files.get(&path), range) {
+                        (Some(data), Some(range)) => {
+                            let (start, end) = range.trim().split_once('-').unwrap();
+                            let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+                            let mut res = format!("HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", end + 1 - start).into_bytes();
+                            res.extend(&data[start..=end]);
+                            res
+                        },
+                        _ => b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 2\r\nConnection: close\r\n\r\n30".to_vec()
+                    };
+                    stream.write_all(&res).await.unwrap();
+                });
+            }
+        });
+
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn segments_test() {
+
+        let files = vec![("a".to_string(), 5), ("b".to_string(), 0), ("c".to_string(), 7)];
+        assert_eq!(segments(&files, 3, 6), vec![(0, 3, 2), (2, 0, 4)]);
+        assert_eq!(segments(&files, 6, 20), vec![(2, 1, 6)]);
+    }
+
+    #[tokio::test]
+    async fn fetch_test() {
+
+        let files = HashMap::from([("/seed/t/x".to_string(), b"01234".to_vec()), ("/seed/t/dir/y%20z".to_string(), b"5678901".to_vec())]);
+        let base = serve(files).await;
+
+        let mut seed = WebSeed {
+            url: format!("{}/seed/", base),
+            kind: WebSeedKind::UrlList,
+            name: "t".to_string(),
+            files: vec![("x".to_string(), 5), ("dir/y z".to_string(), 7)],
+            multi_file: true,
+            info_hash: [0; 20],
+            client: Client::builder().no_proxy().build().unwrap()
+        };
+
+        // Piece spanning both files
+        assert_eq!(seed.fetch(1, 3, 6).await.unwrap(), b"345678");
+
+        // Busy http seed tells us when to retry
+        seed.kind = WebSeedKind::HttpSeed;
+        seed.url = format!("{}/seed.php", base);
+        assert_eq!(seed.fetch(0, 0, 4).await, Err(FetchError::RetryAfter(30)));
+    }
+
+    #[tokio::test]
+    async fn store_piece_test() {
+
+        let good = vec![1; 16384];
+        let mut torrent = test_torrent("http://t/announce", 1).await;
+        torrent.piece_hashes = Arc::new(vec![Sha1::from(&good).digest().bytes().to_vec()]);
+        let path = std::env::temp_dir().join(format!("ft_webseed_{}", rand::random::<u32>()));
+        let file = vec![(OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap(), 16384)];
+        fs::write(&path, vec![7; 16384]).unwrap();
+
+        // A corrupt mirror leaves the blocks already on disk alone
+        assert!(!store_piece(&torrent, &file, 0, 0, &[2; 16384]).await);
+        assert_eq!(fs::read(&path).unwrap(), vec![7; 16384]);
+
+        // Nor is anything written once the piece is done
+        torrent.piece_freq.lock().await[0].completed = true;
+        assert!(store_piece(&torrent, &file, 0, 0, &good).await);
+        assert_eq!(fs::read(&path).unwrap(), vec![7; 16384]);
+
+        torrent.piece_freq.lock().await[0].completed = false;
+        assert!(store_piece(&torrent, &file, 0, 0, &good).await);
+        assert_eq!(fs::read(&path).unwrap(), good);
+        fs::remove_file(path).unwrap();
+    }
+}